@@ -0,0 +1,83 @@
+// Compares the ways `JsonPoller` can frame messages on a long stream of
+// states, like the ones a host sends over a session. Run with `cargo bench`,
+// while `cargo test --benches` only checks that it works.
+
+use std::time::{Duration, Instant};
+
+use albjorkm_chess_gui::bot::{MovePicker, RandomMover};
+use albjorkm_chess_gui::{chess_representaiton_to_wire, ChessState, Framing,
+                         JsonPoller};
+use chess_network_protocol::{ServerToClient, ServerToClientHandshake};
+
+/// Plays random games and returns the handshake followed by the states sent
+/// for their moves.
+fn state_stream(states: usize) -> Vec<u8> {
+    let mut picker = RandomMover::with_seed(8483);
+    let mut chess_state = ChessState::new();
+    let handshake = ServerToClientHandshake {
+        features: vec![],
+        board: chess_representaiton_to_wire(&chess_state.chess_representation),
+        moves: chess_state.legal_moves.clone(),
+        joever: chess_state.to_joever(),
+    };
+    let mut stream = serde_json::to_vec(&handshake).unwrap();
+    for _ in 0..states {
+        let mv = match picker.pick_move(&chess_state) {
+            Some(mv) if !chess_state.is_game_over => mv,
+            _ => {
+                chess_state = ChessState::new();
+                picker.pick_move(&chess_state).unwrap()
+            }
+        };
+        chess_state.ingest_client_move(&mv);
+        let state = ServerToClient::State {
+            board: chess_representaiton_to_wire(
+                &chess_state.chess_representation),
+            moves: chess_state.legal_moves.clone(),
+            joever: chess_state.to_joever(),
+            move_made: mv,
+        };
+        stream.extend(serde_json::to_vec(&state).unwrap());
+    }
+    stream
+}
+
+/// Feeds the stream to a poller a piece at a time, returning the number of
+/// messages that came out.
+fn poll(framing: Framing, stream: &[u8], piece: usize) -> usize {
+    let mut poller =
+        JsonPoller::<ServerToClientHandshake, ServerToClient>::with_framing(
+            framing);
+    let mut packets = vec![];
+    let mut count = 0;
+    for piece in stream.chunks(piece) {
+        poller.feed(piece, &mut packets);
+        count += packets.len();
+        packets.clear();
+    }
+    assert!(poller.deviations.is_empty(), "{:?}", poller.deviations);
+    count
+}
+
+fn main() {
+    let bench = std::env::args().any(|arg| arg == "--bench");
+    let (states, runs) = if bench { (10_000, 10) } else { (100, 1) };
+    let stream = state_stream(states);
+    println!("{states} states, {} bytes", stream.len());
+
+    // The size of the network thread's buffer, a TCP segment on most
+    // networks, and a stream that trickles in.
+    for piece in [65535, 1460, 64] {
+        for framing in [Framing::Finder, Framing::Streaming] {
+            let mut best = Duration::MAX;
+            for _ in 0..runs {
+                let start = Instant::now();
+                assert_eq!(poll(framing, &stream, piece), states + 1);
+                best = best.min(start.elapsed());
+            }
+            let rate = stream.len() as f64 / best.as_secs_f64() / 1e6;
+            println!("{framing:?} framing, {piece} byte pieces: {best:?} \
+                      ({rate:.1} MB/s)");
+        }
+    }
+}
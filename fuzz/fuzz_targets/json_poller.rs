@@ -0,0 +1,30 @@
+// Feeds arbitrary bytes to the pollers of both ends of a connection, cut
+// into pieces of arbitrary size. Run with `cargo fuzz run json_poller`.
+
+#![no_main]
+
+use albjorkm_chess_gui::JsonPoller;
+use chess_network_protocol::{ClientToServer, ClientToServerHandshake,
+                             ServerToClient, ServerToClientHandshake};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    // The first byte picks the size of the pieces.
+    let Some((&size, data)) = data.split_first() else {
+        return
+    };
+    let mut server =
+        JsonPoller::<ServerToClientHandshake, ServerToClient>::new();
+    let mut client =
+        JsonPoller::<ClientToServerHandshake, ClientToServer>::new();
+    // Small enough for the fuzzer to run into.
+    server.max_message_size = 4096;
+    client.max_message_size = 4096;
+    let (mut from_server, mut from_client) = (vec![], vec![]);
+    for piece in data.chunks(size as usize + 1) {
+        server.feed(piece, &mut from_server);
+        client.feed(piece, &mut from_client);
+        assert!(server.buffered() <= server.max_message_size);
+        assert!(client.buffered() <= client.max_message_size);
+    }
+});
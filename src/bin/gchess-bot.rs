@@ -0,0 +1,47 @@
+// Joins a game and plays random moves until it is over, for testing servers
+// without having to click through games by hand.
+
+use albjorkm_chess_gui::bot::{Bot, RandomMover};
+use albjorkm_chess_gui::{usage, Settings};
+
+fn main() {
+    let settings = match Settings::parse(std::env::args()) {
+        Ok(settings) => settings,
+        Err(e) => {
+            eprintln!("{e}");
+            let program = std::env::args().next().unwrap_or_default();
+            eprintln!("{}", usage(&program));
+            std::process::exit(2);
+        }
+    };
+
+    let is_white = settings.color.is_white();
+    let picker = RandomMover::new();
+    let mut bot = match Bot::connect(&settings.join_address, is_white, picker) {
+        Ok(bot) => bot,
+        Err(e) => {
+            eprintln!("[bot] could not join {}: {e}", settings.join_address);
+            std::process::exit(1);
+        }
+    };
+
+    bot.session.interop.mode = settings.interop;
+    bot.session.heartbeat = settings.heartbeat;
+
+    // An hour is more than any random game should take.
+    let result = bot.play(std::time::Duration::from_secs(3600));
+    for message in &bot.errors {
+        eprintln!("[bot] server error: {message}");
+    }
+    for message in &bot.desync_log {
+        eprintln!("[bot] turn desync: {message}");
+    }
+    println!("[interop] {}", bot.session.interop.summary());
+    match result {
+        Ok(_) => println!("[bot] {}", bot.chess_state.end_text()),
+        Err(e) => {
+            eprintln!("[bot] {e}");
+            std::process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,99 @@
+// Replays a session file written with --record, decoding it again and
+// ingesting everything the server sent like the client did, so that desyncs
+// and parsing bugs can be reproduced without a peer.
+
+use albjorkm_chess_gui::record::{read_session, replay, Replayed};
+use albjorkm_chess_gui::{ChessState, Packet};
+
+/// Draws the board as the white player sees it, upper case being white.
+fn board_text(chess_state: &ChessState) -> String {
+    let mut text = String::new();
+    for (index, (piece, team)) in
+        chess_state.chess_representation.iter().enumerate() {
+        let letter = match piece {
+            1 => 'p',
+            2 => 'r',
+            3 => 'n',
+            4 => 'b',
+            5 => 'q',
+            6 => 'k',
+            _ => '.',
+        };
+        text.push(if *team == -1 { letter.to_ascii_uppercase() } else {
+            letter
+        });
+        if index % 8 == 7 {
+            text.push('\n');
+        }
+    }
+    text
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let (path, verbose) = match args.as_slice() {
+        [_, path] => (path, false),
+        [_, flag, path] if flag == "--verbose" => (path, true),
+        _ => {
+            let program = args.first().map_or("gchess-replay", |a| a);
+            eprintln!("usage: {program} [--verbose] <session file>");
+            std::process::exit(2);
+        }
+    };
+
+    let entries = match read_session(std::path::Path::new(path)) {
+        Ok(entries) => entries,
+        Err(e) => {
+            eprintln!("[replay] could not read {path}: {e}");
+            std::process::exit(1);
+        }
+    };
+
+    let result = replay(&entries, |time_ms, message, chess_state| {
+        match message {
+            Replayed::FromServer(Packet::Handshake(h)) => {
+                println!("{time_ms:>8} ms server: handshake {:?}", h.features);
+            }
+            Replayed::FromServer(Packet::Data(d)) => {
+                println!("{time_ms:>8} ms server: {d:?}");
+            }
+            Replayed::FromClient(Packet::Handshake(h)) => {
+                println!("{time_ms:>8} ms client: handshake {h:?}");
+            }
+            Replayed::FromClient(Packet::Data(d)) => {
+                println!("{time_ms:>8} ms client: {d:?}");
+            }
+        }
+        if verbose {
+            if let Replayed::FromServer(_) = message {
+                print!("{}", board_text(chess_state));
+            }
+        }
+    });
+
+    println!();
+    print!("{}", board_text(&result.chess_state));
+    let turn = if result.chess_state.is_white_turn { "white" } else {
+        "black"
+    };
+    println!("{turn} to move");
+    if result.chess_state.is_game_over {
+        println!("{}", result.chess_state.end_text());
+    }
+    for line in &result.chat {
+        let sender = if line.from_server { "server" } else { "client" };
+        println!("{:>8} ms {sender} chat: {}", line.time_ms, line.text);
+    }
+    for message in &result.errors {
+        println!("[replay] server error: {message}");
+    }
+    for message in &result.desync_log {
+        println!("[replay] turn desync: {message}");
+    }
+    for deviation in &result.deviations {
+        println!("[replay] deviation: {deviation}");
+    }
+    for mismatch in &result.mismatches {
+        println!("[replay] mismatch: {mismatch}");
+    }
+}
@@ -0,0 +1,67 @@
+// A headless referee. Two clients connect and the server relays the game
+// between them without ever playing a side itself. With --lobby, any number
+// of clients connect and pick one of many games to play.
+
+use albjorkm_chess_gui::{bind_host, server_usage, Connection, Settings};
+use albjorkm_chess_gui::lobby::Lobby;
+use albjorkm_chess_gui::record::{Recorder, Role};
+use albjorkm_chess_gui::referee::Game;
+
+fn main() {
+    let settings = match Settings::parse_server(std::env::args()) {
+        Ok(settings) => settings,
+        Err(e) => {
+            eprintln!("{e}");
+            let program = std::env::args().next().unwrap_or_default();
+            eprintln!("{}", server_usage(&program));
+            std::process::exit(2);
+        }
+    };
+
+    let listener = match bind_host(&settings.bind_address, settings.port) {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("[server] failed to bind {}: {e}",
+                      settings.bind_address);
+            std::process::exit(1);
+        }
+    };
+    if let Ok(address) = listener.local_addr() {
+        println!("[server] listening on {address}");
+    }
+
+    if settings.lobby {
+        let mut lobby = Lobby::new(settings.interop, settings.record.clone(),
+                                   settings.framing);
+        loop {
+            lobby.accept(&listener);
+            lobby.update();
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+    }
+
+    let mut game = Game::new(settings.interop);
+    loop {
+        if let Ok((stream, address)) = listener.accept() {
+            if game.is_full() {
+                // Dropping the stream closes it.
+                println!("[server] turning away {address}, the game is full");
+            } else {
+                let record = settings.record.as_deref();
+                let recorder = Recorder::start(record, Role::Host);
+                match Connection::spawn_recorded(stream, settings.framing,
+                                                 recorder) {
+                    Ok(connection) => {
+                        println!("[server] {address} connected");
+                        game.add_player(connection);
+                    }
+                    Err(e) => eprintln!("[server] {address} failed: {e}"),
+                }
+            }
+        }
+
+        game.update();
+
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+}
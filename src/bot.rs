@@ -0,0 +1,233 @@
+//! Clients that play without a window, for testing servers from scripts and
+//! tests. A `Bot` speaks the protocol and leaves the choice of moves to a
+//! `MovePicker`.
+
+use chess_network_protocol::{ClientToServer, Move};
+
+use crate::{connect_client, ChessState, ClientSession, GameEnd, InteropMode,
+            Packet};
+
+/// Chooses the moves of a bot.
+pub trait MovePicker {
+    /// Picks the move to make in the given game, where it is always the turn
+    /// of the bot. Returning `None` makes the bot resign.
+    fn pick_move(&mut self, chess_state: &ChessState) -> Option<Move>;
+}
+
+/// Plays any one of the legal moves sent by the server. Resigns if the server
+/// sends none, since it then has no idea what to play.
+pub struct RandomMover {
+    seed: u64,
+}
+
+impl RandomMover {
+    pub fn new() -> Self {
+        use std::hash::{BuildHasher, Hasher};
+        let state = std::collections::hash_map::RandomState::new();
+        Self::with_seed(state.build_hasher().finish())
+    }
+    /// Creates a mover that plays the same moves every time it is given the
+    /// same games.
+    pub fn with_seed(seed: u64) -> Self {
+        // Xorshift gets stuck on zero.
+        RandomMover { seed: seed | 1 }
+    }
+    fn next(&mut self) -> u64 {
+        self.seed ^= self.seed << 13;
+        self.seed ^= self.seed >> 7;
+        self.seed ^= self.seed << 17;
+        self.seed
+    }
+}
+
+impl MovePicker for RandomMover {
+    fn pick_move(&mut self, chess_state: &ChessState) -> Option<Move> {
+        let moves = &chess_state.legal_moves;
+        if moves.is_empty() {
+            return None
+        }
+        let index = (self.next() % moves.len() as u64) as usize;
+        Some(moves[index])
+    }
+}
+
+/// A client connected to a server, making a move whenever it is its turn.
+pub struct Bot<P: MovePicker> {
+    pub chess_state: ChessState,
+    pub is_white: bool,
+    /// The messages of all errors sent by the server.
+    pub errors: Vec<String>,
+    pub desync_log: Vec<String>,
+    pub picker: P,
+    pub session: ClientSession,
+    /// Set while a move or resignation has yet to be answered, so that it
+    /// isn't sent twice.
+    awaiting_reply: bool,
+}
+
+impl<P: MovePicker> Bot<P> {
+    /// Connects to a server and sends the handshake asking to play the given
+    /// colour. Deviations from the protocol are tolerated, see
+    /// `ClientSession::interop` to change that.
+    pub fn connect(address: &str, is_white: bool, picker: P)
+        -> std::io::Result<Self> {
+        let connection = connect_client(address, !is_white)?;
+        let mut chess_state = ChessState::new();
+        chess_state.is_client = true;
+        Ok(Bot {
+            chess_state,
+            is_white,
+            errors: vec![],
+            desync_log: vec![],
+            picker,
+            session: ClientSession::new(connection),
+            awaiting_reply: false,
+        })
+    }
+
+    /// Handles everything received so far and moves if it is our turn.
+    /// Returns false once the connection has been lost.
+    pub fn step(&mut self) -> bool {
+        let received = self.session.received;
+        let connected = self.session.update(&mut self.chess_state,
+                                            &mut self.errors,
+                                            &mut self.desync_log);
+        if self.session.received != received {
+            // Whatever the server sent, it was its answer.
+            self.awaiting_reply = false;
+        }
+
+        let s = &self.chess_state;
+        if !self.session.handshake_received || self.awaiting_reply
+            || s.is_game_over || s.is_white_turn != self.is_white {
+            return connected
+        }
+        // The move is sent as is, without checking it against the list of
+        // legal moves, so that pickers can be used to test how servers
+        // handle bad moves.
+        let message = match self.picker.pick_move(s) {
+            Some(mv) => ClientToServer::Move(mv),
+            None => ClientToServer::Resign,
+        };
+        println!("[bot] sending {message:?}");
+        self.session.connection.send(Packet::Data(message));
+        self.awaiting_reply = true;
+        connected
+    }
+
+    /// Plays until the game is over, giving up if the connection is lost or
+    /// the game takes longer than `timeout`.
+    pub fn play(&mut self, timeout: std::time::Duration)
+        -> Result<GameEnd, String> {
+        let start = std::time::Instant::now();
+        loop {
+            let connected = self.step();
+            if let Some(end) = self.chess_state.end {
+                return Ok(end)
+            }
+            if !connected {
+                let interop = &self.session.interop;
+                return Err(match interop.deviations.last() {
+                    Some(deviation) if interop.mode == InteropMode::Strict => {
+                        format!("hung up in strict mode: {deviation}")
+                    }
+                    _ => "the connection was lost".into(),
+                })
+            }
+            if self.session.timed_out() {
+                return Err("the server stopped responding".into())
+            }
+            if start.elapsed() > timeout {
+                return Err("the game timed out".into())
+            }
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{handle_client_move, send_server_handshake,
+                synchronize_board_state, Connection, HostConnection,
+                Packet, UnsentNetMove};
+    use crate::bot::*;
+    use chess_network_protocol::Color;
+
+    #[test]
+    pub fn random_mover() {
+        let chess_state = ChessState::new();
+        let mut mover = RandomMover::with_seed(8483);
+        for _ in 0..100 {
+            let mv = mover.pick_move(&chess_state).unwrap();
+            assert!(chess_state.is_legal_move(&mv));
+        }
+
+        let mut mover = RandomMover::with_seed(0);
+        let mut over = ChessState::new();
+        over.legal_moves.clear();
+        assert_eq!(mover.pick_move(&over), None);
+    }
+
+    #[test]
+    pub fn bot_plays_host() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let mut bot = Bot::connect(&address, false, RandomMover::with_seed(1))
+            .unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let connection: HostConnection = Connection::spawn(stream).unwrap();
+
+        // The host plays white with a random mover of its own.
+        let mut host_state = ChessState::new();
+        host_state.is_host = true;
+        let mut host_mover = RandomMover::with_seed(2);
+        let mut packets = vec![];
+        let start = std::time::Instant::now();
+        let mut plies = 0;
+        let mut handshake_sent = false;
+        while plies < 40 && !host_state.is_game_over {
+            assert!(start.elapsed().as_secs() < 10, "the game got stuck");
+            assert!(connection.poll(&mut packets));
+            for packet in packets.drain(..) {
+                match packet {
+                    Packet::Handshake(h) => {
+                        assert!(h.server_color == Color::White);
+                        send_server_handshake(&connection, &host_state, &[]);
+                        handshake_sent = true;
+                    }
+                    Packet::Data(ClientToServer::Move(mv)) => {
+                        handle_client_move(&connection, &mv, &mut host_state)
+                            .unwrap();
+                        synchronize_board_state(&connection, &mut host_state);
+                        plies += 1;
+                    }
+                    Packet::Data(d) => panic!("unexpected {d:?}"),
+                }
+            }
+            if handshake_sent && host_state.is_white_turn
+                && !host_state.is_game_over {
+                let mv = host_mover.pick_move(&host_state).unwrap();
+                assert!(host_state.ingest_client_move(&mv));
+                plies += 1;
+            }
+            assert!(!matches!(host_state.unsent_net_move,
+                              UnsentNetMove::PendingPromotion(_)));
+            synchronize_board_state(&connection, &mut host_state);
+            bot.step();
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+
+        // Give the last state time to arrive.
+        let start = std::time::Instant::now();
+        while bot.chess_state.chess_representation
+            != host_state.chess_representation
+            && start.elapsed().as_secs() < 10 {
+            bot.step();
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+        assert_eq!(bot.chess_state.chess_representation,
+                   host_state.chess_representation);
+        assert_eq!(bot.errors, Vec::<String>::new());
+        assert_eq!(bot.desync_log, Vec::<String>::new());
+    }
+}
@@ -0,0 +1,61 @@
+//! Chatting with the opponent over the connection the game is played on.
+//!
+//! Chat is not part of the protocol, so it is only used with peers that have
+//! agreed to it: hosts advertise `CHAT_FEATURE` in their handshake, and
+//! clients that want to chat answer with a line without text. Peers that
+//! know nothing of chat are never sent any.
+
+use crate::extension::Extension;
+use crate::net::Connection;
+
+/// The feature hosts advertise in their handshake if they can chat.
+pub const CHAT_FEATURE: &str = "Chat";
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChatLine {
+    /// Whether we wrote the line, rather than the peer.
+    pub ours: bool,
+    pub text: String,
+}
+
+/// The chat alongside a game.
+pub struct Chat {
+    /// Whether we want to chat with peers that can.
+    pub enabled: bool,
+    /// Whether the peer has said that it can chat.
+    pub peer_can_chat: bool,
+    pub lines: Vec<ChatLine>,
+}
+
+impl Chat {
+    pub fn new() -> Self {
+        Chat { enabled: false, peer_can_chat: false, lines: vec![] }
+    }
+
+    /// Whether both we and the peer want to chat.
+    pub fn is_open(&self) -> bool {
+        self.enabled && self.peer_can_chat
+    }
+
+    /// Takes in a line the peer has sent. Any line, even one without text,
+    /// tells us that the peer can chat.
+    pub fn receive(&mut self, text: String) {
+        self.peer_can_chat = true;
+        if self.enabled && !text.is_empty() {
+            self.lines.push(ChatLine { ours: false, text });
+        }
+    }
+
+    /// Sends a line, if chat is open and there is something to say.
+    pub fn send<InHandshake, InData, OutHandshake, OutData>(
+        &mut self,
+        connection: &Connection<InHandshake, InData, OutHandshake, OutData>,
+        text: &str) {
+        let text = text.trim();
+        if !self.is_open() || text.is_empty() {
+            return
+        }
+        connection.send_extension(Extension::Chat(text.into()));
+        self.lines.push(ChatLine { ours: true, text: text.into() });
+    }
+}
@@ -0,0 +1,320 @@
+//! Chess clocks. The host keeps the time of network games: it advertises
+//! `CLOCK_FEATURE` in its handshake, clients that keep time answer with a
+//! clock without a state, and from then on they are sent the state of the
+//! clock whenever it changes. When a flag falls the host ends the game, which
+//! peers without clocks learn of from the usual resignation or draw message.
+
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::game::{ChessState, GameEnd};
+
+/// The feature hosts advertise in their handshake if the game is timed.
+pub const CLOCK_FEATURE: &str = "Clock";
+
+/// How much time each player gets.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum TimeControl {
+    /// The whole game has to be played in the time given.
+    SuddenDeath { base_ms: u64 },
+    /// Time is added after every move.
+    Increment { base_ms: u64, increment_ms: u64 },
+    /// The clock only starts running once the delay of each move is over.
+    Delay { base_ms: u64, delay_ms: u64 },
+}
+
+impl TimeControl {
+    pub fn base(&self) -> Duration {
+        match *self {
+            TimeControl::SuddenDeath { base_ms }
+            | TimeControl::Increment { base_ms, .. }
+            | TimeControl::Delay { base_ms, .. } => {
+                Duration::from_millis(base_ms)
+            }
+        }
+    }
+
+    pub fn increment(&self) -> Duration {
+        match *self {
+            TimeControl::Increment { increment_ms, .. } => {
+                Duration::from_millis(increment_ms)
+            }
+            _ => Duration::ZERO,
+        }
+    }
+
+    pub fn delay(&self) -> Duration {
+        match *self {
+            TimeControl::Delay { delay_ms, .. } => {
+                Duration::from_millis(delay_ms)
+            }
+            _ => Duration::ZERO,
+        }
+    }
+
+    /// Parses minutes, optionally followed by seconds of increment as in
+    /// "5+3" or seconds of delay as in "5d3".
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let error = || format!("bad time control: {text}");
+        // Reads a number of the given unit as milliseconds.
+        let number = |text: &str, unit_ms: u64| text.trim().parse::<u64>()
+            .ok()
+            .and_then(|number| number.checked_mul(unit_ms))
+            .ok_or_else(error);
+        let control = if let Some((base, increment)) = text.split_once('+') {
+            TimeControl::Increment {
+                base_ms: number(base, 60_000)?,
+                increment_ms: number(increment, 1000)?,
+            }
+        } else if let Some((base, delay)) = text.split_once('d') {
+            TimeControl::Delay {
+                base_ms: number(base, 60_000)?,
+                delay_ms: number(delay, 1000)?,
+            }
+        } else {
+            TimeControl::SuddenDeath { base_ms: number(text, 60_000)? }
+        };
+        if control.base().is_zero() {
+            return Err(error())
+        }
+        Ok(control)
+    }
+}
+
+impl std::fmt::Display for TimeControl {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let minutes = self.base().as_secs() / 60;
+        match self {
+            TimeControl::SuddenDeath { .. } => write!(f, "{minutes} min"),
+            TimeControl::Increment { .. } => {
+                write!(f, "{minutes} min + {} s", self.increment().as_secs())
+            }
+            TimeControl::Delay { .. } => {
+                write!(f, "{minutes} min, {} s delay", self.delay().as_secs())
+            }
+        }
+    }
+}
+
+/// The clock as sent by the host.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct ClockState {
+    pub control: TimeControl,
+    pub white_ms: u64,
+    pub black_ms: u64,
+    /// Whether it is the clock of white that is running, if any is.
+    pub running_white: Option<bool>,
+}
+
+/// Shows the time left as minutes and seconds, and tenths of a second once
+/// it gets short.
+pub fn format_time(time: Duration) -> String {
+    let tenths = time.as_millis() / 100;
+    let seconds = tenths / 10;
+    if seconds < 10 {
+        format!("0:{seconds:02}.{}", tenths % 10)
+    } else {
+        format!("{}:{:02}", seconds / 60, seconds % 60)
+    }
+}
+
+/// The two clocks of a game.
+pub struct Clock {
+    pub control: TimeControl,
+    white: Duration,
+    black: Duration,
+    /// The side whose clock is running, and since when.
+    running: Option<(bool, Instant)>,
+}
+
+impl Clock {
+    pub fn new(control: TimeControl) -> Self {
+        Clock {
+            control,
+            white: control.base(),
+            black: control.base(),
+            running: None,
+        }
+    }
+
+    /// Takes on the clock as sent by the host. The time the state spent on
+    /// the way is not made up for.
+    pub fn from_state(state: &ClockState) -> Self {
+        Clock {
+            control: state.control,
+            white: Duration::from_millis(state.white_ms),
+            black: Duration::from_millis(state.black_ms),
+            running: state.running_white.map(|white| (white, Instant::now())),
+        }
+    }
+
+    pub fn state(&self) -> ClockState {
+        ClockState {
+            control: self.control,
+            white_ms: self.remaining(true).as_millis() as u64,
+            black_ms: self.remaining(false).as_millis() as u64,
+            running_white: self.running(),
+        }
+    }
+
+    /// The time left for the given side.
+    pub fn remaining(&self, white: bool) -> Duration {
+        let left = if white { self.white } else { self.black };
+        match self.running {
+            Some((side, since)) if side == white => {
+                let delay = self.control.delay();
+                left.saturating_sub(since.elapsed().saturating_sub(delay))
+            }
+            _ => left,
+        }
+    }
+
+    /// Whether it is the clock of white that is running, if any is.
+    pub fn running(&self) -> Option<bool> {
+        self.running.map(|(white, _)| white)
+    }
+
+    pub fn stop(&mut self) {
+        let Some((white, _)) = self.running else {
+            return
+        };
+        let left = self.remaining(white);
+        if white {
+            self.white = left;
+        } else {
+            self.black = left;
+        }
+        self.running = None;
+    }
+
+    fn start(&mut self, white: bool) {
+        self.stop();
+        self.running = Some((white, Instant::now()));
+    }
+
+    /// Ends the turn of the given side, adding any increment, and starts
+    /// the clock of the other.
+    pub fn press(&mut self, white: bool) {
+        self.stop();
+        if white {
+            self.white += self.control.increment();
+        } else {
+            self.black += self.control.increment();
+        }
+        self.start(!white);
+    }
+
+    /// The side whose time has run out, if any.
+    pub fn flagged(&self) -> Option<bool> {
+        [true, false].into_iter().find(|&white| {
+            self.remaining(white).is_zero()
+        })
+    }
+
+    /// Keeps the clock in step with the game: the clock of the side to move
+    /// runs until the game is over. Ends the game if a flag falls.
+    pub fn update(&mut self, chess_state: &mut ChessState) {
+        if chess_state.is_game_over {
+            self.stop();
+            return
+        }
+        match self.running() {
+            None => self.start(chess_state.is_white_turn),
+            Some(white) if white != chess_state.is_white_turn => {
+                self.press(white);
+            }
+            Some(_) => {}
+        }
+        if let Some(white) = self.flagged() {
+            let name = if white { "white" } else { "black" };
+            println!("[clock] {name} ran out of time");
+            self.stop();
+            chess_state.run_out_of_time(white);
+        }
+    }
+
+    /// Tells games that the host ended on time apart from those that were
+    /// resigned or drawn, as that is how the protocol has to announce them.
+    pub fn explain_end(&self, chess_state: &mut ChessState) {
+        let end = match chess_state.end {
+            Some(GameEnd::Resignation { white_won })
+                if self.remaining(!white_won).is_zero() => {
+                GameEnd::OutOfTime { white_won: Some(white_won) }
+            }
+            Some(GameEnd::DrawAgreed) if self.flagged().is_some() => {
+                GameEnd::OutOfTime { white_won: None }
+            }
+            _ => return,
+        };
+        chess_state.end = Some(end);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::clock::*;
+
+    #[test]
+    pub fn time_controls() {
+        assert_eq!(TimeControl::parse("5"),
+                   Ok(TimeControl::SuddenDeath { base_ms: 300_000 }));
+        assert_eq!(TimeControl::parse("3+2"), Ok(TimeControl::Increment {
+            base_ms: 180_000,
+            increment_ms: 2000,
+        }));
+        assert_eq!(TimeControl::parse("10d5"), Ok(TimeControl::Delay {
+            base_ms: 600_000,
+            delay_ms: 5000,
+        }));
+        assert!(TimeControl::parse("0").is_err());
+        assert!(TimeControl::parse("5+").is_err());
+        assert!(TimeControl::parse("fast").is_err());
+        // Too long to count in milliseconds.
+        assert!(TimeControl::parse("999999999999999999").is_err());
+        assert!(TimeControl::parse("5+999999999999999999").is_err());
+
+        let control = TimeControl::parse("3+2").unwrap();
+        assert_eq!(control.to_string(), "3 min + 2 s");
+        assert_eq!(format_time(Duration::from_secs(754)), "12:34");
+        assert_eq!(format_time(Duration::from_millis(9_250)), "0:09.2");
+    }
+
+    #[test]
+    pub fn clock_increment_and_delay() {
+        let control = TimeControl::Increment {
+            base_ms: 1000,
+            increment_ms: 500,
+        };
+        let mut clock = Clock::new(control);
+        clock.press(true);
+        assert_eq!(clock.running(), Some(false));
+        assert_eq!(clock.remaining(true), Duration::from_millis(1500));
+
+        // The delay is spent before the clock itself runs.
+        let control = TimeControl::Delay { base_ms: 1000, delay_ms: 60_000 };
+        let mut clock = Clock::new(control);
+        clock.start(true);
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(clock.remaining(true), Duration::from_millis(1000));
+
+        let state = clock.state();
+        assert_eq!(state.running_white, Some(true));
+        assert_eq!(Clock::from_state(&state).state(), state);
+    }
+
+    #[test]
+    pub fn flag_fall() {
+        let control = TimeControl::SuddenDeath { base_ms: 10 };
+        let mut clock = Clock::new(control);
+        let mut chess_state = ChessState::new();
+        clock.update(&mut chess_state);
+        assert_eq!(clock.running(), Some(true));
+        std::thread::sleep(Duration::from_millis(20));
+        clock.update(&mut chess_state);
+        assert!(chess_state.is_game_over);
+        assert_eq!(chess_state.end,
+                   Some(GameEnd::OutOfTime { white_won: Some(false) }));
+        assert_eq!(clock.running(), None);
+    }
+}
@@ -0,0 +1,885 @@
+//! Framing of the JSON messages sent over the connection.
+
+use std::marker::PhantomData;
+
+use crate::interop::Deviation;
+
+enum JsonState {
+    Normal,
+    String,
+    StringEscape,
+    /// A number, literal or plain junk outside of any object. It lasts until
+    /// the next byte that could start or end a value.
+    Scalar,
+    /// Bytes skipped to get back in step with the messages after losing
+    /// track of them. Quotes can no longer be trusted to start strings, so
+    /// everything up to the next `{` is skipped.
+    Junk,
+}
+
+/// What may come next inside an object or array.
+#[derive(Clone, Copy, PartialEq)]
+enum Expect {
+    Value,
+    ValueOrEnd,
+    Key,
+    KeyOrEnd,
+    Colon,
+    CommaOrEnd,
+    /// The rest of a number or literal.
+    Scalar,
+}
+
+/// Because serde_json exposes no way to know when serialization ended we
+/// implement this ourselves.
+pub struct JsonFinder {
+    pub length: usize,
+    nesting: i32,
+    state: JsonState,
+    /// The brackets closing the objects and arrays we are in.
+    closers: Vec<u8>,
+    expect: Expect,
+    /// Whether the string we are in is the key of an object.
+    in_key: bool,
+    /// Set when the byte at `length` can not be part of a JSON value, which
+    /// means that the value will never end where the finder expects it to.
+    pub malformed: bool,
+}
+
+impl JsonFinder {
+    pub fn new() -> Self {
+        return JsonFinder {
+            length: 0,
+            nesting: 0,
+            state: JsonState::Normal,
+            closers: vec![],
+            expect: Expect::Value,
+            in_key: false,
+            malformed: false,
+        }
+    }
+    pub fn reset(&mut self) {
+        self.length = 0;
+        self.nesting = 0;
+        self.state = JsonState::Normal;
+        self.closers.clear();
+        self.expect = Expect::Value;
+        self.malformed = false;
+    }
+
+    fn open(&mut self, b: u8) {
+        if b == b'{' {
+            self.closers.push(b'}');
+            self.expect = Expect::KeyOrEnd;
+        } else {
+            self.closers.push(b']');
+            self.expect = Expect::ValueOrEnd;
+        }
+    }
+
+    /// Checks a byte outside of strings against what may come next in the
+    /// objects and arrays we are in. Anything outside of them is left to
+    /// `feed`.
+    fn follows_syntax(&mut self, b: u8) -> bool {
+        let Some(&closer) = self.closers.last() else {
+            if b == b'{' || b == b'[' {
+                self.open(b);
+            }
+            return true
+        };
+        let scalar = b.is_ascii_alphanumeric() || b"+-.".contains(&b);
+        if self.expect == Expect::Scalar {
+            if scalar {
+                return true
+            }
+            self.expect = Expect::CommaOrEnd;
+        }
+        if b.is_ascii_whitespace() {
+            return true
+        }
+        match (self.expect, b) {
+            (Expect::Value | Expect::ValueOrEnd, b'"') => {
+                self.in_key = false;
+            }
+            (Expect::Value | Expect::ValueOrEnd, b'{' | b'[') => self.open(b),
+            (Expect::Value | Expect::ValueOrEnd, _) if scalar => {
+                self.expect = Expect::Scalar;
+            }
+            (Expect::Key | Expect::KeyOrEnd, b'"') => self.in_key = true,
+            (Expect::Colon, b':') => self.expect = Expect::Value,
+            (Expect::CommaOrEnd, b',') => {
+                self.expect = if closer == b'}' {
+                    Expect::Key
+                } else {
+                    Expect::Value
+                };
+            }
+            (Expect::ValueOrEnd | Expect::KeyOrEnd | Expect::CommaOrEnd, _)
+                if b == closer => {
+                self.closers.pop();
+                self.expect = Expect::CommaOrEnd;
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    /// Returns true once the finder has found a complete JSON value, which
+    /// ends at `length`. Stray closing brackets and runs of bytes that are
+    /// neither objects, arrays nor strings count as values of their own, so
+    /// that they can be thrown away without losing track of the messages
+    /// after them. Objects and arrays that break the syntax of JSON stop the
+    /// search, see `malformed`.
+    pub fn feed(&mut self, bytes: &[u8]) -> bool {
+        for b in bytes {
+            match self.state {
+                JsonState::Normal => {
+                    if !self.follows_syntax(*b) {
+                        self.malformed = true;
+                        return false;
+                    }
+                    match b {
+                        b'"' => self.state = JsonState::String,
+                        b'{' | b'[' => self.nesting += 1,
+                        b'}' | b']' => {
+                            self.nesting -= 1;
+                            if self.nesting <= 0 {
+                                return true;
+                            }
+                        },
+                        _ if self.nesting == 0
+                            && !b.is_ascii_whitespace() => {
+                            self.state = JsonState::Scalar;
+                        }
+                        _ => {},
+                    }
+                },
+                JsonState::StringEscape => self.state = JsonState::String,
+                JsonState::String => match b {
+                    // Unit variants such as `ClientToServer::Resign` are
+                    // sent as bare strings.
+                    b'"' if self.nesting == 0 => return true,
+                    b'"'  => {
+                        self.state = JsonState::Normal;
+                        self.expect = if self.in_key {
+                            Expect::Colon
+                        } else {
+                            Expect::CommaOrEnd
+                        };
+                    }
+                    b'\\' => self.state = JsonState::StringEscape,
+                    _ => {}
+                },
+                JsonState::Scalar => match b {
+                    b'"' | b'{' | b'[' | b'}' | b']' => {
+                        // The scalar ended with the byte before this one.
+                        self.length -= 1;
+                        return true;
+                    }
+                    _ if b.is_ascii_whitespace() => {
+                        self.length -= 1;
+                        return true;
+                    }
+                    _ => {}
+                },
+                JsonState::Junk => if *b == b'{' {
+                    if self.length > 0 {
+                        self.length -= 1;
+                        return true;
+                    }
+                    self.state = JsonState::Normal;
+                    self.nesting += 1;
+                    self.open(b'{');
+                },
+            }
+            self.length += 1;
+        }
+        false
+    }
+}
+
+/// How `JsonPoller` tells where one message ends and the next begins.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Framing {
+    /// Scanning the bytes with `JsonFinder` and parsing a message once it is
+    /// complete.
+    Finder,
+    /// Letting serde_json's `StreamDeserializer` parse the messages as they
+    /// come, and tell how far into the stream it got.
+    Streaming,
+}
+
+/// The longest message accepted by default. The longest ones sent by the
+/// protocol, states listing every legal move, are a few kilobytes.
+pub const MAX_MESSAGE_SIZE: usize = 256 * 1024;
+
+pub struct JsonPoller<Handshake: serde::de::DeserializeOwned,
+                  Data: serde::de::DeserializeOwned> {
+    finder: JsonFinder,
+    buf: Vec<u8>,
+    framing: Framing,
+    handshake_complete: bool,
+    /// Ways in which the stream did not follow the protocol, for the caller
+    /// to take.
+    pub deviations: Vec<Deviation>,
+    /// Tells the data messages that may be sent at any time, even before
+    /// the handshake, without standing in for it.
+    pub out_of_band: fn(&Data) -> bool,
+    /// Messages longer than this are thrown away.
+    pub max_message_size: usize,
+    /// How much of an overly long message has been thrown away so far. Such
+    /// a message is skipped until it ends.
+    skipped: usize,
+    /// Set after losing track of where messages start, until the next
+    /// message has been read.
+    resynchronizing: bool,
+
+    // This is done such that the compiler doesn't complain about unused
+    // generics. Function pointers are used as they, unlike raw pointers,
+    // let the poller be sent to the network thread.
+    _phantom1: std::marker::PhantomData<fn() -> Handshake>,
+    _phantom2: std::marker::PhantomData<fn() -> Data>,
+
+}
+
+#[derive(Eq, PartialEq, Debug)]
+pub enum Packet<Handshake, Data> {
+    Handshake(Handshake),
+    Data(Data),
+}
+
+/// The start of a message, for telling the user what it looked like.
+fn excerpt(data: &[u8]) -> String {
+    let excerpt = String::from_utf8_lossy(&data[..data.len().min(32)]);
+    if data.len() > 32 {
+        format!("{excerpt}...")
+    } else {
+        excerpt.into()
+    }
+}
+
+impl<Handshake: serde::de::DeserializeOwned,
+     Data: serde::de::DeserializeOwned> JsonPoller<Handshake, Data> {
+    pub fn new() -> Self {
+        Self::with_framing(Framing::Finder)
+    }
+
+    pub fn with_framing(framing: Framing) -> Self {
+        return JsonPoller::<Handshake, Data> {
+            finder: JsonFinder::new(),
+            buf: vec![],
+            framing,
+            handshake_complete: false,
+            deviations: vec![],
+            out_of_band: |_| false,
+            max_message_size: MAX_MESSAGE_SIZE,
+            skipped: 0,
+            resynchronizing: false,
+            _phantom1: PhantomData,
+            _phantom2: PhantomData,
+        }
+    }
+
+    /// The number of bytes of incomplete messages held on to.
+    pub fn buffered(&self) -> usize {
+        self.buf.len()
+    }
+
+    pub fn feed(&mut self, data: &[u8], into: &mut Vec<Packet<Handshake, Data>>) {
+        match self.framing {
+            Framing::Finder => self.feed_finder(data, into),
+            Framing::Streaming => self.feed_streaming(data, into),
+        }
+    }
+
+    fn feed_finder(&mut self, data: &[u8],
+                   into: &mut Vec<Packet<Handshake, Data>>) {
+        let start = self.buf.len();
+        self.buf.extend_from_slice(data);
+        let mut scan_slice = start .. self.buf.len();
+        loop {
+            if !self.finder.feed(&self.buf[scan_slice]) {
+                if !self.check_incomplete() {
+                    break
+                }
+                scan_slice = 0..self.buf.len();
+                continue
+            }
+            let end = self.finder.length + 1 - self.skipped;
+            let rest = self.buf.split_off(end);
+            let data = std::mem::replace(&mut self.buf, rest);
+            // The end of an overly long message is thrown away like the
+            // rest of it.
+            if self.skipped == 0 {
+                self.parse(&data, into);
+            }
+            self.finder.reset();
+            if self.resynchronizing {
+                self.finder.state = JsonState::Junk;
+            }
+            self.skipped = 0;
+            scan_slice = 0..self.buf.len();
+        }
+    }
+
+    fn feed_streaming(&mut self, data: &[u8],
+                      into: &mut Vec<Packet<Handshake, Data>>) {
+        if self.skipped > 0 {
+            // Overly long messages are skipped by the finder, as it can tell
+            // where they end without holding on to them.
+            self.feed_finder(data, into);
+            return
+        }
+        self.buf.extend_from_slice(data);
+        let buf = std::mem::take(&mut self.buf);
+        let mut start = 0;
+        while start < buf.len() {
+            if self.resynchronizing {
+                // Quotes can no longer be trusted to start strings, so
+                // everything up to the next `{` is skipped.
+                match buf[start..].iter().position(|b| *b == b'{') {
+                    Some(offset) => start += offset,
+                    None => {
+                        start = buf.len();
+                        break
+                    }
+                }
+            }
+            let mut stream = serde_json::Deserializer::from_slice(&buf[start..])
+                .into_iter::<serde::de::IgnoredAny>();
+            match stream.next() {
+                Some(Ok(_)) => {
+                    let end = start + stream.byte_offset();
+                    self.parse(&buf[start..end], into);
+                    start = end;
+                }
+                // Only whitespace, or the start of a message, is left.
+                None => break,
+                Some(Err(e)) if e.is_eof() => break,
+                Some(Err(e)) => {
+                    let first = start + buf[start..].iter()
+                        .position(|b| !b.is_ascii_whitespace())
+                        .unwrap_or(0);
+                    let deviation = if matches!(buf[first], b'{' | b'"') {
+                        Deviation::Malformed(e.to_string())
+                    } else {
+                        // Junk is cut off where the finder would have.
+                        let length = buf[first + 1..].iter()
+                            .position(|b| b"{}[]\"".contains(b)
+                                      || b.is_ascii_whitespace())
+                            .map_or(buf.len() - first, |end| end + 1);
+                        let junk = &buf[first..first + length];
+                        Deviation::NotAnObject(excerpt(junk))
+                    };
+                    self.lose_track(deviation);
+                    start = first + 1;
+                }
+            }
+        }
+        self.buf = buf;
+        self.buf.drain(..start);
+        if self.buf.len() > self.max_message_size {
+            // The finder reports the message and skips the rest of it.
+            let pending = std::mem::take(&mut self.buf);
+            self.finder.reset();
+            self.feed_finder(&pending, into);
+        }
+    }
+
+    /// Throws away a message that made us lose track of where messages
+    /// start, and skips ahead to the next object. Only the first deviation
+    /// is recorded, as the bytes skipped after it are just its fallout.
+    fn lose_track(&mut self, deviation: Deviation) {
+        if !self.resynchronizing {
+            eprintln!("lost track of messages: {deviation}");
+            self.deviations.push(deviation);
+        }
+        self.resynchronizing = true;
+    }
+
+    /// Deals with the incomplete message at the start of the buffer if it
+    /// is too long or can never be parsed. Returns true if bytes were thrown
+    /// away such that the buffer should be scanned anew.
+    fn check_incomplete(&mut self) -> bool {
+        if self.skipped > 0 || self.finder.length > self.max_message_size {
+            if self.skipped == 0 {
+                let size = self.max_message_size;
+                self.deviations.push(Deviation::TooLong(size));
+            }
+            if self.finder.malformed {
+                // There is no telling where the message ends, so what is
+                // left of it is skipped on the way to the next object.
+                self.buf.drain(..self.finder.length - self.skipped + 1);
+                self.skipped = 0;
+                self.resynchronizing = true;
+                self.finder.reset();
+                self.finder.state = JsonState::Junk;
+                return true
+            }
+            // The finder keeps track of where the message ends.
+            self.skipped += self.buf.len();
+            self.buf.clear();
+            return false
+        }
+        let Some(first) = self.buf.iter()
+            .position(|b| !b.is_ascii_whitespace()) else {
+            return false
+        };
+        // A message that is no longer valid JSON, such as one cut off by
+        // the start of the next, may never end where the finder expects it
+        // to. The next message is looked for from the byte after its start.
+        if !self.finder.malformed {
+            return false
+        }
+        let at = self.finder.length;
+        let message = format!("unexpected `{}` at byte {at}",
+                              self.buf[at] as char);
+        self.lose_track(Deviation::Malformed(message));
+        self.buf.drain(..first + 1);
+        self.finder.reset();
+        self.finder.state = JsonState::Junk;
+        true
+    }
+
+    fn parse(&mut self, data: &[u8], into: &mut Vec<Packet<Handshake, Data>>) {
+        if self.resynchronizing && data[0] != b'{' {
+            // Skipped on the way to the next object.
+            return
+        }
+        let Some(first) = data.iter().position(|b| !b.is_ascii_whitespace())
+        else {
+            return
+        };
+        if first > 0 {
+            self.deviations.push(Deviation::Whitespace);
+        }
+        // Only objects are messages, apart from the strings that unit
+        // variants are sent as. Anything else, such as an array that serde
+        // would happily read a struct from, is thrown away.
+        if !matches!(data[first], b'{' | b'"') {
+            self.lose_track(Deviation::NotAnObject(excerpt(&data[first..])));
+            return
+        }
+        let error = if self.handshake_complete {
+            match serde_json::from_slice(data) {
+                Ok(v) => {
+                    into.push(Packet::Data(v));
+                    None
+                }
+                Err(_) if serde_json::from_slice::<Handshake>(data)
+                    .is_ok() => {
+                    self.deviations.push(Deviation::RepeatedHandshake);
+                    None
+                }
+                Err(e) => {
+                    eprintln!("data parse error: {e}");
+                    Some(e)
+                }
+            }
+        } else {
+            match serde_json::from_slice::<Data>(data) {
+                Ok(v) if (self.out_of_band)(&v) => {
+                    into.push(Packet::Data(v));
+                    None
+                }
+                as_data => match serde_json::from_slice(data) {
+                    Ok(v) => {
+                        self.handshake_complete = true;
+                        into.push(Packet::Handshake(v));
+                        None
+                    }
+                    // Some peers skip the handshake altogether, which we
+                    // can live with as long as the message makes sense.
+                    Err(e) => match as_data {
+                        Ok(v) => {
+                            self.handshake_complete = true;
+                            self.deviations.push(Deviation::MissingHandshake);
+                            into.push(Packet::Data(v));
+                            None
+                        }
+                        Err(_) => {
+                            eprintln!("handshake parse error: {e}");
+                            Some(e)
+                        }
+                    },
+                },
+            }
+        };
+        match error {
+            // The message was framed wrongly, so the next one may be too.
+            Some(e) if e.is_syntax() => {
+                self.lose_track(Deviation::Malformed(e.to_string()));
+            }
+            Some(e) => {
+                self.deviations.push(Deviation::Malformed(e.to_string()));
+                self.resynchronizing = false;
+            }
+            None => self.resynchronizing = false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::codec::*;
+    use serde::Deserialize;
+
+    #[test]
+    pub fn json_finder() {
+        let mut finder = JsonFinder::new();
+        assert_eq!(finder.feed(b"{\"hi\": 3"), false);
+        assert_eq!(finder.feed(b"2 } excess data here"), true);
+        assert_eq!(finder.length, 10);
+
+        finder.reset();
+        assert_eq!(finder.feed(b"{\"hi\": \""), false);
+        assert_eq!(finder.feed(b"s\\\\t\\\"r\" } excess data here"), true);
+        assert_eq!(finder.length, 17);
+
+        // Values that are not objects are found all the same.
+        finder.reset();
+        assert_eq!(finder.feed(b" [1, {\"a\": [2]}] {"), true);
+        assert_eq!(finder.length, 15);
+        finder.reset();
+        assert_eq!(finder.feed(b"} {"), true);
+        assert_eq!(finder.length, 0);
+        finder.reset();
+        assert_eq!(finder.feed(b"nul"), false);
+        assert_eq!(finder.feed(b"l{"), true);
+        assert_eq!(finder.length, 3);
+        finder.reset();
+        assert_eq!(finder.feed(b"\n12 "), true);
+        assert_eq!(finder.length, 2);
+
+        // Objects that can never be valid are told apart as soon as they
+        // break, rather than once they seem to end.
+        finder.reset();
+        assert_eq!(finder.feed(b"{\"a\": [1, \"b\", {}], \"c\""), false);
+        assert!(!finder.malformed);
+        assert_eq!(finder.feed(b" 2}"), false);
+        assert!(finder.malformed);
+        assert_eq!(finder.length, 24);
+        finder.reset();
+        assert_eq!(finder.feed(b"{\"a\": \"\"b\": 1}"), false);
+        assert!(finder.malformed);
+        assert_eq!(finder.length, 8);
+    }
+
+    #[test]
+    pub fn json_poller_resign_and_draw() {
+        use chess_network_protocol::{ClientToServer, ClientToServerHandshake,
+                                     Color};
+        // Resigning and offering a draw are sent as bare strings, which
+        // have to be told apart from what follows them.
+        let handshake = ClientToServerHandshake { server_color: Color::White };
+        let messages = [ClientToServer::Resign, ClientToServer::Draw,
+                        ClientToServer::Resign];
+        let mut bytes = serde_json::to_vec(&handshake).unwrap();
+        for message in &messages {
+            bytes.extend(serde_json::to_vec(message).unwrap());
+        }
+        let mut poller =
+            JsonPoller::<ClientToServerHandshake, ClientToServer>::new();
+        let mut into = vec![];
+        for byte in &bytes {
+            poller.feed(std::slice::from_ref(byte), &mut into);
+        }
+        let mut expected = vec![Packet::Handshake(handshake)];
+        expected.extend(messages.into_iter().map(Packet::Data));
+        assert_eq!(into, expected);
+    }
+
+    #[derive(Deserialize, Debug, Eq, PartialEq)]
+    struct TestStruct {
+        hi: String,
+    }
+
+    #[test]
+    pub fn json_poller() {
+        let mut poller  = JsonPoller::<TestStruct, TestStruct>::new();
+        let mut into = vec![];
+        poller.feed(b"{\"hi\": \"", &mut into);
+        assert_eq!(into, vec![]);
+        poller.feed(b"s\\\\t\\\"r\" }{\"hi\": \"hey\"}", &mut into);
+        assert_eq!(into, vec![
+            Packet::Handshake(TestStruct  {
+                hi: "s\\t\"r".into(),
+            }),
+            Packet::Data(TestStruct {
+                hi: "hey".into(),
+            })
+        ]);
+        into.clear();
+        poller.feed(b" ", &mut into);
+        assert_eq!(into, vec![]);
+    }
+
+    #[test]
+    pub fn json_poller_empty() {
+        let mut poller  = JsonPoller::<TestStruct, TestStruct>::new();
+        let mut into = vec![];
+        poller.feed(b"{\"hi\": \"there\"}", &mut into);
+        assert_eq!(into, vec![Packet::Handshake(TestStruct  {
+                hi: "there".into(),
+        })]);
+        into.clear();
+        poller.feed(b"", &mut into);
+        assert_eq!(into, vec![]);
+    }
+
+    #[derive(Deserialize, Debug, Eq, PartialEq)]
+    enum TestEnum {
+        Unit,
+        Struct { hi: String },
+    }
+
+    #[test]
+    pub fn json_poller_unit_variants() {
+        let mut poller  = JsonPoller::<TestStruct, TestEnum>::new();
+        let mut into = vec![];
+        poller.feed(b"{\"hi\": \"there\"}\"Un", &mut into);
+        poller.feed(b"it\"{\"Struct\": {\"hi\": \"\\\"\"}} \"Unit\"",
+                    &mut into);
+        assert_eq!(into, vec![
+            Packet::Handshake(TestStruct {
+                hi: "there".into(),
+            }),
+            Packet::Data(TestEnum::Unit),
+            Packet::Data(TestEnum::Struct {
+                hi: "\"".into(),
+            }),
+            Packet::Data(TestEnum::Unit),
+        ]);
+    }
+
+    fn poll_deviations(framing: Framing) {
+        let mut poller =
+            JsonPoller::<TestStruct, TestEnum>::with_framing(framing);
+        let mut into = vec![];
+        // No handshake, newlines after each message and a broken message.
+        poller.feed(b"\"Unit\"\n{\"Unit\": 3}\n\"Unit\"", &mut into);
+        poller.feed(b"{\"hi\": \"again\"}", &mut into);
+        assert_eq!(into, vec![
+            Packet::Data(TestEnum::Unit),
+            Packet::Data(TestEnum::Unit),
+        ]);
+        assert_eq!(poller.deviations.len(), 5);
+        assert_eq!(poller.deviations[0], Deviation::MissingHandshake);
+        assert_eq!(poller.deviations[1], Deviation::Whitespace);
+        assert!(matches!(poller.deviations[2], Deviation::Malformed(_)));
+        assert_eq!(poller.deviations[3], Deviation::Whitespace);
+        assert_eq!(poller.deviations[4], Deviation::RepeatedHandshake);
+    }
+
+    #[test]
+    pub fn json_poller_deviations() {
+        poll_deviations(Framing::Finder);
+        poll_deviations(Framing::Streaming);
+    }
+
+    fn poll_resync(framing: Framing) {
+        let mut poller =
+            JsonPoller::<TestStruct, TestEnum>::with_framing(framing);
+        let mut into = vec![];
+        // Junk before the handshake does not take its place.
+        poller.feed(b"GET / HTTP/1.1\r\n\r\n{\"hi\": \"there\"}", &mut into);
+        // An array that serde would read a `TestStruct` from.
+        poller.feed(b"[\"a\"]\"Unit\"{\"Struct\": {\"hi\": \"a\"}}", &mut into);
+        // A stray bracket and a message cut off by the next one, which
+        // throws off which quotes start strings.
+        poller.feed(b"}{\"Struct\": {\"hi\": \"\"Unit\"", &mut into);
+        poller.feed(b"{\"Struct\": {\"hi\": \"b\"}}\"Unit\"", &mut into);
+        // Broken objects and numbers.
+        poller.feed(b"{\"Unit\" 1}\"Unit\"12.5{\"Struct\": {\"hi\": \"c\"}}",
+                    &mut into);
+        assert_eq!(into, vec![
+            Packet::Handshake(TestStruct {
+                hi: "there".into(),
+            }),
+            Packet::Data(TestEnum::Struct {
+                hi: "a".into(),
+            }),
+            Packet::Data(TestEnum::Struct {
+                hi: "b".into(),
+            }),
+            Packet::Data(TestEnum::Unit),
+            Packet::Data(TestEnum::Struct {
+                hi: "c".into(),
+            }),
+        ]);
+        assert_eq!(poller.buffered(), 0);
+        assert!(matches!(&poller.deviations[..], [
+            Deviation::NotAnObject(get),
+            Deviation::NotAnObject(array),
+            Deviation::NotAnObject(bracket),
+            Deviation::Malformed(_),
+        ] if get == "GET" && array == "[\"a\"]" && bracket == "}"),
+            "{:?}", poller.deviations);
+    }
+
+    #[test]
+    pub fn json_poller_resync() {
+        poll_resync(Framing::Finder);
+        poll_resync(Framing::Streaming);
+    }
+
+    fn poll_max_message_size(framing: Framing) {
+        let mut poller =
+            JsonPoller::<TestStruct, TestEnum>::with_framing(framing);
+        poller.max_message_size = 16;
+        let mut into = vec![];
+        poller.feed(b"{\"hi\": \"there\"}{\"Struct\": {\"hi\": \"", &mut into);
+        for _ in 0..100 {
+            poller.feed(b"{}[]\\\"", &mut into);
+            assert!(poller.buffered() <= poller.max_message_size);
+        }
+        poller.feed(b"\"}}\"Unit\"", &mut into);
+        assert_eq!(into, vec![
+            Packet::Handshake(TestStruct {
+                hi: "there".into(),
+            }),
+            Packet::Data(TestEnum::Unit),
+        ]);
+        assert_eq!(poller.deviations, vec![Deviation::TooLong(16)]);
+        assert_eq!(poller.buffered(), 0);
+    }
+
+    #[test]
+    pub fn json_poller_trickle() {
+        // A message just short of the limit, which is checked as it comes
+        // without going over what came before again.
+        let mut message = b"{\"Struct\": {\"hi\": \"a\", \"rest\": [".to_vec();
+        while message.len() < MAX_MESSAGE_SIZE - 32 {
+            message.extend(b"{\"b\": [1, true, null]}, ");
+        }
+        message.extend(b"\"c\"]}}");
+        let mut poller = JsonPoller::<TestStruct, TestEnum>::new();
+        let mut into = vec![];
+        poller.feed(b"{\"hi\": \"there\"}", &mut into);
+        let start = std::time::Instant::now();
+        for byte in &message {
+            poller.feed(std::slice::from_ref(byte), &mut into);
+        }
+        assert!(start.elapsed() < std::time::Duration::from_secs(5),
+                "took {:?}", start.elapsed());
+        assert_eq!(into.len(), 2);
+        assert!(poller.deviations.is_empty(), "{:?}", poller.deviations);
+    }
+
+    #[test]
+    pub fn json_poller_max_message_size() {
+        poll_max_message_size(Framing::Finder);
+        poll_max_message_size(Framing::Streaming);
+    }
+
+    fn poll_out_of_band(framing: Framing) {
+        let mut poller =
+            JsonPoller::<TestStruct, TestEnum>::with_framing(framing);
+        poller.out_of_band = |data| *data == TestEnum::Unit;
+        let mut into = vec![];
+        // Such messages may come before the handshake without taking its
+        // place.
+        poller.feed(b"\"Unit\"{\"hi\": \"there\"}\"Un", &mut into);
+        poller.feed(b"it\"", &mut into);
+        assert_eq!(into, vec![
+            Packet::Data(TestEnum::Unit),
+            Packet::Handshake(TestStruct {
+                hi: "there".into(),
+            }),
+            Packet::Data(TestEnum::Unit),
+        ]);
+        assert!(poller.deviations.is_empty());
+    }
+
+    #[test]
+    pub fn json_poller_out_of_band() {
+        poll_out_of_band(Framing::Finder);
+        poll_out_of_band(Framing::Streaming);
+    }
+
+    /// A stream of every kind of message the client may be sent, with
+    /// strings that look like the framing.
+    fn server_stream() -> Vec<u8> {
+        use chess_network_protocol::*;
+        let chess_state = crate::game::ChessState::new();
+        let board = crate::wire::chess_representaiton_to_wire(
+            &chess_state.chess_representation);
+        // A couple of moves are enough, and keep the test quick.
+        let moves = chess_state.legal_moves[..2].to_vec();
+        let mut stream = serde_json::to_vec(&ServerToClientHandshake {
+            features: vec![Features::Castling,
+                           Features::Other("{\"}\\".into())],
+            board,
+            moves: moves.clone(),
+            joever: Joever::Ongoing,
+        }).unwrap();
+        let messages = [
+            ServerToClient::State {
+                board,
+                moves: moves.clone(),
+                joever: Joever::Ongoing,
+                move_made: moves[0],
+            },
+            ServerToClient::Error {
+                board,
+                moves: moves.clone(),
+                joever: Joever::Ongoing,
+                message: "} \"Resign\" {".into(),
+            },
+            ServerToClient::Draw { board, moves },
+            ServerToClient::Resigned { board, joever: Joever::White },
+        ];
+        for message in &messages {
+            stream.extend(serde_json::to_vec(message).unwrap());
+        }
+        stream
+    }
+
+    fn poll_pieces(framing: Framing, stream: &[u8], cuts: &[usize])
+        -> Vec<Packet<chess_network_protocol::ServerToClientHandshake,
+                      chess_network_protocol::ServerToClient>> {
+        let mut poller = JsonPoller::with_framing(framing);
+        let mut into = vec![];
+        let mut start = 0;
+        for &cut in cuts.iter().chain([stream.len()].iter()) {
+            poller.feed(&stream[start..cut], &mut into);
+            start = cut;
+        }
+        assert!(poller.deviations.is_empty(), "{:?}", poller.deviations);
+        assert_eq!(poller.buffered(), 0);
+        into
+    }
+
+    fn split_streams(framing: Framing) {
+        let stream = server_stream();
+        let whole = poll_pieces(Framing::Finder, &stream, &[]);
+        assert_eq!(whole.len(), 5);
+
+        // However a stream is cut into pieces, the same messages come out.
+        for cut in 0..=stream.len() {
+            assert_eq!(poll_pieces(framing, &stream, &[cut]), whole,
+                       "cut at {cut}");
+        }
+        let mut seed = 0x9e3779b97f4a7c15u64;
+        for _ in 0..200 {
+            let mut cuts = vec![];
+            for _ in 0..8 {
+                seed ^= seed << 13;
+                seed ^= seed >> 7;
+                seed ^= seed << 17;
+                cuts.push(seed as usize % (stream.len() + 1));
+            }
+            cuts.sort();
+            assert_eq!(poll_pieces(framing, &stream, &cuts), whole,
+                       "cuts at {cuts:?}");
+        }
+        let bytes: Vec<usize> = (0..=stream.len()).collect();
+        assert_eq!(poll_pieces(framing, &stream, &bytes), whole);
+    }
+
+    #[test]
+    pub fn json_poller_split_streams() {
+        split_streams(Framing::Finder);
+        split_streams(Framing::Streaming);
+    }
+}
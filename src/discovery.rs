@@ -0,0 +1,167 @@
+//! Finding games on the local network. Hosts waiting for an opponent
+//! broadcast an announcement over UDP every now and then, which those
+//! picking a game listen for.
+
+use std::net::{Ipv4Addr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+/// The UDP port announcements are sent to.
+pub const DISCOVERY_PORT: u16 = 8484;
+
+/// How often hosts announce themselves.
+pub const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long a game is listed after its last announcement.
+pub const GAME_EXPIRY: Duration = Duration::from_secs(3);
+
+/// Tells announcements apart from anything else sent to the port.
+const PROTOCOL_NAME: &str = "gchess";
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Announcement {
+    pub protocol: String,
+    /// The port the game is hosted on, at the address the announcement was
+    /// sent from.
+    pub port: u16,
+}
+
+/// Announces a game on behalf of a host.
+pub struct Announcer {
+    socket: UdpSocket,
+    target: SocketAddr,
+    message: Vec<u8>,
+    last_sent: Option<Instant>,
+}
+
+impl Announcer {
+    /// Announces the game hosted on `port` to everyone on the local
+    /// network.
+    pub fn broadcast(port: u16) -> std::io::Result<Self> {
+        let target = (Ipv4Addr::BROADCAST, DISCOVERY_PORT).into();
+        Announcer::new(port, target)
+    }
+
+    /// Announces the game hosted on `port` to `target` alone.
+    pub fn new(port: u16, target: SocketAddr) -> std::io::Result<Self> {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+        socket.set_broadcast(true)?;
+        let announcement = Announcement {
+            protocol: PROTOCOL_NAME.into(),
+            port,
+        };
+        let message = serde_json::to_vec(&announcement)
+            .expect("announcements always serialize");
+        Ok(Announcer { socket, target, message, last_sent: None })
+    }
+
+    /// Sends the announcement if it has not been sent in a while. Failing
+    /// to do so only means that the game cannot be found, so errors are
+    /// printed rather than returned.
+    pub fn update(&mut self) {
+        if self.last_sent.is_some_and(|t| t.elapsed() < ANNOUNCE_INTERVAL) {
+            return
+        }
+        self.last_sent = Some(Instant::now());
+        if let Err(e) = self.socket.send_to(&self.message, self.target) {
+            eprintln!("[discovery] failed to announce: {e}");
+        }
+    }
+}
+
+/// A game found on the local network.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DiscoveredGame {
+    pub address: SocketAddr,
+    pub last_seen: Instant,
+}
+
+/// Listens for announcements and keeps a list of the games still being
+/// announced.
+pub struct Discovery {
+    socket: UdpSocket,
+    games: Vec<DiscoveredGame>,
+    /// How long a game is listed after its last announcement.
+    pub expiry: Duration,
+}
+
+impl Discovery {
+    pub fn bind(address: impl ToSocketAddrs) -> std::io::Result<Self> {
+        let socket = UdpSocket::bind(address)?;
+        socket.set_nonblocking(true)?;
+        Ok(Discovery { socket, games: vec![], expiry: GAME_EXPIRY })
+    }
+
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// Takes in every announcement received so far and forgets the games
+    /// that have not been announced for a while.
+    pub fn update(&mut self) {
+        let mut buffer = [0u8; 1024];
+        while let Ok((length, source)) = self.socket.recv_from(&mut buffer) {
+            let Ok(announcement) =
+                serde_json::from_slice::<Announcement>(&buffer[..length])
+            else {
+                continue
+            };
+            if announcement.protocol != PROTOCOL_NAME {
+                continue
+            }
+            let address = SocketAddr::new(source.ip(), announcement.port);
+            let last_seen = Instant::now();
+            match self.games.iter_mut().find(|g| g.address == address) {
+                Some(game) => game.last_seen = last_seen,
+                None => {
+                    println!("[discovery] found a game at {address}");
+                    self.games.push(DiscoveredGame { address, last_seen });
+                }
+            }
+        }
+        let expiry = self.expiry;
+        self.games.retain(|game| game.last_seen.elapsed() < expiry);
+    }
+
+    pub fn games(&self) -> &[DiscoveredGame] {
+        &self.games
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::discovery::*;
+
+    #[test]
+    pub fn discovery_loopback() {
+        let mut discovery = Discovery::bind("127.0.0.1:0").unwrap();
+        let target = discovery.local_addr().unwrap();
+        let mut announcer = Announcer::new(9000, target).unwrap();
+
+        // Anything that is not an announcement is ignored.
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.send_to(b"{\"protocol\": \"other\", \"port\": 1}", target)
+            .unwrap();
+        socket.send_to(b"hello", target).unwrap();
+
+        let start = Instant::now();
+        while discovery.games().is_empty() {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            announcer.update();
+            discovery.update();
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        let address: SocketAddr = "127.0.0.1:9000".parse().unwrap();
+        let addresses: Vec<_> = discovery.games().iter()
+            .map(|game| game.address)
+            .collect();
+        assert_eq!(addresses, vec![address]);
+
+        // Games that are no longer announced are forgotten.
+        discovery.expiry = Duration::from_millis(10);
+        std::thread::sleep(Duration::from_millis(20));
+        discovery.update();
+        assert!(discovery.games().is_empty());
+    }
+}
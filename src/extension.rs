@@ -0,0 +1,120 @@
+//! Messages of our own, sent alongside those of the protocol to peers that
+//! have agreed to them. They are decoded along with the packets, so that
+//! they reach the sessions in the order they were sent: a request for a
+//! rematch sent after a resignation belongs to the game after it.
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::clock::ClockState;
+use crate::codec::{Framing, JsonPoller, Packet};
+use crate::rematch::RematchRequest;
+
+/// A message that is not part of the protocol, sent the way serde writes
+/// enums, e.g. `"Heartbeat"` or `{"Chat": "..."}`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum Extension {
+    /// Sent by peers that have opted in to heartbeats whenever they have had
+    /// nothing else to say for a while.
+    Heartbeat,
+    /// A line of chat. A line without text only says that chat is supported.
+    Chat(String),
+    /// The state of the clock, sent by hosts of timed games to clients that
+    /// keep time. A clock without a state only says that clocks are
+    /// supported.
+    Clock(Option<ClockState>),
+    /// A request to play again.
+    Rematch(RematchRequest),
+}
+
+/// The data messages of the protocol along with our own, which are tried
+/// first.
+#[derive(Deserialize, Debug, PartialEq)]
+#[serde(untagged)]
+pub enum ExtendedData<Data> {
+    Extension(Extension),
+    Data(Data),
+}
+
+/// What is received over a connection, in the order it was sent.
+#[derive(Debug, PartialEq)]
+pub enum Incoming<Handshake, Data> {
+    Packet(Packet<Handshake, Data>),
+    Extension(Extension),
+}
+
+impl<Handshake, Data> Incoming<Handshake, Data> {
+    /// Tells our messages apart from the packets decoded along with them.
+    pub fn split(packet: Packet<Handshake, ExtendedData<Data>>) -> Self {
+        match packet {
+            Packet::Handshake(h) => Incoming::Packet(Packet::Handshake(h)),
+            Packet::Data(ExtendedData::Data(d)) => {
+                Incoming::Packet(Packet::Data(d))
+            }
+            Packet::Data(ExtendedData::Extension(e)) => Incoming::Extension(e),
+        }
+    }
+}
+
+/// A poller that decodes our messages along with the packets. They may come
+/// before the handshake without taking its place.
+pub fn extended_poller<Handshake: DeserializeOwned, Data: DeserializeOwned>(
+    framing: Framing) -> JsonPoller<Handshake, ExtendedData<Data>> {
+    let mut poller = JsonPoller::with_framing(framing);
+    poller.out_of_band = |data| matches!(data, ExtendedData::Extension(_));
+    poller
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::extension::*;
+
+    fn poll_extensions(framing: Framing) {
+        use chess_network_protocol::{ClientToServer,
+                                     ClientToServerHandshake, Color};
+        use crate::clock::TimeControl;
+
+        let mut poller = extended_poller::<ClientToServerHandshake,
+                                           ClientToServer>(framing);
+        let mut into = vec![];
+        let state = ClockState {
+            control: TimeControl::SuddenDeath { base_ms: 60_000 },
+            white_ms: 60_000,
+            black_ms: 59_000,
+            running_white: Some(true),
+        };
+        let bytes = serde_json::to_vec(&Extension::Clock(Some(state)))
+            .unwrap();
+        // Our messages may come before the handshake, look like one another
+        // and be split anywhere.
+        poller.feed(b"\"Heartbeat\"{\"Chat\": \"{\\\"Chat\\\"\"}", &mut into);
+        poller.feed(b"{\"server_color\": \"White\"}\"Heart", &mut into);
+        poller.feed(b"beat\"{\"Rematch\": \"Offer\"}\"Resign\"", &mut into);
+        poller.feed(&bytes[..10], &mut into);
+        poller.feed(&bytes[10..], &mut into);
+        // Objects with more than the line of chat are not chat, nor are
+        // requests that we do not know rematches.
+        poller.feed(b"{\"Chat\": \"a\", \"b\": 1}", &mut into);
+        poller.feed(b"{\"Rematch\": \"Later\"}{\"Clock\": null}", &mut into);
+        let incoming: Vec<_> = into.into_iter().map(Incoming::split)
+            .collect();
+        let handshake = ClientToServerHandshake { server_color: Color::White };
+        assert_eq!(incoming, vec![
+            Incoming::Extension(Extension::Heartbeat),
+            Incoming::Extension(Extension::Chat("{\"Chat\"".into())),
+            Incoming::Packet(Packet::Handshake(handshake)),
+            Incoming::Extension(Extension::Heartbeat),
+            Incoming::Extension(Extension::Rematch(RematchRequest::Offer)),
+            Incoming::Packet(Packet::Data(ClientToServer::Resign)),
+            Incoming::Extension(Extension::Clock(Some(state))),
+            Incoming::Extension(Extension::Clock(None)),
+        ]);
+        assert_eq!(poller.deviations.len(), 2);
+    }
+
+    #[test]
+    pub fn extensions() {
+        poll_extensions(Framing::Finder);
+        poll_extensions(Framing::Streaming);
+    }
+}
@@ -0,0 +1,689 @@
+//! The state of a game of chess, whether it is played locally, hosted or
+//! joined.
+
+use chess_network_protocol::{Joever, Move, Piece};
+
+use crate::wire::{chess_piece_to_wire, indices_to_wire_move,
+                  wire_move_to_indices, wire_to_chess_representation,
+                  wire_to_piece_kind};
+
+/// Finds every legal move for the given side by trying them all on copies of
+/// the board.
+pub fn generate_legal_moves(chess_board: &chess::ChessBoard, white: bool)
+    -> Vec<Move> {
+    let representation = chess_board.get_board();
+    let team = if white { -1 } else { 1 };
+    let mut moves = vec![];
+    for from in 0..64 {
+        let (piece, piece_team) = representation[from];
+        if piece == 0 || piece_team != team {
+            continue
+        }
+        for to in 0..64 {
+            let mut board = chess_board.clone();
+            if !board.move_by_index(from, to) {
+                continue
+            }
+            let mv = indices_to_wire_move(from, to);
+            if board.can_promote() {
+                for piece in [5, 2, 4, 3] {
+                    let promotion = chess_piece_to_wire((piece, team));
+                    moves.push(Move { promotion, ..mv });
+                }
+            } else {
+                moves.push(mv);
+            }
+        }
+    }
+    moves
+}
+
+pub enum UnsentNetMove {
+    None,
+    PendingPromotion(Move),
+    Unsent(Move),
+}
+
+/// Actions other than moves that are waiting to be sent to the peer.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UnsentNetAction {
+    None,
+    Resign,
+    OfferDraw,
+    AcceptDraw,
+    OutOfTime,
+}
+
+/// How a game was ended.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GameEnd {
+    Checkmate { white_won: bool },
+    Resignation { white_won: bool },
+    Stalemate,
+    InsufficientMaterial,
+    DrawAgreed,
+    /// The other player stopped responding and the game was claimed.
+    Abandoned { white_won: bool },
+    /// A flag fell. It is a draw if the other side could not have won.
+    OutOfTime { white_won: Option<bool> },
+    /// The game is over, but we were never told why.
+    Unknown,
+}
+
+/// Returns true if the given square is attacked by any piece of the given
+/// team.
+fn is_attacked(board: &[(i8, i8); 64], square: usize, team: i8) -> bool {
+    let row = (square >> 3) as i32;
+    let column = (square & 7) as i32;
+    let piece_at = |r: i32, c: i32| {
+        if (0..8).contains(&r) && (0..8).contains(&c) {
+            Some(board[(r << 3 | c) as usize])
+        } else {
+            None
+        }
+    };
+
+    // White pawns move towards row 0 and black pawns towards row 7, so the
+    // attacking pawn stands one row behind the square.
+    let pawn_row = row - team as i32;
+    if piece_at(pawn_row, column - 1) == Some((1, team))
+        || piece_at(pawn_row, column + 1) == Some((1, team)) {
+        return true
+    }
+
+    let knight = [(1, 2), (2, 1), (2, -1), (1, -2),
+                  (-1, -2), (-2, -1), (-2, 1), (-1, 2)];
+    let orthogonal = [(1, 0), (0, 1), (-1, 0), (0, -1)];
+    let diagonal = [(1, 1), (-1, 1), (-1, -1), (1, -1)];
+    // The king steps one square in any of the directions.
+    let king = orthogonal.iter().chain(&diagonal);
+    let jumps = knight.iter().map(|d| (d, 3))
+        .chain(king.map(|d| (d, 6)));
+    for ((dr, dc), piece) in jumps {
+        if piece_at(row + dr, column + dc) == Some((piece, team)) {
+            return true
+        }
+    }
+
+    // Rooks and queens slide along the orthogonal directions, bishops and
+    // queens along the diagonal ones.
+    let slides = orthogonal.iter().map(|d| (d, 2))
+        .chain(diagonal.iter().map(|d| (d, 4)));
+    for ((dr, dc), slider) in slides {
+        let (mut r, mut c) = (row + dr, column + dc);
+        while let Some((piece, piece_team)) = piece_at(r, c) {
+            if piece != 0 {
+                if piece_team == team && (piece == slider || piece == 5) {
+                    return true
+                }
+                break
+            }
+            r += dr;
+            c += dc;
+        }
+    }
+    false
+}
+
+/// Returns true if the king of the given side is in check.
+pub fn is_in_check(board: &[(i8, i8); 64], white: bool) -> bool {
+    let team = if white { -1 } else { 1 };
+    match board.iter().position(|&square| square == (6, team)) {
+        Some(king) => is_attacked(board, king, -team),
+        None => false,
+    }
+}
+
+/// Returns true if neither side has enough pieces left to checkmate.
+pub fn is_insufficient_material(board: &[(i8, i8); 64]) -> bool {
+    let mut pieces = board.iter()
+        .filter(|(piece, _)| *piece != 0 && *piece != 6);
+    match (pieces.next(), pieces.next()) {
+        (None, _) => true,
+        // A lone knight or bishop can not checkmate.
+        (Some((3 | 4, _)), None) => true,
+        _ => false,
+    }
+}
+
+/// Returns true if the given side has more than a lone knight or bishop
+/// besides its king, which is what it takes to win on time.
+pub fn has_mating_material(board: &[(i8, i8); 64], white: bool) -> bool {
+    let team = if white { -1 } else { 1 };
+    let mut pieces = board.iter()
+        .filter(|(piece, t)| *t == team && *piece != 0 && *piece != 6);
+    !matches!((pieces.next(), pieces.next()),
+              (None, _) | (Some((3 | 4, _)), None))
+}
+
+/// Works out why a game that has ended did so, given the side to move.
+pub fn classify_game_end(board: &[(i8, i8); 64], white_to_move: bool)
+    -> GameEnd {
+    if is_in_check(board, white_to_move) {
+        GameEnd::Checkmate { white_won: !white_to_move }
+    } else if is_insufficient_material(board) {
+        GameEnd::InsufficientMaterial
+    } else {
+        GameEnd::Stalemate
+    }
+}
+
+pub struct ChessState {
+    pub chess_board: chess::ChessBoard,
+    pub chess_representation: [(i8, i8); 64],
+    pub moving_piece: usize,
+    pub is_white_turn: bool,
+    pub is_promoting: bool,
+    pub is_game_over: bool,
+    pub is_client: bool,
+    pub is_host: bool,
+    /// For the host (and in local play) this means that the opponent has
+    /// offered a draw that we have yet to answer. For the client it means
+    /// that our offer has yet to be answered.
+    pub draw_offered: bool,
+    pub end: Option<GameEnd>,
+    /// The legal moves of the side to move. Computed by ourselves unless we
+    /// are the client, in which case it is whatever the server sent us.
+    pub legal_moves: Vec<Move>,
+    /// Whether the client should check its moves against `legal_moves`. Only
+    /// set once the server has shown that it sends them.
+    pub has_move_list: bool,
+    pub unsent_net_move: UnsentNetMove,
+    pub unsent_net_action: UnsentNetAction,
+}
+
+impl ChessState {
+    pub fn new() -> ChessState {
+        let chess_board = chess::ChessBoard::new();
+        let chess_representation = chess_board.get_board();
+        let legal_moves = generate_legal_moves(&chess_board, true);
+
+        ChessState {
+            chess_board,
+            chess_representation,
+            moving_piece: 65,
+            unsent_net_move: UnsentNetMove::None,
+            is_white_turn: true,
+            is_promoting: false,
+            is_game_over: false,
+            is_client: false,
+            is_host: false,
+            draw_offered: false,
+            end: None,
+            legal_moves,
+            has_move_list: false,
+            unsent_net_action: UnsentNetAction::None,
+        }
+    }
+    pub fn do_move(self: &mut Self, from: usize, to: usize) -> bool {
+        if self.is_game_over {
+            println!("The game is over, moving is not allowed");
+            return false
+        }
+        if self.is_promoting {
+            println!("Please promote first");
+        }
+
+        let to_rank = to >> 3;
+
+        let net_move = indices_to_wire_move(from, to);
+
+        let mut did_move = false;
+
+        if self.is_client {
+            // Bad moves are rejected here rather than by the server, which
+            // would cost us a round trip and an error.
+            if self.has_move_list && !self.is_legal_target(from, to) {
+                println!("[client] illegal move rejected: {net_move:?}");
+                return false
+            }
+            self.is_promoting = self.chess_representation[from].0 == 1 &&
+                (to_rank == 0 || to_rank == 7);
+            did_move = true;
+        } else if self.chess_board.move_by_index(from, to) {
+            self.chess_representation = self.chess_board.get_board();
+            self.is_white_turn = !self.is_white_turn;
+            self.is_promoting = self.chess_board.can_promote();
+
+
+            self.unsent_net_move = if self.is_promoting {
+                // If we are promoting, we don't want to send the
+                // update just yet.
+                UnsentNetMove::PendingPromotion(net_move)
+            } else {
+                UnsentNetMove::Unsent(net_move)
+            };
+
+            self.check_game_end();
+            if !self.is_promoting {
+                self.update_legal_moves();
+            }
+            did_move = true;
+        }
+
+        if did_move {
+            // Pending draw offers lapse once a move has been made.
+            self.draw_offered = false;
+            self.unsent_net_move = if self.is_promoting {
+                // If we are promoting, we don't want to send the
+                // update just yet.
+                UnsentNetMove::PendingPromotion(net_move)
+            } else {
+                UnsentNetMove::Unsent(net_move)
+            };
+        }
+
+        did_move
+    }
+    /// Finishes a pending promotion with the given kind of piece.
+    pub fn promote(self: &mut Self, piece: i8) -> Result<(), String> {
+        let UnsentNetMove::PendingPromotion(mut mv)
+            = self.unsent_net_move else {
+            return Err("there is no promotion to finish".into())
+        };
+        // The client does not pass the turn until the server says so, so
+        // is_white_turn is still the colour of the promoting side.
+        let white = self.is_white_turn == self.is_client;
+        let color = if white { -1 } else { 1 };
+        mv.promotion = chess_piece_to_wire((piece, color));
+        if self.is_client && self.has_move_list && !self.is_legal_move(&mv) {
+            return Err(format!("illegal promotion rejected: {mv:?}"))
+        }
+
+        self.chess_board.promote(piece);
+        self.is_promoting = false;
+        if !self.is_client {
+            self.chess_representation = self.chess_board.get_board();
+            self.check_game_end();
+            self.update_legal_moves();
+        }
+        self.unsent_net_move = UnsentNetMove::Unsent(mv);
+        Ok(())
+    }
+    pub fn ingest_client_move(self: &mut Self, mv: &Move)
+        -> bool {
+        let Some((from, to)) = wire_move_to_indices(mv) else {
+            return false
+        };
+        // A pawn reaching the last rank has to say what it becomes, which
+        // is checked before the move so that a bad one leaves no trace.
+        let promotion = wire_to_piece_kind(mv.promotion);
+        let to_rank = to >> 3;
+        let promotes = self.chess_representation[from].0 == 1
+            && (to_rank == 0 || to_rank == 7);
+        if promotes && !(2..=5).contains(&promotion) {
+            return false
+        }
+        let result = self.do_move(from, to);
+        if self.is_promoting && self.promote(promotion).is_err() {
+            return false
+        }
+        result
+    }
+    /// Replaces our view of the game with the one sent by the server.
+    pub fn ingest_server_state(self: &mut Self, board: &[[Piece; 8]; 8],
+                               moves: &[Move], joever: &Joever) {
+        self.chess_representation = wire_to_chess_representation(board);
+        self.ingest_legal_moves(moves);
+        self.ingest_joever(joever);
+    }
+    /// Takes on the legal moves sent by the server, leaving out any that
+    /// are not on the board.
+    pub fn ingest_legal_moves(self: &mut Self, moves: &[Move]) {
+        self.legal_moves = moves.iter()
+            .filter(|mv| wire_move_to_indices(mv).is_some())
+            .cloned()
+            .collect();
+    }
+    /// Works out whose turn it is from what the server sent us, preferring
+    /// the colour of the piece that was just moved and otherwise the colour
+    /// of the pieces in the list of legal moves.
+    fn server_side_to_move(&self, move_made: Option<&Move>) -> Option<bool> {
+        let team_of = |square: usize| match self.chess_representation[square] {
+            (0, _) => None,
+            (_, team) => Some(team == -1),
+        };
+        if let Some((_, to)) = move_made.and_then(wire_move_to_indices) {
+            if let Some(mover_is_white) = team_of(to) {
+                return Some(!mover_is_white)
+            }
+        }
+        let (from, _) = wire_move_to_indices(self.legal_moves.first()?)?;
+        team_of(from)
+    }
+    /// Sets the side to move to the one the server says it is. Returns a
+    /// description of the problem if it was not the one we expected.
+    pub fn sync_turn(self: &mut Self, move_made: Option<&Move>,
+                     expected_white: bool) -> Result<(), String> {
+        let Some(white) = self.server_side_to_move(move_made) else {
+            // Nothing to go on, so we have to trust ourselves.
+            self.is_white_turn = expected_white;
+            return Ok(())
+        };
+        self.is_white_turn = white;
+        if white == expected_white {
+            Ok(())
+        } else {
+            let side = |white| if white { "white" } else { "black" };
+            Err(format!("expected it to be {}'s turn, but the server says \
+                         it is {}'s", side(expected_white), side(white)))
+        }
+    }
+    fn update_legal_moves(self: &mut Self) {
+        self.legal_moves = if self.is_game_over {
+            vec![]
+        } else {
+            generate_legal_moves(&self.chess_board, self.is_white_turn)
+        };
+    }
+    /// Whether any move has been made, as in a game that is resumed.
+    pub fn has_started(&self) -> bool {
+        self.chess_representation != chess::ChessBoard::new().get_board()
+    }
+    /// Returns true if the piece on the given square can move to the target
+    /// square according to the list of legal moves.
+    pub fn is_legal_target(&self, from: usize, to: usize) -> bool {
+        self.legal_moves.iter()
+            .any(|mv| wire_move_to_indices(mv) == Some((from, to)))
+    }
+    /// Like `is_legal_target` but also checks the promotion. Moves in the
+    /// list without a promotion allow promoting to anything.
+    pub fn is_legal_move(&self, mv: &Move) -> bool {
+        let Some(squares) = wire_move_to_indices(mv) else {
+            return false
+        };
+        let kind = wire_to_piece_kind(mv.promotion);
+        self.legal_moves.iter().any(|legal| {
+            wire_move_to_indices(legal) == Some(squares)
+                && (legal.promotion == Piece::None
+                    || wire_to_piece_kind(legal.promotion) == kind)
+        })
+    }
+    fn check_game_end(self: &mut Self) {
+        if self.chess_board.is_game_ended() {
+            let board = &self.chess_representation;
+            self.end = Some(classify_game_end(board, self.is_white_turn));
+            self.is_game_over = true;
+        }
+    }
+    /// Updates the result with the one told to us by the server.
+    pub fn ingest_joever(self: &mut Self, joever: &Joever) {
+        let end = match joever {
+            Joever::Ongoing => return,
+            Joever::White => GameEnd::Checkmate { white_won: true },
+            Joever::Black => GameEnd::Checkmate { white_won: false },
+            // The server does not tell us what kind of draw it is, but the
+            // board does.
+            Joever::Draw => {
+                if is_insufficient_material(&self.chess_representation) {
+                    GameEnd::InsufficientMaterial
+                } else {
+                    GameEnd::Stalemate
+                }
+            }
+            Joever::Indeterminate => GameEnd::Unknown,
+        };
+        self.end_game(end);
+    }
+    pub fn end_game(self: &mut Self, end: GameEnd) {
+        self.end = Some(end);
+        self.is_game_over = true;
+        self.legal_moves.clear();
+        self.is_promoting = false;
+        self.draw_offered = false;
+    }
+    pub fn resign(self: &mut Self, white_resigns: bool) {
+        if self.is_game_over {
+            return
+        }
+        self.unsent_net_action = UnsentNetAction::Resign;
+        if !self.is_client {
+            // The client waits for the server to confirm the resignation.
+            self.end_game(GameEnd::Resignation { white_won: !white_resigns });
+        }
+    }
+    pub fn run_out_of_time(self: &mut Self, white: bool) {
+        if self.is_game_over {
+            return
+        }
+        let board = &self.chess_representation;
+        let white_won = has_mating_material(board, !white).then_some(!white);
+        self.end_game(GameEnd::OutOfTime { white_won });
+        if !self.is_client {
+            self.unsent_net_action = UnsentNetAction::OutOfTime;
+        }
+    }
+    pub fn offer_draw(self: &mut Self) {
+        if self.is_game_over || self.draw_offered {
+            return
+        }
+        self.draw_offered = true;
+        if self.is_client {
+            self.unsent_net_action = UnsentNetAction::OfferDraw;
+        }
+    }
+    pub fn answer_draw(self: &mut Self, accept: bool) {
+        self.draw_offered = false;
+        // Declining is done by simply not answering, the protocol has no
+        // message for it.
+        if accept {
+            self.end_game(GameEnd::DrawAgreed);
+            self.unsent_net_action = UnsentNetAction::AcceptDraw;
+        }
+    }
+    pub fn end_text(&self) -> &'static str {
+        match self.end {
+            Some(GameEnd::Checkmate { white_won: true }) =>
+                "Checkmate, white wins!",
+            Some(GameEnd::Checkmate { white_won: false }) =>
+                "Checkmate, black wins!",
+            Some(GameEnd::Resignation { white_won: true }) =>
+                "Black resigned, white wins!",
+            Some(GameEnd::Resignation { white_won: false }) =>
+                "White resigned, black wins!",
+            Some(GameEnd::Stalemate) => "Stalemate, it's a draw!",
+            Some(GameEnd::InsufficientMaterial) =>
+                "Insufficient material, it's a draw!",
+            Some(GameEnd::DrawAgreed) => "Draw by agreement!",
+            Some(GameEnd::Abandoned { white_won: true }) =>
+                "Black stopped responding, white wins!",
+            Some(GameEnd::Abandoned { white_won: false }) =>
+                "White stopped responding, black wins!",
+            Some(GameEnd::OutOfTime { white_won: Some(true) }) =>
+                "Black ran out of time, white wins!",
+            Some(GameEnd::OutOfTime { white_won: Some(false) }) =>
+                "White ran out of time, black wins!",
+            Some(GameEnd::OutOfTime { white_won: None }) =>
+                "Out of time, but no way to lose, it's a draw!",
+            Some(GameEnd::Unknown) | None => "IT'S SO OVER!",
+        }
+    }
+    pub fn to_joever(&self) -> Joever {
+        match self.end {
+            Some(GameEnd::Checkmate { white_won: true })
+            | Some(GameEnd::Resignation { white_won: true })
+            | Some(GameEnd::Abandoned { white_won: true })
+            | Some(GameEnd::OutOfTime { white_won: Some(true) }) => {
+                Joever::White
+            }
+            Some(GameEnd::Checkmate { white_won: false })
+            | Some(GameEnd::Resignation { white_won: false })
+            | Some(GameEnd::Abandoned { white_won: false })
+            | Some(GameEnd::OutOfTime { white_won: Some(false) }) => {
+                Joever::Black
+            }
+            Some(GameEnd::Stalemate)
+            | Some(GameEnd::OutOfTime { white_won: None })
+            | Some(GameEnd::InsufficientMaterial)
+            | Some(GameEnd::DrawAgreed) => Joever::Draw,
+            Some(GameEnd::Unknown) => Joever::Indeterminate,
+            None if self.is_game_over => Joever::Indeterminate,
+            None => Joever::Ongoing,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::game::*;
+    use crate::wire::indices_to_wire_move;
+    use chess_network_protocol::Piece;
+
+    /// Builds a board from eight rows of text, using upper case for white.
+    fn board_from_text(rows: [&str; 8]) -> [(i8, i8); 64] {
+        let mut board = [(0, 0); 64];
+        for (i, c) in rows.concat().chars().enumerate() {
+            let piece = match c.to_ascii_lowercase() {
+                'p' => 1, 'r' => 2, 'n' => 3, 'b' => 4, 'q' => 5, 'k' => 6,
+                _ => continue,
+            };
+            board[i] = (piece, if c.is_ascii_uppercase() { -1 } else { 1 });
+        }
+        board
+    }
+
+    #[test]
+    pub fn game_end() {
+        let back_rank_mate = board_from_text([
+            "R.....k.",
+            ".....ppp",
+            "........",
+            "........",
+            "........",
+            "........",
+            ".....PPP",
+            "......K.",
+        ]);
+        assert_eq!(classify_game_end(&back_rank_mate, false),
+                   GameEnd::Checkmate { white_won: true });
+
+        let pawn_mate = board_from_text([
+            "......k.",
+            "........",
+            "........",
+            "........",
+            "........",
+            "........",
+            ".....PPP",
+            "...q..K.",
+        ]);
+        assert_eq!(classify_game_end(&pawn_mate, true),
+                   GameEnd::Checkmate { white_won: false });
+
+        let stalemate = board_from_text([
+            "k.......",
+            "..Q.....",
+            ".K......",
+            "........",
+            "........",
+            "........",
+            "........",
+            "........",
+        ]);
+        assert_eq!(classify_game_end(&stalemate, false), GameEnd::Stalemate);
+
+        let lone_bishop = board_from_text([
+            "k.......",
+            "........",
+            ".K......",
+            "....B...",
+            "........",
+            "........",
+            "........",
+            "........",
+        ]);
+        assert_eq!(classify_game_end(&lone_bishop, false),
+                   GameEnd::InsufficientMaterial);
+        assert!(!has_mating_material(&lone_bishop, true));
+        assert!(!has_mating_material(&lone_bishop, false));
+        assert!(has_mating_material(&back_rank_mate, true));
+    }
+
+    #[test]
+    pub fn out_of_time() {
+        let mut state = ChessState::new();
+        state.run_out_of_time(true);
+        assert_eq!(state.end, Some(GameEnd::OutOfTime {
+            white_won: Some(false),
+        }));
+        assert_eq!(state.to_joever(), Joever::Black);
+        assert_eq!(state.unsent_net_action, UnsentNetAction::OutOfTime);
+
+        // Black cannot win with a lone king, so white running out of time
+        // only draws.
+        let mut state = ChessState::new();
+        for square in &mut state.chess_representation[..16] {
+            if square.0 != 6 {
+                *square = (0, 0);
+            }
+        }
+        state.is_client = true;
+        state.run_out_of_time(true);
+        assert_eq!(state.end, Some(GameEnd::OutOfTime { white_won: None }));
+        assert_eq!(state.to_joever(), Joever::Draw);
+        assert_eq!(state.unsent_net_action, UnsentNetAction::None);
+    }
+
+    #[test]
+    pub fn client_turn_sync() {
+        let mut state = ChessState::new();
+        state.is_client = true;
+        state.legal_moves.clear();
+
+        // White moved a piece to e4 and it is now black's turn.
+        let e2e4 = indices_to_wire_move(52, 36);
+        state.chess_representation[52] = (0, 0);
+        state.chess_representation[36] = (1, -1);
+        assert_eq!(state.sync_turn(Some(&e2e4), false), Ok(()));
+        assert!(!state.is_white_turn);
+
+        // We thought it was white's turn again, the server knows better.
+        assert!(state.sync_turn(Some(&e2e4), true).is_err());
+        assert!(!state.is_white_turn);
+
+        // Without anything to go on we keep what we expected.
+        assert_eq!(state.sync_turn(None, true), Ok(()));
+        assert!(state.is_white_turn);
+
+        // The legal moves show whose turn it is when no move was made.
+        state.chess_representation[12] = (1, 1);
+        state.legal_moves = vec![indices_to_wire_move(12, 28)];
+        assert!(state.sync_turn(None, true).is_err());
+        assert!(!state.is_white_turn);
+    }
+
+    #[test]
+    pub fn client_move_validation() {
+        // e2 to e4 and the promotion of a pawn on a7.
+        let e2e4 = indices_to_wire_move(52, 36);
+        let a7a8 = chess_network_protocol::Move {
+            promotion: Piece::WhiteQueen,
+            ..indices_to_wire_move(8, 0)
+        };
+
+        let mut state = ChessState::new();
+        state.is_client = true;
+        state.has_move_list = true;
+        state.legal_moves = vec![e2e4, a7a8];
+        assert!(!state.do_move(52, 28));
+        assert!(matches!(state.unsent_net_move, UnsentNetMove::None));
+        assert!(state.do_move(52, 36));
+        assert!(matches!(state.unsent_net_move, UnsentNetMove::Unsent(m)
+                         if m == e2e4));
+
+        assert!(state.is_legal_move(&a7a8));
+        let underpromotion = chess_network_protocol::Move {
+            promotion: Piece::WhiteKnight,
+            ..a7a8
+        };
+        assert!(!state.is_legal_move(&underpromotion));
+
+        // Promoting is only possible once a pawn has reached the last rank.
+        assert!(state.promote(5).is_err());
+
+        // Servers that don't send moves are trusted to validate them.
+        let mut state = ChessState::new();
+        state.is_client = true;
+        assert!(state.do_move(52, 28));
+    }
+}
@@ -0,0 +1,122 @@
+//! Keeping track of the ways in which peers written by others stray from the
+//! protocol, so that they can be told about it.
+
+use std::collections::BTreeMap;
+
+/// How to treat a peer that does not follow the protocol to the letter.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum InteropMode {
+    /// Hang up on the first deviation.
+    Strict,
+    /// Log deviations and carry on as well as we can.
+    Lenient,
+}
+
+/// A way in which a peer did not follow the protocol.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Deviation {
+    /// Whitespace, such as a newline after every message, between messages.
+    Whitespace,
+    /// A message that could not be decoded.
+    Malformed(String),
+    /// A value other than an object, or a string naming a unit variant,
+    /// where a message was expected.
+    NotAnObject(String),
+    /// A message longer than the given number of bytes.
+    TooLong(usize),
+    /// The first message was not a handshake.
+    MissingHandshake,
+    /// A second handshake on the same connection.
+    RepeatedHandshake,
+    /// A message that makes no sense at this point of the game.
+    UnexpectedMessage(String),
+    /// A feature that we have never heard of.
+    UnknownFeature(String),
+    /// A move with squares off the board, which is ignored.
+    OffBoardMove(String),
+}
+
+impl std::fmt::Display for Deviation {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Deviation::Whitespace => write!(f, "whitespace between messages"),
+            Deviation::Malformed(e) => write!(f, "malformed message: {e}"),
+            Deviation::NotAnObject(v) => write!(f, "not an object: {v}"),
+            Deviation::TooLong(size) => {
+                write!(f, "message longer than {size} bytes")
+            }
+            Deviation::MissingHandshake => write!(f, "missing handshake"),
+            Deviation::RepeatedHandshake => write!(f, "repeated handshake"),
+            Deviation::UnexpectedMessage(m) => {
+                write!(f, "unexpected message: {m}")
+            }
+            Deviation::UnknownFeature(name) => {
+                write!(f, "unknown feature: {name}")
+            }
+            Deviation::OffBoardMove(m) => write!(f, "move off the board: {m}"),
+        }
+    }
+}
+
+/// The deviations seen during one session with a peer.
+pub struct InteropLog {
+    pub mode: InteropMode,
+    pub deviations: Vec<Deviation>,
+}
+
+impl InteropLog {
+    pub fn new(mode: InteropMode) -> Self {
+        InteropLog { mode, deviations: vec![] }
+    }
+
+    /// Logs a deviation. Returns false if the session should be ended
+    /// because of it.
+    pub fn record(&mut self, deviation: Deviation) -> bool {
+        eprintln!("[interop] {deviation}");
+        self.deviations.push(deviation);
+        self.mode == InteropMode::Lenient
+    }
+
+    /// Describes all the deviations seen, counting those that are alike.
+    pub fn summary(&self) -> String {
+        let mode = match self.mode {
+            InteropMode::Strict => "strict",
+            InteropMode::Lenient => "lenient",
+        };
+        if self.deviations.is_empty() {
+            return format!("no deviations from the protocol ({mode} mode)")
+        }
+        let mut counts = BTreeMap::new();
+        for deviation in &self.deviations {
+            *counts.entry(deviation.to_string()).or_insert(0) += 1;
+        }
+        let mut summary = format!("{} deviations from the protocol ({mode} \
+                                   mode):", self.deviations.len());
+        for (description, count) in counts {
+            summary += &format!("\n    {count}x {description}");
+        }
+        summary
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::interop::*;
+
+    #[test]
+    pub fn interop_summary() {
+        let mut log = InteropLog::new(InteropMode::Lenient);
+        assert_eq!(log.summary(),
+                   "no deviations from the protocol (lenient mode)");
+        assert!(log.record(Deviation::Whitespace));
+        assert!(log.record(Deviation::UnknownFeature("Clocks".into())));
+        assert!(log.record(Deviation::Whitespace));
+        assert_eq!(log.summary(), "\
+3 deviations from the protocol (lenient mode):
+    1x unknown feature: Clocks
+    2x whitespace between messages");
+
+        let mut log = InteropLog::new(InteropMode::Strict);
+        assert!(!log.record(Deviation::MissingHandshake));
+    }
+}
@@ -0,0 +1,33 @@
+//! Everything in GChess that works without a window: the chess state, the
+//! conversion to and from the network protocol and the netcode itself. This
+//! is shared by the GUI, the headless server and the bots.
+
+pub mod bot;
+pub mod chat;
+pub mod clock;
+pub mod codec;
+pub mod discovery;
+pub mod extension;
+pub mod game;
+pub mod interop;
+pub mod lobby;
+pub mod net;
+pub mod record;
+pub mod referee;
+pub mod rematch;
+pub mod settings;
+pub mod spectators;
+pub mod wire;
+
+pub use chat::{Chat, ChatLine, CHAT_FEATURE};
+pub use clock::{Clock, TimeControl, CLOCK_FEATURE};
+pub use codec::{Framing, JsonFinder, JsonPoller, Packet};
+pub use extension::{Extension, Incoming};
+pub use game::*;
+pub use interop::{Deviation, InteropLog, InteropMode};
+pub use net::*;
+pub use rematch::{MatchScore, Rematch, REMATCH_FEATURE};
+pub use settings::{server_usage, usage, ColorChoice, Settings,
+                   DEFAULT_HEARTBEAT, DEFAULT_PORT};
+pub use spectators::{Spectators, SPECTATOR_ERROR};
+pub use wire::*;
@@ -0,0 +1,493 @@
+//! Many games behind one listener. Before the handshake of the protocol,
+//! clients may ask the lobby for the open games, create a game or join one.
+//! Clients that send the handshake straight away are seated in the first
+//! game that has their colour free, so that the lobby can be played on like
+//! any other server.
+//!
+//! Lobby messages are JSON like the rest of the protocol. Clients wait for
+//! `LobbyResponse::Joined` before sending their handshake, after which the
+//! connection carries nothing but the game.
+
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::mpsc::{Receiver, TryRecvError};
+use std::time::{Duration, Instant};
+
+use chess_network_protocol::ClientToServerHandshake;
+use serde::{Deserialize, Serialize};
+
+use crate::codec::{Framing, JsonPoller, Packet};
+use crate::interop::InteropMode;
+use crate::net::{address_with_port, start_client, ClientConnection,
+                 Connection};
+use crate::record::{Recorder, Role};
+use crate::referee::{color_name, Game};
+
+/// How long clients wait for the lobby to answer.
+pub const LOBBY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long a client may stay in the lobby without sending anything. Long
+/// enough to look through the list of games before joining one.
+const PENDING_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// How much may be waiting to be sent to a client that does not read its
+/// responses before it is dropped.
+const MAX_UNSENT: usize = 64 * 1024;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum LobbyRequest {
+    List,
+    /// Creates a game in which we play the given colour.
+    Create { is_white: bool },
+    Join { id: u64 },
+}
+
+/// A game waiting for a second player.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct OpenGame {
+    pub id: u64,
+    /// The colour left for whoever joins.
+    pub is_white: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum LobbyResponse {
+    Games(Vec<OpenGame>),
+    /// We have been given a seat, and should send our handshake.
+    Joined { id: u64, is_white: bool },
+    Error(String),
+}
+
+/// What a client may send before it has been seated.
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum LobbyMessage {
+    Request(LobbyRequest),
+    Handshake(ClientToServerHandshake),
+}
+
+/// A client that has yet to be seated.
+struct Pending {
+    stream: TcpStream,
+    address: SocketAddr,
+    poller: JsonPoller<LobbyMessage, LobbyMessage>,
+    /// Responses the stream was not ready to take yet.
+    unsent: Vec<u8>,
+    last_heard: Instant,
+}
+
+impl Pending {
+    /// Sends what it can of the responses without blocking, returning
+    /// false if the client is gone.
+    fn flush(&mut self) -> bool {
+        while !self.unsent.is_empty() {
+            match self.stream.write(&self.unsent) {
+                Ok(0) => return false,
+                Ok(length) => {
+                    self.unsent.drain(..length);
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    eprintln!("[lobby] write error: {e}");
+                    return false
+                }
+            }
+        }
+        self.unsent.len() <= MAX_UNSENT
+    }
+
+    /// Sends a response, returning false if the client is gone.
+    fn respond(&mut self, response: &LobbyResponse) -> bool {
+        let bytes = serde_json::to_vec(response)
+            .expect("responses always serialize");
+        self.unsent.extend_from_slice(&bytes);
+        self.flush()
+    }
+}
+
+struct LobbyGame {
+    id: u64,
+    game: Game,
+    /// The colour of whoever created the game, until its handshake says
+    /// otherwise.
+    created_as: bool,
+}
+
+impl LobbyGame {
+    fn open_color(&self) -> Option<bool> {
+        match self.game.players.as_slice() {
+            [player] => Some(!player.is_white.unwrap_or(self.created_as)),
+            _ => None,
+        }
+    }
+}
+
+/// What to do with a pending client after a message.
+enum Outcome {
+    Wait,
+    Seat { game: usize, handshake: Option<ClientToServerHandshake> },
+    Drop,
+}
+
+pub struct Lobby {
+    games: Vec<LobbyGame>,
+    pending: Vec<Pending>,
+    next_id: u64,
+    interop: InteropMode,
+    /// The directory to record games to, if any.
+    record: Option<String>,
+    /// How the messages of the players are told apart.
+    framing: Framing,
+}
+
+impl Lobby {
+    pub fn new(interop: InteropMode, record: Option<String>,
+               framing: Framing) -> Self {
+        Lobby {
+            games: vec![],
+            pending: vec![],
+            next_id: 1,
+            interop,
+            record,
+            framing,
+        }
+    }
+
+    pub fn open_games(&self) -> Vec<OpenGame> {
+        self.games.iter().filter_map(|lobby_game| {
+            let is_white = lobby_game.open_color()?;
+            Some(OpenGame { id: lobby_game.id, is_white })
+        }).collect()
+    }
+
+    /// Accepts everyone waiting on the listener, which must be
+    /// non-blocking.
+    pub fn accept(&mut self, listener: &TcpListener) {
+        while let Ok((stream, address)) = listener.accept() {
+            if let Err(e) = stream.set_nonblocking(true) {
+                eprintln!("[lobby] {address} failed: {e}");
+                continue
+            }
+            println!("[lobby] {address} connected");
+            self.pending.push(Pending {
+                stream,
+                address,
+                poller: JsonPoller::new(),
+                unsent: vec![],
+                last_heard: Instant::now(),
+            });
+        }
+    }
+
+    fn create_game(&mut self, created_as: bool) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+        println!("[lobby] game {id} created by {}", color_name(created_as));
+        self.games.push(LobbyGame {
+            id,
+            game: Game::new(self.interop),
+            created_as,
+        });
+        self.games.len() - 1
+    }
+
+    fn handle(&mut self, pending: &mut Pending, message: LobbyMessage)
+        -> Outcome {
+        let address = pending.address;
+        let (game, response) = match message {
+            LobbyMessage::Request(LobbyRequest::List) => {
+                let games = LobbyResponse::Games(self.open_games());
+                return if pending.respond(&games) {
+                    Outcome::Wait
+                } else {
+                    Outcome::Drop
+                }
+            }
+            LobbyMessage::Request(LobbyRequest::Create { is_white }) => {
+                let game = self.create_game(is_white);
+                let id = self.games[game].id;
+                (game, LobbyResponse::Joined { id, is_white })
+            }
+            LobbyMessage::Request(LobbyRequest::Join { id }) => {
+                let open = self.games.iter().position(|lobby_game| {
+                    lobby_game.id == id && lobby_game.open_color().is_some()
+                });
+                let Some(game) = open else {
+                    let message = format!("Game {id} is not open");
+                    let error = LobbyResponse::Error(message);
+                    return if pending.respond(&error) {
+                        Outcome::Wait
+                    } else {
+                        Outcome::Drop
+                    }
+                };
+                let is_white = self.games[game].open_color().unwrap();
+                (game, LobbyResponse::Joined { id, is_white })
+            }
+            LobbyMessage::Handshake(handshake) => {
+                // The client names the colour of its opponent.
+                let is_white = handshake.server_color
+                    != chess_network_protocol::Color::White;
+                let open = self.games.iter().position(|lobby_game| {
+                    lobby_game.open_color() == Some(is_white)
+                });
+                let game = match open {
+                    Some(game) => game,
+                    None => self.create_game(is_white),
+                };
+                println!("[lobby] {address} took game {}",
+                         self.games[game].id);
+                let handshake = Some(handshake);
+                return Outcome::Seat { game, handshake }
+            }
+        };
+        println!("[lobby] {address} took game {}", self.games[game].id);
+        if pending.respond(&response) {
+            Outcome::Seat { game, handshake: None }
+        } else {
+            Outcome::Drop
+        }
+    }
+
+    /// Hands a client over to its game.
+    fn seat(&mut self, mut pending: Pending, game: usize,
+            handshake: Option<ClientToServerHandshake>) {
+        if pending.poller.buffered() > 0 {
+            eprintln!("[lobby] {} sent data before it was seated, which is \
+                       lost", pending.address);
+        }
+        if !pending.unsent.is_empty() {
+            // The rest of the answer has to go out before the game starts
+            // using the stream.
+            let stream = &mut pending.stream;
+            let mut send_rest = || -> std::io::Result<()> {
+                stream.set_nonblocking(false)?;
+                stream.set_write_timeout(Some(LOBBY_TIMEOUT))?;
+                stream.write_all(&pending.unsent)?;
+                stream.set_write_timeout(None)
+            };
+            if let Err(e) = send_rest() {
+                eprintln!("[lobby] {} failed: {e}", pending.address);
+                return
+            }
+        }
+        let recorder = Recorder::start(self.record.as_deref(), Role::Host);
+        let connection =
+            Connection::spawn_recorded(pending.stream, self.framing, recorder);
+        let game = &mut self.games[game].game;
+        match connection {
+            Ok(connection) => {
+                let index = game.add_player(connection);
+                if let Some(handshake) = handshake {
+                    game.handle_handshake(index, &handshake);
+                }
+            }
+            Err(e) => eprintln!("[lobby] {} failed: {e}", pending.address),
+        }
+    }
+
+    /// Answers the clients in the lobby, seats those that have picked a
+    /// game and plays all games. Games are forgotten once everyone has left
+    /// them.
+    pub fn update(&mut self) {
+        let mut buffer = [0u8; 4096];
+        let mut packets = vec![];
+        for mut pending in std::mem::take(&mut self.pending) {
+            let mut outcome = Outcome::Wait;
+            loop {
+                match pending.stream.read(&mut buffer) {
+                    Ok(0) => outcome = Outcome::Drop,
+                    Ok(length) => {
+                        pending.poller.feed(&buffer[..length], &mut packets);
+                        pending.last_heard = Instant::now();
+                        continue
+                    }
+                    Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {}
+                    Err(e) => {
+                        eprintln!("[lobby] read error: {e}");
+                        outcome = Outcome::Drop;
+                    }
+                }
+                break
+            }
+            if !pending.flush() {
+                outcome = Outcome::Drop;
+            }
+            if pending.last_heard.elapsed() > PENDING_TIMEOUT {
+                println!("[lobby] {} never picked a game", pending.address);
+                outcome = Outcome::Drop;
+            }
+            for deviation in pending.poller.deviations.drain(..) {
+                println!("[lobby] {}: {deviation}", pending.address);
+            }
+
+            let mut handshake = None;
+            let mut seat = None;
+            for packet in packets.drain(..) {
+                let message = match packet {
+                    Packet::Handshake(m) | Packet::Data(m) => m,
+                };
+                if seat.is_some() {
+                    // Only a handshake sent without waiting to be seated
+                    // makes sense here.
+                    if let LobbyMessage::Handshake(h) = message {
+                        handshake = Some(h);
+                    }
+                    continue
+                }
+                match self.handle(&mut pending, message) {
+                    Outcome::Wait => {}
+                    Outcome::Seat { game, handshake: h } => {
+                        seat = Some(game);
+                        handshake = h;
+                    }
+                    Outcome::Drop => outcome = Outcome::Drop,
+                }
+            }
+            match (seat, outcome) {
+                (Some(game), _) => self.seat(pending, game, handshake),
+                (None, Outcome::Drop) => {
+                    println!("[lobby] {} left", pending.address);
+                }
+                (None, _) => self.pending.push(pending),
+            }
+        }
+
+        self.games.retain_mut(|lobby_game| {
+            lobby_game.game.update();
+            let empty = lobby_game.game.players.is_empty();
+            if empty {
+                println!("[lobby] game {} is over", lobby_game.id);
+            }
+            !empty
+        });
+    }
+}
+
+/// A connection to a lobby, before a game has been picked. Requests wait
+/// for the answer of the lobby, see `LobbyReply` for making them without
+/// waiting.
+pub struct LobbyClient {
+    stream: TcpStream,
+    poller: JsonPoller<LobbyResponse, LobbyResponse>,
+}
+
+impl LobbyClient {
+    /// Connects to a lobby. The address may leave out the port, in which
+    /// case the default one is used.
+    pub fn connect(address: &str) -> std::io::Result<Self> {
+        let address = address_with_port(address);
+        println!("[client] attempting to connect to lobby: {address}");
+        let stream = TcpStream::connect(address)?;
+        stream.set_read_timeout(Some(Duration::from_millis(10)))?;
+        Ok(LobbyClient { stream, poller: JsonPoller::new() })
+    }
+
+    /// The address of the lobby, for connecting to it again.
+    pub fn peer_addr(&self) -> std::io::Result<SocketAddr> {
+        self.stream.peer_addr()
+    }
+
+    fn request(&mut self, request: &LobbyRequest)
+        -> std::io::Result<LobbyResponse> {
+        let bytes = serde_json::to_vec(request)
+            .expect("requests always serialize");
+        self.stream.write_all(&bytes)?;
+
+        let start = Instant::now();
+        let mut buffer = [0u8; 4096];
+        let mut packets = vec![];
+        while packets.is_empty() {
+            if start.elapsed() > LOBBY_TIMEOUT {
+                return Err(std::io::ErrorKind::TimedOut.into())
+            }
+            match self.stream.read(&mut buffer) {
+                Ok(0) => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+                Ok(length) => {
+                    self.poller.feed(&buffer[..length], &mut packets);
+                }
+                Err(e) if matches!(e.kind(),
+                                   std::io::ErrorKind::WouldBlock
+                                   | std::io::ErrorKind::TimedOut) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        match packets.remove(0) {
+            Packet::Handshake(LobbyResponse::Error(message))
+            | Packet::Data(LobbyResponse::Error(message)) => {
+                Err(std::io::Error::other(message))
+            }
+            Packet::Handshake(response) | Packet::Data(response) => {
+                Ok(response)
+            }
+        }
+    }
+
+    pub fn list(&mut self) -> std::io::Result<Vec<OpenGame>> {
+        match self.request(&LobbyRequest::List)? {
+            LobbyResponse::Games(games) => Ok(games),
+            response => Err(std::io::Error::other(
+                format!("unexpected response: {response:?}"))),
+        }
+    }
+
+    /// Creates a game and starts playing it as the given colour.
+    pub fn create(self, is_white: bool, framing: Framing,
+                  recorder: Option<Recorder>)
+        -> std::io::Result<ClientConnection> {
+        let request = LobbyRequest::Create { is_white };
+        self.seat(&request, framing, recorder)
+            .map(|(connection, _)| connection)
+    }
+
+    /// Joins an open game. Returns whether we play white.
+    pub fn join(self, id: u64, framing: Framing, recorder: Option<Recorder>)
+        -> std::io::Result<(ClientConnection, bool)> {
+        self.seat(&LobbyRequest::Join { id }, framing, recorder)
+    }
+
+    fn seat(mut self, request: &LobbyRequest, framing: Framing,
+            recorder: Option<Recorder>)
+        -> std::io::Result<(ClientConnection, bool)> {
+        let LobbyResponse::Joined { id, is_white } = self.request(request)?
+        else {
+            return Err(std::io::Error::other("unexpected response"))
+        };
+        println!("[client] playing game {id} as {}", color_name(is_white));
+        let connection =
+            start_client(self.stream, !is_white, framing, recorder)?;
+        Ok((connection, is_white))
+    }
+}
+
+/// The reply to lobby requests made on a thread of their own, so that
+/// waiting for the lobby does not hold up the caller.
+pub struct LobbyReply<T> {
+    reply: Receiver<std::io::Result<T>>,
+}
+
+impl<T: Send + 'static> LobbyReply<T> {
+    /// Runs `requests` on a new thread.
+    pub fn spawn(requests: impl FnOnce() -> std::io::Result<T>
+                           + Send + 'static)
+        -> std::io::Result<Self> {
+        let (sender, reply) = std::sync::mpsc::channel();
+        std::thread::Builder::new()
+            .name("lobby".into())
+            .spawn(move || {
+                let _ = sender.send(requests());
+            })?;
+        Ok(LobbyReply { reply })
+    }
+
+    /// The result of the requests, once the lobby has answered them.
+    pub fn poll(&self) -> Option<std::io::Result<T>> {
+        match self.reply.try_recv() {
+            Ok(result) => Some(result),
+            Err(TryRecvError::Empty) => None,
+            Err(TryRecvError::Disconnected) => {
+                Some(Err(std::io::Error::other("the lobby thread failed")))
+            }
+        }
+    }
+}
@@ -1,13 +1,17 @@
 // The boilerplate code is shamelessly stolen from the imgui rust examples.
 // If you get a linking error, download SDL2.lib from the SDL website.
 
-use std::{io::Read, marker::PhantomData};
-
-use chess_network_protocol::{ClientToServerHandshake,
-                             ClientToServer, ServerToClient,
-                             ServerToClientHandshake,
-                             Piece, Move, Joever};
-use chess_network_protocol::Color::White;
+use albjorkm_chess_gui::{bind_host, connect_client_recorded, usage,
+                         wire_move_to_indices, ChessState, ClientConnection,
+                         ClientSession, Clock, ColorChoice, Connection,
+                         GameEnd, HostSession, InteropLog, InteropMode,
+                         MatchScore, Rematch, Settings, TimeControl,
+                         UnsentNetAction, UnsentNetMove, DEFAULT_HEARTBEAT};
+use albjorkm_chess_gui::clock::format_time;
+use albjorkm_chess_gui::discovery::{Announcer, Discovery, DISCOVERY_PORT};
+use albjorkm_chess_gui::lobby::{LobbyClient, LobbyReply, OpenGame};
+use albjorkm_chess_gui::record::{Recorder, Role};
+use albjorkm_chess_gui::referee::color_name;
 use glow::HasContext;
 use imgui::{Context, WindowFlags};
 use imgui_glow_renderer::AutoRenderer;
@@ -17,213 +21,254 @@ use sdl2::{
     video::{GLProfile, Window},
 };
 
-fn chess_piece_to_wire(square: (i8, i8)) -> Piece {
-    match square {
-        (1, -1) => Piece::WhitePawn,
-        (2, -1) => Piece::WhiteRook,
-        (3, -1) => Piece::WhiteKnight,
-        (4, -1) => Piece::WhiteBishop,
-        (5, -1) => Piece::WhiteQueen,
-        (6, -1) => Piece::WhiteKing,
-        (1, 1) => Piece::BlackPawn,
-        (2, 1) => Piece::BlackRook,
-        (3, 1) => Piece::BlackKnight,
-        (4, 1) => Piece::BlackBishop,
-        (5, 1) => Piece::BlackQueen,
-        (6, 1) => Piece::BlackKing,
-        _ => Piece::None,
-    }
-}
-
-fn chess_representaiton_to_wire(data: &[(i8, i8); 64]) -> [[Piece; 8]; 8] {
-    let mut result = [[Piece::None; 8]; 8];
-    for i in 0..64 {
-        let square = data[i];
-        let row = i >> 3;
-        let column = i & 7;
-        result[7 - row][column] = chess_piece_to_wire(square);
-    }
-    result
-}
-
-fn wire_to_chess_representation(data: &[[Piece; 8]; 8]) -> [(i8, i8); 64] {
-    let mut result = [(0, 0); 64];
-    for i in 0..64 {
-        let row = i >> 3;
-        let column = i & 7;
-        let square = data[7 - row][column];
-        result[i] = match square {
-            Piece::WhitePawn   => (1, -1),
-            Piece::WhiteRook   => (2, -1),
-            Piece::WhiteKnight => (3, -1),
-            Piece::WhiteBishop => (4, -1),
-            Piece::WhiteQueen  => (5, -1),
-            Piece::WhiteKing   => (6, -1),
-            Piece::BlackPawn   => (1, 1),
-            Piece::BlackRook   => (2, 1),
-            Piece::BlackKnight => (3, 1),
-            Piece::BlackBishop => (4, 1),
-            Piece::BlackQueen  => (5, 1),
-            Piece::BlackKing   => (6, 1),
-            _ => (0, 0),
-        }
-    }
-    result
-}
-
 enum GameMode {
-    Undecided(String),
-    HostWaitForOpponent(std::net::TcpListener),
-    Host(std::net::TcpStream, JsonPoller<ClientToServerHandshake,
-                                         ClientToServer>),
-    Client(std::net::TcpStream, JsonPoller<ServerToClientHandshake,
-                                           ServerToClient>),
+    /// The player has not yet picked a mode. Holds the error of the last
+    /// failed attempt at hosting or joining, if any.
+    Undecided(Option<String>),
+    /// The game is announced on the local network while we wait, if that
+    /// can be done.
+    HostWaitForOpponent(std::net::TcpListener, Option<Announcer>),
+    /// The listener is kept around to accept spectators, and so that we can
+    /// wait for a new opponent if this one disconnects.
+    Host(std::net::TcpListener, HostSession),
+    Client(ClientSession),
+    /// The opponent or server went away. The host keeps its listener, and
+    /// both keep the error of the last attempt at reconnecting.
+    Disconnected(Option<std::net::TcpListener>, Option<String>),
+    /// The opponent or server, having promised to send heartbeats, went
+    /// silent. Holds the `Host` or `Client` mode to return to should it come
+    /// back.
+    ConnectionLost(Box<GameMode>),
     Local,
 }
 
-enum UnsentNetMove {
-    None,
-    PendingPromotion(Move),
-    Unsent(Move),
-}
-
-struct ChessState {
-    chess_board: chess::ChessBoard,
-    chess_representation: [(i8, i8); 64],
-    moving_piece: usize,
-    is_white_turn: bool,
-    is_promoting: bool,
-    is_game_over: bool,
-    is_client: bool,
-    unsent_net_move: UnsentNetMove,
-}
-
-impl ChessState {
-    fn do_move(self: &mut Self, from: usize, to: usize) -> bool {
-        if self.is_game_over {
-            println!("The game is over, moving is not allowed");
-            return false
-        }
-        if self.is_promoting {
-            println!("Please promote first");
-        }
-
-        let to_rank = to >> 3;
-
-        let net_move = Move {
-            end_y: 7 - to_rank,
-            end_x: to & 7,
-            start_y: 7 - (from >> 3),
-            start_x: from & 7,
-            promotion: Piece::None,
+impl GameMode {
+    fn wait_for_opponent(listener: std::net::TcpListener) -> GameMode {
+        let announcer = match listener.local_addr() {
+            // Nobody else on the network can join a game on loopback, so
+            // there is nothing to announce.
+            Ok(address) if address.ip().is_loopback() => Ok(None),
+            Ok(address) => Announcer::broadcast(address.port()).map(Some),
+            Err(e) => Err(e),
         };
-
-        let mut did_move = false;
-
-        if self.is_client {
-            self.is_promoting = self.chess_representation[from].0 == 1 &&
-                (to_rank == 0 || to_rank == 7);
-            did_move = true;
-        } else if self.chess_board.move_by_index(from, to) {
-            self.chess_representation = self.chess_board.get_board();
-            self.is_white_turn = !self.is_white_turn;
-            self.is_promoting = self.chess_board.can_promote();
-
-
-            self.unsent_net_move = if self.is_promoting {
-                // If we are promoting, we don't want to send the
-                // update just yet.
-                UnsentNetMove::PendingPromotion(net_move)
-            } else {
-                UnsentNetMove::Unsent(net_move)
-            };
-
-            self.is_game_over = self.chess_board.is_game_ended();
-            did_move = true;
-        }
-
-        if did_move {
-            self.unsent_net_move = if self.is_promoting {
-                // If we are promoting, we don't want to send the
-                // update just yet.
-                UnsentNetMove::PendingPromotion(net_move)
-            } else {
-                UnsentNetMove::Unsent(net_move)
-            };
-        }
-
-        did_move
+        let announcer = match announcer {
+            Ok(announcer) => announcer,
+            Err(e) => {
+                eprintln!("[discovery] cannot announce the game: {e}");
+                None
+            }
+        };
+        GameMode::HostWaitForOpponent(listener, announcer)
     }
-    fn promote(self: &mut Self, piece: i8) {
-        self.chess_board.promote(piece);
-        self.is_promoting = false;
-        if !self.is_client {
-            self.chess_representation = self.chess_board.get_board();
-            self.is_game_over = self.chess_board.is_game_ended();
+    fn timed_out(&self) -> bool {
+        match self {
+            GameMode::Host(_, session) => session.timed_out(),
+            GameMode::Client(session) => session.timed_out(),
+            GameMode::ConnectionLost(mode) => mode.timed_out(),
+            _ => false,
         }
-        let UnsentNetMove::PendingPromotion(mut mv)
-            = self.unsent_net_move else {
-            panic!("promote() called with bad unsent_net_move value");
-        };
-        let color = if self.is_white_turn { 1 } else { -1 };
-        mv.promotion = chess_piece_to_wire((piece, color));
-        self.unsent_net_move = UnsentNetMove::Unsent(mv);
-    }
-    fn ingest_client_move(self: &mut Self, mv: &Move)
-        -> bool {
-        let from = (7 - mv.start_y) << 3 | mv.start_x;
-        let to = (7 - mv.end_y) << 3 | mv.end_x;
-        let result = self.do_move(from, to);
-        if self.is_promoting {
-            let piece = match mv.promotion {
-                Piece::WhitePawn   | Piece::BlackPawn   => 1,
-                Piece::WhiteRook   | Piece::BlackRook   => 2,
-                Piece::WhiteKnight | Piece::BlackKnight => 3,
-                Piece::WhiteBishop | Piece::BlackBishop => 4,
-                Piece::WhiteQueen  | Piece::BlackQueen  => 5,
-                Piece::WhiteKing   | Piece::BlackKing   => 6,
-                _ => 0,
-            };
-            self.promote(piece);
+    }
+    /// Gives a silent peer another timeout to come back in.
+    fn wait_longer(&self) {
+        match self {
+            GameMode::Host(_, session) => session.connection.wait_longer(),
+            GameMode::Client(session) => session.connection.wait_longer(),
+            GameMode::ConnectionLost(mode) => mode.wait_longer(),
+            _ => {}
         }
-        result
     }
-    fn to_joever(&self) -> Joever {
-        if self.is_game_over {
-            Joever::Indeterminate
-        } else {
-            Joever::Ongoing
+    /// The clock kept by the session, if any.
+    fn clock(&self) -> Option<&Clock> {
+        match self {
+            GameMode::Host(_, session) => session.clock.as_ref(),
+            GameMode::Client(session) => session.clock.as_ref(),
+            GameMode::ConnectionLost(mode) => mode.clock(),
+            _ => None,
         }
     }
 }
 
+/// A request to the lobby waiting for its reply.
+enum LobbyTask {
+    List(LobbyReply<(Vec<OpenGame>, LobbyClient)>),
+    /// Taking a seat, which also tells the address of the lobby.
+    Seat(LobbyReply<(ClientConnection, bool, std::net::SocketAddr)>),
+}
+
 /// All the state related to the running of the game including netcode.
 struct GameState {
     chess_state: ChessState,
     mode: GameMode,
     host_is_white: bool,
+    settings: Settings,
+    /// The latest error from the server that has not yet been dismissed.
+    error_toast: Option<String>,
+    /// Every error the server has sent us this game.
+    error_log: Vec<String>,
+    /// Every time we disagreed with the server about whose turn it was.
+    desync_log: Vec<String>,
+    /// The interop summaries of the sessions that have ended.
+    interop_reports: Vec<String>,
+    show_debug: bool,
+    /// The lobby the open games were last listed from, kept to join one of
+    /// them.
+    lobby: Option<LobbyClient>,
+    lobby_games: Vec<OpenGame>,
+    /// The lobby request being answered, if any.
+    lobby_task: Option<LobbyTask>,
+    /// The address of the server we joined, which is dialled again to
+    /// reconnect.
+    server_address: Option<String>,
+    /// Listens for games announced on the local network. Kept from game to
+    /// game, as only one can listen on the port.
+    discovery: Option<Discovery>,
+    /// The line of chat being written.
+    chat_input: String,
+    /// The clock of local games, and of network games between sessions.
+    clock: Option<Clock>,
+    /// The games played against the same opponent through rematches.
+    score: MatchScore,
+    /// Whether the game that is over has been added to the score.
+    scored: bool,
 }
 
 impl GameState {
-    fn new_game() -> GameState {
-        let chess_board = chess::ChessBoard::new();
-        let chess_representation = chess_board.get_board();
+    fn interop(&self) -> Option<&InteropLog> {
+        let mode = match &self.mode {
+            GameMode::ConnectionLost(mode) => mode.as_ref(),
+            mode => mode,
+        };
+        match mode {
+            GameMode::Host(_, session) => Some(&session.interop),
+            GameMode::Client(session) => Some(&session.interop),
+            _ => None,
+        }
+    }
+    /// Prints and keeps the interop summary of the session that is ending.
+    fn report_interop(&mut self) -> Option<String> {
+        let interop = self.interop()?;
+        let summary = interop.summary();
+        println!("[interop] {summary}");
+        // In strict mode it was the last deviation that made us hang up.
+        let reason = match interop.mode {
+            InteropMode::Strict => interop.deviations.last()
+                .map(|deviation| format!("Hung up in strict mode: \
+                                          {deviation}")),
+            InteropMode::Lenient => None,
+        };
+        self.interop_reports.push(summary);
+        reason
+    }
+    /// Leaves a networked mode after the connection was lost, keeping the
+    /// position so that the game can be resumed.
+    fn disconnect(&mut self) {
+        let reason = self.report_interop();
+        let mode = match std::mem::replace(&mut self.mode, GameMode::Local) {
+            GameMode::ConnectionLost(mode) => *mode,
+            mode => mode,
+        };
+        self.mode = match mode {
+            GameMode::Host(listener, session) => {
+                println!("[server] opponent disconnected");
+                self.clock = session.clock;
+                GameMode::Disconnected(Some(listener), reason)
+            }
+            GameMode::Client(session) => {
+                println!("[client] server disconnected");
+                self.clock = session.clock.or(self.clock.take());
+                GameMode::Disconnected(None, reason)
+            }
+            mode => mode,
+        };
+        // The clock waits for the game to be resumed.
+        if let Some(clock) = &mut self.clock {
+            clock.stop();
+        }
 
+        let chess_state = &mut self.chess_state;
+        chess_state.draw_offered = false;
+        chess_state.unsent_net_action = UnsentNetAction::None;
+        // The board is sent in full on the next handshake. A host that is
+        // promoting keeps its pending move since the board waits for it.
+        if chess_state.is_client {
+            chess_state.is_promoting = false;
+            chess_state.unsent_net_move = UnsentNetMove::None;
+        } else if let UnsentNetMove::Unsent(_) = chess_state.unsent_net_move {
+            chess_state.unsent_net_move = UnsentNetMove::None;
+        }
+    }
+    /// Moves into `ConnectionLost` when the peer goes silent, and back out of
+    /// it when the peer is heard from again.
+    fn check_liveness(&mut self) {
+        let lost = matches!(self.mode, GameMode::ConnectionLost(_));
+        if self.mode.timed_out() == lost {
+            return
+        }
+        let mode = std::mem::replace(&mut self.mode, GameMode::Local);
+        self.mode = match mode {
+            GameMode::ConnectionLost(mode) => {
+                println!("[network] connection is back");
+                *mode
+            }
+            mode => {
+                println!("[network] connection lost");
+                GameMode::ConnectionLost(Box::new(mode))
+            }
+        };
+    }
+    fn new_game(settings: Settings) -> GameState {
         GameState {
-            chess_state: ChessState {
-                chess_board,
-                chess_representation,
-                moving_piece: 65,
-                unsent_net_move: UnsentNetMove::None,
-                is_white_turn: true,
-                is_promoting: false,
-                is_game_over: false,
-                is_client: false,
-            },
-            mode: GameMode::Undecided(String::from("localhost")),
+            chess_state: ChessState::new(),
+            mode: GameMode::Undecided(None),
             host_is_white: true,
+            settings,
+            error_toast: None,
+            error_log: vec![],
+            desync_log: vec![],
+            interop_reports: vec![],
+            show_debug: false,
+            lobby: None,
+            lobby_games: vec![],
+            lobby_task: None,
+            server_address: None,
+            discovery: None,
+            chat_input: String::new(),
+            clock: None,
+            score: MatchScore::default(),
+            scored: false,
         }
     }
+    /// Adds the game to the score once it is over. Only network games have
+    /// an opponent to keep score against.
+    fn keep_score(&mut self) {
+        let chess_state = &self.chess_state;
+        if !chess_state.is_game_over || self.scored
+            || !(chess_state.is_host || chess_state.is_client) {
+            return
+        }
+        let we_are_white = if chess_state.is_client {
+            !self.host_is_white
+        } else {
+            self.host_is_white
+        };
+        self.score.add(&chess_state.to_joever(), we_are_white);
+        self.scored = true;
+    }
+    /// Takes on the new game once the session has started a rematch. The
+    /// host's colour is kept up to date by the host session itself.
+    fn start_rematch(&mut self) {
+        if self.chess_state.is_client {
+            self.host_is_white = !self.host_is_white;
+        }
+        self.scored = false;
+        self.error_toast = None;
+    }
+    /// Goes back to picking a mode for a new game.
+    fn restart(&mut self) {
+        let discovery = self.discovery.take();
+        *self = GameState::new_game(self.settings.clone());
+        self.discovery = discovery;
+    }
 }
 
 fn piece_to_unicode(piece: i8) -> &'static str {
@@ -245,7 +290,8 @@ fn team_to_color(team: i8) -> [f32; 4] {
     if team == 1 { [1.0, 0.0, 1.0, 1.0 ] } else { [1.0, 1.0, 0.0, 1.0] }
 }
 
-fn draw_chess(ui: &imgui::Ui, chess_state: &mut ChessState, can_move: bool) {
+fn draw_chess(ui: &imgui::Ui, chess_state: &mut ChessState, can_move: bool,
+              player_is_white: bool, clock: Option<&Clock>) {
     let display_size = ui.io().display_size;
     let cell_size = (display_size[0].min(display_size[1]) / 8.).round() - 10.;
     let draw_list = ui.get_window_draw_list();
@@ -259,38 +305,55 @@ fn draw_chess(ui: &imgui::Ui, chess_state: &mut ChessState, can_move: bool) {
             draw_list.add_rect(begin, end, 0xFF336622).filled(true).build();
         }
     }
-
-    let _no_padding = ui.push_style_var(imgui::StyleVar::ItemSpacing([0., 0.]));
-    let _no_bg = ui.push_style_color(imgui::StyleColor::Button, [0., 0., 0., 0.]);
-    let _no_border_popup = ui.push_style_var(imgui::StyleVar::PopupBorderSize(0.));
-    let _no_bg = ui.push_style_color(imgui::StyleColor::PopupBg, [0., 0., 0., 0.]);
-    for i in 0..64 {
-        if i % 8 != 0 {
-            ui.same_line();
+    if chess_state.moving_piece < 64 {
+        for mv in &chess_state.legal_moves {
+            let Some((from, to)) = wire_move_to_indices(mv) else {
+                continue
+            };
+            if from != chess_state.moving_piece {
+                continue
+            }
+            let row = (to >> 3) as f32;
+            let column = (to & 7) as f32;
+            let center = [cell_size * (column + 0.5), cell_size * (row + 0.5)];
+            draw_list.add_circle(center, cell_size / 6., 0x88FFFFFF).filled(true).build();
         }
-        let _id = ui.push_id_usize(i);
-        let (piece, team) = chess_state.chess_representation[i];
-        let piece_unicode = if chess_state.moving_piece == i {
-            "###0"
-        } else {
-            piece_to_unicode(piece)
-        };
-        let fg_color = team_to_color(team);
-        let _color_stck = ui.push_style_color(imgui::StyleColor::Text, fg_color);
-        ui.button_with_size(piece_unicode, [cell_size, cell_size]);
+    }
 
-        if !can_move {
-            continue
-        }
+    // The styles only apply to the board, not the buttons beneath it.
+    {
+        let _no_padding = ui.push_style_var(imgui::StyleVar::ItemSpacing([0., 0.]));
+        let _no_bg = ui.push_style_color(imgui::StyleColor::Button, [0., 0., 0., 0.]);
+        let _no_border_popup = ui.push_style_var(imgui::StyleVar::PopupBorderSize(0.));
+        let _no_bg = ui.push_style_color(imgui::StyleColor::PopupBg, [0., 0., 0., 0.]);
+        for i in 0..64 {
+            if i % 8 != 0 {
+                ui.same_line();
+            }
+            let _id = ui.push_id_usize(i);
+            let (piece, team) = chess_state.chess_representation[i];
+            let piece_unicode = if chess_state.moving_piece == i {
+                "###0"
+            } else {
+                piece_to_unicode(piece)
+            };
+            let fg_color = team_to_color(team);
+            let _color_stck = ui.push_style_color(imgui::StyleColor::Text, fg_color);
+            ui.button_with_size(piece_unicode, [cell_size, cell_size]);
 
-        if let Some(_) = ui.drag_drop_source_config("move").begin_payload(i) {
-            chess_state.moving_piece = i;
-        }
-        if let Some(v) = ui.drag_drop_target() {
-            if let Some(p) = v.accept_payload("move", imgui::DragDropFlags::empty()) {
-                if let Ok(v) = p {
-                    let source: usize = v.data;
-                    chess_state.do_move(source, i);
+            if !can_move {
+                continue
+            }
+
+            if let Some(_) = ui.drag_drop_source_config("move").begin_payload(i) {
+                chess_state.moving_piece = i;
+            }
+            if let Some(v) = ui.drag_drop_target() {
+                if let Some(p) = v.accept_payload("move", imgui::DragDropFlags::empty()) {
+                    if let Ok(v) = p {
+                        let source: usize = v.data;
+                        chess_state.do_move(source, i);
+                    }
                 }
             }
         }
@@ -308,14 +371,75 @@ fn draw_chess(ui: &imgui::Ui, chess_state: &mut ChessState, can_move: bool) {
         }
     }
     if chess_state.is_game_over {
-        draw_list.add_text([cell_size * 4. - 150., cell_size * 4.], 0xFFFFFFFF, "IT'S SO OVER!");
-    } else {
+        let text = chess_state.end_text();
+        let text_size = ui.calc_text_size(text);
+        let begin = [cell_size * 4. - text_size[0] / 2., cell_size * 4. - text_size[1] / 2.];
+        let end = [begin[0] + text_size[0], begin[1] + text_size[1]];
+        draw_list.add_rect(begin, end, 0xAA000000).filled(true).build();
+        draw_list.add_text(begin, 0xFFFFFFFF, text);
+    }
+    // The clocks go to the right of the board, each next to the side it
+    // times.
+    if let Some(clock) = clock {
+        let left = cell_size * 8. + 10.;
+        let control = clock.control.to_string();
+        let control_size = ui.calc_text_size(&control);
+        draw_list.add_text([left, cell_size * 4. - control_size[1] / 2.],
+                           0xFF888888, control);
+        for white in [false, true] {
+            let remaining = clock.remaining(white);
+            let text = format_time(remaining);
+            let top = if white {
+                cell_size * 8. - 10. - ui.calc_text_size(&text)[1]
+            } else {
+                10.
+            };
+            let color = if remaining.is_zero() {
+                0xFF4444FF
+            } else if clock.running() == Some(white) {
+                0xFFFFFFFF
+            } else {
+                0xFF888888
+            };
+            draw_list.add_text([left, top], color, text);
+        }
+    }
+    if !chess_state.is_game_over {
         let t = if chess_state.is_white_turn {
             "It is white's turn"
         } else {
             "It is black's turn"
         };
         ui.text(t);
+
+        if ui.button("Resign") {
+            chess_state.resign(player_is_white);
+        }
+        // The protocol has no way for the host to offer a draw.
+        if !chess_state.is_host {
+            ui.same_line();
+            if chess_state.is_client && chess_state.draw_offered {
+                ui.text_disabled("Draw offered");
+            } else if ui.button("Offer Draw") {
+                chess_state.offer_draw();
+            }
+        }
+    }
+
+    if chess_state.draw_offered && !chess_state.is_client {
+        let window = ui.window("Draw offered")
+            .size([400., 0.], imgui::Condition::Always)
+            .flags(WindowFlags::NO_COLLAPSE);
+        if let Some(_t) = window.begin() {
+            ui.text("Accept a draw?");
+            if ui.button("Accept") {
+                chess_state.answer_draw(true);
+            }
+            ui.same_line();
+            if ui.button("Decline") {
+                chess_state.answer_draw(false);
+            }
+        }
     }
 
     if chess_state.is_promoting {
@@ -323,69 +447,311 @@ fn draw_chess(ui: &imgui::Ui, chess_state: &mut ChessState, can_move: bool) {
             .size([230., 0.], imgui::Condition::Always)
             .flags(WindowFlags::NO_COLLAPSE);
         if let Some(_t) = window.begin() {
+            let mut piece = None;
             if ui.button("\u{265C}") {
-                chess_state.promote(2);
+                piece = Some(2);
             }
             ui.same_line();
             if ui.button("\u{265E}") {
-                chess_state.promote(3);
+                piece = Some(3);
             }
             ui.same_line();
             if ui.button("\u{265D}") {
-                chess_state.promote(4);
+                piece = Some(4);
             }
             ui.same_line();
             if ui.button("\u{265B}") {
-                chess_state.promote(5);
+                piece = Some(5);
+            }
+            if let Some(Err(message)) = piece.map(|p| chess_state.promote(p)) {
+                println!("[client] {message}");
             }
         }
     }
 }
 
+/// Picks how games that we host or play locally are timed.
+fn draw_time_control(ui: &imgui::Ui, clock: &mut Option<TimeControl>) {
+    let kinds = ["Untimed", "Sudden death", "Increment", "Delay"];
+    let mut kind = match clock {
+        None => 0,
+        Some(TimeControl::SuddenDeath { .. }) => 1,
+        Some(TimeControl::Increment { .. }) => 2,
+        Some(TimeControl::Delay { .. }) => 3,
+    };
+    ui.combo_simple_string("Time control", &mut kind, &kinds);
+    let mut minutes = clock.map_or(10, |c| c.base().as_secs() / 60) as i32;
+    let mut seconds = clock.map_or(5, |c| {
+        (c.increment() + c.delay()).as_secs()
+    }) as i32;
+    if kind != 0 {
+        ui.input_int("Minutes", &mut minutes).build();
+    }
+    match kind {
+        2 => ui.input_int("Increment seconds", &mut seconds).build(),
+        3 => ui.input_int("Delay seconds", &mut seconds).build(),
+        _ => false,
+    };
+    let base_ms = minutes.clamp(1, 600) as u64 * 60_000;
+    let bonus_ms = seconds.clamp(0, 600) as u64 * 1000;
+    *clock = match kind {
+        1 => Some(TimeControl::SuddenDeath { base_ms }),
+        2 => Some(TimeControl::Increment { base_ms, increment_ms: bonus_ms }),
+        3 => Some(TimeControl::Delay { base_ms, delay_ms: bonus_ms }),
+        _ => None,
+    };
+}
+
 fn draw_ui(ui: &imgui::Ui, game_state: &mut GameState) {
-    if let GameMode::Undecided(address) = &mut game_state.mode {
+    if let GameMode::Undecided(error) = &mut game_state.mode {
+        let settings = &mut game_state.settings;
         let window = ui.window("Select Mode")
             .size([500., 0.], imgui::Condition::Once);
         if let Some(_t) = window.begin() {
             if ui.button("Local Play") {
+                game_state.clock = settings.clock.map(Clock::new);
                 game_state.mode = GameMode::Local;
                 return
             }
+            let _ = ui.input_text("Bind", &mut settings.bind_address).build();
+            let mut port = settings.port as i32;
+            if ui.input_int("Port", &mut port).step(0).build() {
+                settings.port = port.clamp(0, u16::MAX as i32) as u16;
+            }
+            draw_time_control(ui, &mut settings.clock);
             if ui.button("Host Game") {
-                let listener = std::net::TcpListener::bind("127.0.0.1:8483")
-                    .unwrap();
-                listener.set_nonblocking(true).unwrap();
-                game_state.mode = GameMode::HostWaitForOpponent(listener);
-                return
+                match bind_host(&settings.bind_address, settings.port) {
+                    Ok(listener) => {
+                        game_state.mode = GameMode::wait_for_opponent(listener);
+                        return
+                    }
+                    Err(e) => {
+                        let address = &settings.bind_address;
+                        let port = settings.port;
+                        eprintln!("[server] failed to bind {address}: {e}");
+                        *error = Some(format!("Could not host on \
+                                               {address} port {port}: {e}"));
+                    }
+                }
             }
+            ui.separator();
+            let address = &mut settings.join_address;
             let _ = ui.input_text("Address", address).build();
-            if ui.button("Join Game") {
-                let address = if address.contains(":") {
-                    address.clone()
+            ui.text("Play as");
+            let color = &mut settings.color;
+            ui.same_line();
+            ui.radio_button("White", color, ColorChoice::White);
+            ui.same_line();
+            ui.radio_button("Black", color, ColorChoice::Black);
+            ui.same_line();
+            ui.radio_button("Random", color, ColorChoice::Random);
+            ui.separator();
+            let mut strict = settings.interop == InteropMode::Strict;
+            if ui.checkbox("Strict protocol", &mut strict) {
+                settings.interop = if strict {
+                    InteropMode::Strict
                 } else {
-                    format!("{address}:8483")
-                };
-                println!("[client] attempting to connect to: {address}");
-                let stream = std::net::TcpStream::connect(address).unwrap();
-                stream.set_nonblocking(true).unwrap();
-
-                let handshake = ClientToServerHandshake {
-                    server_color: chess_network_protocol::Color::Black,
+                    InteropMode::Lenient
                 };
-                serde_json::to_writer(&stream, &handshake).unwrap();
-
-
-                game_state.mode = GameMode::Client(stream, JsonPoller::new());
-                return
+            }
+            let mut record = settings.record.is_some();
+            if ui.checkbox("Record traffic", &mut record) {
+                settings.record = record.then(|| String::from("."));
+            }
+            if let Some(directory) = &mut settings.record {
+                let _ = ui.input_text("Session directory", directory).build();
+            }
+            let mut heartbeat = settings.heartbeat.is_some();
+            if ui.checkbox("Heartbeats", &mut heartbeat) {
+                settings.heartbeat = heartbeat.then_some(DEFAULT_HEARTBEAT);
+            }
+            if let Some(interval) = &mut settings.heartbeat {
+                let mut seconds = interval.as_secs() as i32;
+                if ui.input_int("Heartbeat seconds", &mut seconds).build() {
+                    let seconds = seconds.clamp(1, 3600) as u64;
+                    *interval = std::time::Duration::from_secs(seconds);
+                }
+            }
+            ui.checkbox("Chat", &mut settings.chat);
+            if let Some(error) = error {
+                ui.separator();
+                ui.text_colored([1.0, 0.3, 0.3, 1.0], error);
+            }
+            let mut join = None;
+            if ui.button("Join Game") {
+                join = Some(address.clone());
+            }
+            if let Some(discovery) = &game_state.discovery {
+                ui.separator();
+                ui.text("Games on the local network");
+                if discovery.games().is_empty() {
+                    ui.text("None found yet");
+                }
+                for game in discovery.games() {
+                    ui.text(game.address.to_string());
+                    ui.same_line();
+                    if ui.button(format!("Join##{}", game.address)) {
+                        join = Some(game.address.to_string());
+                    }
+                }
+            }
+            if let Some(target) = join {
+                // The handshake names the colour of the server, which is the
+                // opposite of the one we play as.
+                let is_white = settings.color.is_white();
+                let recorder = Recorder::start(settings.record.as_deref(),
+                                               Role::Client);
+                match connect_client_recorded(&target, !is_white,
+                                              settings.framing, recorder) {
+                    Ok(connection) => {
+                        println!("[client] playing as {}",
+                                 if is_white { "white" } else { "black" });
+                        game_state.host_is_white = !is_white;
+                        game_state.server_address = Some(target);
+                        let mut session = ClientSession::new(connection);
+                        session.interop.mode = settings.interop;
+                        session.heartbeat = settings.heartbeat;
+                        session.chat.enabled = settings.chat;
+                        game_state.mode = GameMode::Client(session);
+                        return
+                    }
+                    Err(e) => {
+                        eprintln!("[client] failed to connect: {e}");
+                        *error = Some(format!("Could not join {target}: \
+                                               {e}"));
+                    }
+                }
+            }
+            ui.separator();
+            ui.text("Lobby");
+            let mut seat = None;
+            match &game_state.lobby_task {
+                Some(LobbyTask::List(reply)) => {
+                    ui.text("Waiting for the lobby");
+                    match reply.poll() {
+                        Some(Ok((games, lobby))) => {
+                            game_state.lobby_games = games;
+                            game_state.lobby = Some(lobby);
+                            game_state.lobby_task = None;
+                        }
+                        Some(Err(e)) => {
+                            eprintln!("[client] failed to list games: {e}");
+                            *error = Some(format!("Could not list the games \
+                                                   at {address}: {e}"));
+                            game_state.lobby_task = None;
+                        }
+                        None => {}
+                    }
+                }
+                Some(LobbyTask::Seat(reply)) => {
+                    ui.text("Waiting for the lobby");
+                    seat = reply.poll();
+                    if seat.is_some() {
+                        game_state.lobby_task = None;
+                    }
+                }
+                None => {
+                    let mut task = None;
+                    if ui.button("List Games") {
+                        let address = address.clone();
+                        let reply = LobbyReply::spawn(move || {
+                            let mut lobby = LobbyClient::connect(&address)?;
+                            Ok((lobby.list()?, lobby))
+                        });
+                        task = Some(reply.map(LobbyTask::List));
+                    }
+                    ui.same_line();
+                    if ui.button("Create Game") {
+                        let is_white = settings.color.is_white();
+                        let framing = settings.framing;
+                        let recorder = Recorder::start(
+                            settings.record.as_deref(), Role::Client);
+                        let lobby = game_state.lobby.take();
+                        let address = address.clone();
+                        let reply = LobbyReply::spawn(move || {
+                            let lobby = match lobby {
+                                Some(lobby) => lobby,
+                                None => LobbyClient::connect(&address)?,
+                            };
+                            let lobby_address = lobby.peer_addr()?;
+                            let connection =
+                                lobby.create(is_white, framing, recorder)?;
+                            Ok((connection, is_white, lobby_address))
+                        });
+                        task = Some(reply.map(LobbyTask::Seat));
+                    }
+                    if game_state.lobby.is_some()
+                        && game_state.lobby_games.is_empty() {
+                        ui.text("No open games");
+                    }
+                    for game in &game_state.lobby_games {
+                        ui.text(format!("Game {}: play as {}", game.id,
+                                        color_name(game.is_white)));
+                        ui.same_line();
+                        if !ui.button(format!("Join##{}", game.id)) {
+                            continue
+                        }
+                        if let Some(lobby) = game_state.lobby.take() {
+                            let recorder = Recorder::start(
+                                settings.record.as_deref(), Role::Client);
+                            let framing = settings.framing;
+                            let id = game.id;
+                            let reply = LobbyReply::spawn(move || {
+                                let lobby_address = lobby.peer_addr()?;
+                                let (connection, is_white) =
+                                    lobby.join(id, framing, recorder)?;
+                                Ok((connection, is_white, lobby_address))
+                            });
+                            task = Some(reply.map(LobbyTask::Seat));
+                        }
+                    }
+                    match task {
+                        Some(Ok(task)) => game_state.lobby_task = Some(task),
+                        Some(Err(e)) => {
+                            eprintln!("[client] failed to ask the lobby: {e}");
+                            *error = Some(format!("Could not ask the lobby \
+                                                   at {address}: {e}"));
+                        }
+                        None => {}
+                    }
+                }
+            }
+            match seat {
+                Some(Ok((connection, is_white, lobby_address))) => {
+                    game_state.host_is_white = !is_white;
+                    let lobby_address = lobby_address.to_string();
+                    game_state.server_address = Some(lobby_address);
+                    game_state.lobby_games.clear();
+                    let mut session = ClientSession::new(connection);
+                    session.interop.mode = settings.interop;
+                    session.heartbeat = settings.heartbeat;
+                    session.chat.enabled = settings.chat;
+                    game_state.mode = GameMode::Client(session);
+                    return
+                }
+                Some(Err(e)) => {
+                    eprintln!("[client] failed to take a seat: {e}");
+                    game_state.lobby_games.clear();
+                    *error = Some(format!("Could not start a game at \
+                                           {address}: {e}"));
+                }
+                None => {}
             }
         }
         return
     }
-    if let GameMode::HostWaitForOpponent(_) = &mut game_state.mode {
+    if let GameMode::HostWaitForOpponent(listener, announcer)
+        = &mut game_state.mode {
         let window = ui.window("Awaiting opponent!")
             .size([500., 0.], imgui::Condition::Once);
         if let Some(_t) = window.begin() {
             ui.text("Please wait");
+            if let Ok(address) = listener.local_addr() {
+                ui.text(format!("Listening on {address}"));
+            }
+            if announcer.is_some() {
+                ui.text("Announced on the local network");
+            }
         }
         return
     }
@@ -395,220 +761,304 @@ fn draw_ui(ui: &imgui::Ui, game_state: &mut GameState) {
         .position([0., 0.], imgui::Condition::Always)
         .size(ui.io().display_size, imgui::Condition::Always);
     if let Some(_t) = window.begin() {
-        // Moves can be made if it is the turn of the host. Or we are not
-        // running as a host.
+        // Moves can be made if it is the turn of the host, or the turn of
+        // the client when running as a client.
         let is_whites_turn = game_state.chess_state.is_white_turn;
         let can_move = match game_state.mode {
             GameMode::Host(..) => game_state.host_is_white == is_whites_turn,
+            GameMode::Client(..) => game_state.host_is_white != is_whites_turn,
+            GameMode::Disconnected(..) | GameMode::ConnectionLost(_) => false,
             _ => true
         };
+        // In local play it is whoever's turn it is who can resign.
+        let player_is_white = match game_state.mode {
+            GameMode::Disconnected(..) | GameMode::ConnectionLost(_)
+                if game_state.chess_state.is_client
+                => !game_state.host_is_white,
+            GameMode::Host(..) | GameMode::Disconnected(..)
+            | GameMode::ConnectionLost(_) => game_state.host_is_white,
+            GameMode::Client(..) => !game_state.host_is_white,
+            _ => is_whites_turn,
+        };
 
-        draw_chess(ui, &mut game_state.chess_state, can_move);
+        let clock = game_state.mode.clock().or(game_state.clock.as_ref());
+        draw_chess(ui, &mut game_state.chess_state, can_move, player_is_white,
+                   clock);
 
         if game_state.chess_state.is_game_over {
             if ui.button("Restart") {
-                *game_state = GameState::new_game();
+                game_state.restart();
+                return
+            }
+            match &mut game_state.mode {
+                GameMode::Host(_, session) => {
+                    draw_rematch(ui, &mut session.rematch,
+                                 &session.connection);
+                }
+                GameMode::Client(session) => {
+                    draw_rematch(ui, &mut session.rematch,
+                                 &session.connection);
+                }
+                _ => {}
+            }
+        }
+        if game_state.score.games() > 0 {
+            ui.same_line();
+            ui.text(format!("Match: {}", game_state.score));
+        }
+        if let GameMode::Host(_, session) = &game_state.mode {
+            if !session.spectators.is_empty() {
+                ui.same_line();
+                ui.text(format!("Spectators: {}", session.spectators.len()));
+            }
+        }
+        if let GameMode::Host(..) | GameMode::Client(..)
+            | GameMode::ConnectionLost(_) = game_state.mode {
+            ui.same_line();
+            ui.checkbox("Debug", &mut game_state.show_debug);
+            if !game_state.desync_log.is_empty() {
+                ui.same_line();
+                ui.text_colored([1.0, 0.3, 0.3, 1.0], "Desync!");
             }
         }
     }
+    draw_chat(ui, game_state);
+    draw_disconnected(ui, game_state);
+    draw_connection_lost(ui, game_state);
+    draw_errors(ui, game_state);
 }
 
-fn glow_context(window: &Window) -> glow::Context {
-    unsafe {
-        glow::Context::from_loader_function(|s|
-            window.subsystem().gl_get_proc_address(s) as _)
+fn draw_chat(ui: &imgui::Ui, game_state: &mut GameState) {
+    let chat = match &game_state.mode {
+        GameMode::Host(_, session) => &session.chat,
+        GameMode::Client(session) => &session.chat,
+        _ => return,
+    };
+    if !chat.is_open() {
+        return
     }
-}
-
-enum JsonState {
-    Normal,
-    String,
-    StringEscape,
-}
-
-/// Because serde_json exposes no way to know when serialization ended we
-/// implement this ourselves.
-struct JsonFinder {
-    pub length: usize,
-    nesting: i32,
-    state: JsonState,
-}
+    // Next to the board, which takes up a square of the display.
+    let display_size = ui.io().display_size;
+    let board_size = display_size[0].min(display_size[1]);
+    let window = ui.window("Chat")
+        .position([board_size, 80.], imgui::Condition::FirstUseEver)
+        .size([300., 400.], imgui::Condition::FirstUseEver);
+    let Some(_t) = window.begin() else {
+        return
+    };
 
-impl JsonFinder {
-    fn new() -> Self {
-        return JsonFinder {
-            length: 0,
-            nesting: 0,
-            state: JsonState::Normal,
-        }
-    }
-    fn reset(&mut self) {
-        self.length = 0;
-        self.nesting = 0;
-        self.state = JsonState::Normal;
-    }
-
-    /// Returns true once the finder has found a complete JSON object.
-    fn feed(&mut self, bytes: &[u8]) -> bool {
-        for b in bytes {
-            match self.state {
-                JsonState::Normal => match b {
-                    b'"' => self.state = JsonState::String,
-                    b'{' => self.nesting += 1,
-                    b'}' => {
-                        self.nesting -= 1;
-                        if self.nesting <= 0 {
-                            return true;
-                        }
-                    },
-                    _ => {},
-                },
-                JsonState::StringEscape => self.state = JsonState::String,
-                JsonState::String => match b {
-                    b'"'  => self.state = JsonState::Normal,
-                    b'\\' => self.state = JsonState::StringEscape,
-                    _ => {}
-                }
+    let input_height = ui.frame_height_with_spacing();
+    ui.child_window("Chat lines")
+        .size([0., -input_height])
+        .build(|| {
+            for line in &chat.lines {
+                let sender = if line.ours { "You" } else { "Opponent" };
+                ui.text_wrapped(format!("{sender}: {}", line.text));
+            }
+            // Keep up with new lines unless scrolled back.
+            if ui.scroll_y() >= ui.scroll_max_y() {
+                ui.set_scroll_here_y_with_ratio(1.0);
+            }
+        });
+    let entered = ui.input_text("##chat", &mut game_state.chat_input)
+        .enter_returns_true(true)
+        .build();
+    ui.same_line();
+    if ui.button("Send") || entered {
+        let text = std::mem::take(&mut game_state.chat_input);
+        match &mut game_state.mode {
+            GameMode::Host(_, session) => {
+                session.chat.send(&session.connection, &text);
             }
-            self.length += 1;
+            GameMode::Client(session) => {
+                session.chat.send(&session.connection, &text);
+            }
+            _ => {}
         }
-        false
     }
 }
 
-struct JsonPoller<Handshake: serde::de::DeserializeOwned,
-                  Data: serde::de::DeserializeOwned> {
-    finder: JsonFinder,
-    buf: Vec<u8>,
-    handshake_complete: bool,
-
-    // This is done such that the compiler doesn't complain about unused
-    // generics
-    _phantom1: std::marker::PhantomData<*const Handshake>,
-    _phantom2: std::marker::PhantomData<*const Data>,
-
-}
-
-#[derive(Eq, PartialEq, Debug)]
-enum Packet<Handshake, Data> {
-    Handshake(Handshake),
-    Data(Data),
-}
-
-impl<Handshake: serde::de::DeserializeOwned,
-     Data: serde::de::DeserializeOwned> JsonPoller<Handshake, Data> {
-    fn new() -> Self {
-        return JsonPoller::<Handshake, Data> {
-            finder: JsonFinder::new(),
-            buf: vec![],
-            handshake_complete: false,
-            _phantom1: PhantomData,
-            _phantom2: PhantomData,
-        }
-    }
-    fn feed(&mut self, data: &[u8], into: &mut Vec<Packet<Handshake, Data>>) {
-        let start = self.buf.len();
-        self.buf.extend_from_slice(data);
-        //println!("feed: {:#?}", std::str::from_utf8(&self.buf));
-        let mut scan_slice = start .. self.buf.len();
-        //println!("scan: {:#?}", scan_slice);
-        while self.finder.feed(&self.buf[scan_slice.clone()]) {
-            let data = &self.buf[0..self.finder.length + 1];
-            //println!("data: {:#?}", std::str::from_utf8(data));
-            if self.handshake_complete {
-                match serde_json::from_slice(data) {
-                    Ok(v) => into.push(Packet::Data(v)),
-                    Err(e) => eprintln!("data parse error: {e}"),
-                }
-            } else {
-                self.handshake_complete = true;
-                match serde_json::from_slice(data) {
-                    Ok(v) => into.push(Packet::Handshake(v)),
-                    Err(e) => eprintln!("handshake parse error: {e}"),
-                }
-            };
-            let new_length = self.buf.len() - self.finder.length - 1;
-            //println!("new_length: {new_length}");
-            let slice = self.finder.length + 1 .. self.buf.len();
-            self.buf.copy_within(slice, 0);
-            self.buf.truncate(new_length);
-            self.finder.reset();
-            scan_slice = 0..new_length;
-            //println!("feed 2: {:#?}", std::str::from_utf8(&self.buf));
+fn draw_rematch<InHandshake, InData, OutHandshake, OutData>(
+    ui: &imgui::Ui, rematch: &mut Rematch,
+    connection: &Connection<InHandshake, InData, OutHandshake, OutData>) {
+    if !rematch.peer_can_rematch {
+        return
+    }
+    ui.same_line();
+    if rematch.offered {
+        ui.text_disabled("Rematch offered");
+    } else if rematch.peer_offered {
+        if ui.button("Accept Rematch") {
+            rematch.offer(connection);
         }
+        ui.same_line();
+        if ui.button("Decline") {
+            rematch.decline(connection);
+        }
+    } else if ui.button("Rematch") {
+        rematch.offer(connection);
+    }
+    if rematch.declined {
+        ui.same_line();
+        ui.text_disabled("Declined");
     }
 }
 
-fn send_server_handshake(stream: &mut std::net::TcpStream,
-                         chess_state: &ChessState) {
-    let handshake = ServerToClientHandshake {
-        features: vec![
-            chess_network_protocol::Features::EnPassant,
-            chess_network_protocol::Features::Castling,
-            chess_network_protocol::Features::Promotion
-        ],
-        board: chess_representaiton_to_wire(&chess_state.chess_representation),
-        moves: vec![],
-        joever: chess_state.to_joever(),
+fn draw_connection_lost(ui: &imgui::Ui, game_state: &mut GameState) {
+    let GameMode::ConnectionLost(mode) = &game_state.mode else {
+        return
+    };
+    let window = ui.window("Connection lost")
+        .size([500., 0.], imgui::Condition::Always)
+        .flags(WindowFlags::NO_COLLAPSE);
+    let Some(_t) = window.begin() else {
+        return
     };
-    serde_json::to_writer(stream, &handshake).unwrap();
-}
 
-fn handle_client_move(stream: &mut std::net::TcpStream,
-                      mv: &Move,
-                      chess_state: &mut ChessState) {
-    assert!(!chess_state.is_client);
-    let good = chess_state.ingest_client_move(mv);
-    let board =
-        chess_representaiton_to_wire(&chess_state.chess_representation);
-    if !good {
-        // If the move fails, we immedietly tell the client.
-        let state = ServerToClient::Error {
-            board,
-            moves: vec![],
-            joever: chess_state.to_joever(),
-            message: "Bad move!".into()
-        };
-        serde_json::to_writer(stream, &state).unwrap();
+    let chess_state = &mut game_state.chess_state;
+    let player_is_white = if chess_state.is_client {
+        !game_state.host_is_white
+    } else {
+        game_state.host_is_white
+    };
+    let opponent = if let GameMode::Host(..) = mode.as_ref() {
+        "The opponent"
+    } else {
+        "The server"
+    };
+    ui.text_wrapped(format!("{opponent} has stopped responding."));
+    if !chess_state.is_game_over && ui.button("Claim the game") {
+        chess_state.end_game(GameEnd::Abandoned {
+            white_won: player_is_white,
+        });
+        game_state.disconnect();
+        return
+    }
+    ui.same_line();
+    if ui.button("Wait") {
+        mode.wait_longer();
     }
 }
 
-fn send_client_move(stream: &mut std::net::TcpStream,
-                    chess_state: &mut ChessState) {
-    let UnsentNetMove::Unsent(mv) = chess_state.unsent_net_move else {
+fn draw_disconnected(ui: &imgui::Ui, game_state: &mut GameState) {
+    let GameMode::Disconnected(listener, error) = &mut game_state.mode else {
+        return
+    };
+    let title = if listener.is_some() {
+        "Opponent disconnected"
+    } else {
+        "Disconnected from server"
+    };
+    let window = ui.window(title)
+        .size([500., 0.], imgui::Condition::Always)
+        .flags(WindowFlags::NO_COLLAPSE);
+    let Some(_t) = window.begin() else {
         return
     };
 
-    // If an unsent move has been created before the handshake marks the
-    // chess state as client state - something has gone terribly wrong.
-    assert!(chess_state.is_client);
+    if listener.is_some() {
+        if ui.button("Wait for opponent") {
+            let listener = listener.take().unwrap();
+            game_state.mode = GameMode::wait_for_opponent(listener);
+            return
+        }
+    } else if ui.button("Reconnect") {
+        let settings = &game_state.settings;
+        let address = game_state.server_address.as_ref()
+            .unwrap_or(&settings.join_address);
+        let recorder = Recorder::start(settings.record.as_deref(),
+                                       Role::Client);
+        match connect_client_recorded(address, game_state.host_is_white,
+                                      settings.framing, recorder) {
+            Ok(connection) => {
+                let mut session = ClientSession::new(connection);
+                session.interop.mode = game_state.settings.interop;
+                session.heartbeat = game_state.settings.heartbeat;
+                session.chat.enabled = game_state.settings.chat;
+                game_state.mode = GameMode::Client(session);
+                return
+            }
+            Err(e) => {
+                eprintln!("[client] failed to reconnect: {e}");
+                *error = Some(format!("Could not reconnect: {e}"));
+            }
+        }
+    }
+    ui.same_line();
+    if ui.button("Leave") {
+        game_state.restart();
+        return
+    }
+    if let Some(error) = error {
+        ui.text_colored([1.0, 0.3, 0.3, 1.0], error);
+    }
+}
 
-    chess_state.unsent_net_move = UnsentNetMove::None;
-    let send_move = ClientToServer::Move(mv);
-    serde_json::to_writer(stream, &send_move).unwrap();
+fn draw_errors(ui: &imgui::Ui, game_state: &mut GameState) {
+    if let Some(message) = &game_state.error_toast {
+        let window = ui.window("Server error")
+            .size([500., 0.], imgui::Condition::Always)
+            .flags(WindowFlags::NO_COLLAPSE);
+        let mut dismissed = false;
+        if let Some(_t) = window.begin() {
+            ui.text_wrapped(message);
+            dismissed = ui.button("Dismiss");
+        }
+        if dismissed {
+            game_state.error_toast = None;
+        }
+    }
+
+    if game_state.show_debug {
+        let window = ui.window("Debug")
+            .size([500., 300.], imgui::Condition::Once)
+            .opened(&mut game_state.show_debug);
+        if let Some(_t) = window.begin() {
+            ui.text(format!("Server errors: {}", game_state.error_log.len()));
+            ui.separator();
+            for message in &game_state.error_log {
+                ui.text_wrapped(message);
+            }
+            ui.separator();
+            ui.text(format!("Turn desyncs: {}", game_state.desync_log.len()));
+            ui.separator();
+            for message in &game_state.desync_log {
+                ui.text_wrapped(message);
+            }
+            ui.separator();
+            let deviations = game_state.interop()
+                .map_or(&[][..], |interop| &interop.deviations);
+            ui.text(format!("Protocol deviations: {}", deviations.len()));
+            ui.separator();
+            for deviation in deviations {
+                ui.text_wrapped(deviation.to_string());
+            }
+            for report in &game_state.interop_reports {
+                ui.separator();
+                ui.text_wrapped(report);
+            }
+        }
+    }
 }
 
-fn synchronize_board_state(stream: &mut std::net::TcpStream,
-                           chess_state: &mut ChessState) {
-    let UnsentNetMove::Unsent(move_made) = chess_state.unsent_net_move else {
-        // No need to perform any work.
-        return
-    };
-    println!("attempt synch");
-    chess_state.unsent_net_move = UnsentNetMove::None;
-
-    let board =
-        chess_representaiton_to_wire(&chess_state.chess_representation);
-    let state = ServerToClient::State {
-        board,
-        moves: vec![],
-        joever: chess_state.to_joever(),
-        move_made,
-    };
-    serde_json::to_writer(stream, &state).unwrap();
+fn glow_context(window: &Window) -> glow::Context {
+    unsafe {
+        glow::Context::from_loader_function(|s|
+            window.subsystem().gl_get_proc_address(s) as _)
+    }
 }
 
 fn main() {
+    let settings = match Settings::parse(std::env::args()) {
+        Ok(settings) => settings,
+        Err(e) => {
+            eprintln!("{e}");
+            let program = std::env::args().next().unwrap_or_default();
+            eprintln!("{}", usage(&program));
+            std::process::exit(2);
+        }
+    };
+
     let sdl = sdl2::init().unwrap();
     let video_subsystem = sdl.video().unwrap();
 
@@ -661,12 +1111,15 @@ fn main() {
 
     let mut event_pump = sdl.event_pump().unwrap();
 
-    let mut game_state = GameState::new_game();
-
-    let mut buffer = [0u8; 65535];
-
-    let mut from_server_packets = vec![];
-    let mut from_client_packets = vec![];
+    let mut game_state = GameState::new_game(settings);
+    let address = (std::net::Ipv4Addr::UNSPECIFIED, DISCOVERY_PORT);
+    game_state.discovery = match Discovery::bind(address) {
+        Ok(discovery) => Some(discovery),
+        Err(e) => {
+            eprintln!("[discovery] cannot look for games: {e}");
+            None
+        }
+    };
 
 
     'main: loop {
@@ -678,97 +1131,94 @@ fn main() {
             }
         }
 
-        match &mut game_state.mode {
-            GameMode::HostWaitForOpponent(listener) => {
-                if let Ok((stream, _)) = listener.accept() {
-                    stream.set_nonblocking(true).unwrap();
-                    game_state.mode
-                        = GameMode::Host(stream, JsonPoller::new());
+        let mut disconnected = false;
+        // Sessions keep being updated while the connection is lost, so
+        // that we notice when the peer comes back.
+        let mode = match &mut game_state.mode {
+            GameMode::ConnectionLost(mode) => mode.as_mut(),
+            mode => mode,
+        };
+        match mode {
+            GameMode::HostWaitForOpponent(listener, announcer) => {
+                if let Some(announcer) = announcer {
+                    announcer.update();
+                }
+                let record = game_state.settings.record.as_deref();
+                let framing = game_state.settings.framing;
+                let connection = listener.accept().and_then(|(stream, _)| {
+                    let recorder = Recorder::start(record, Role::Host);
+                    Connection::spawn_recorded(stream, framing, recorder)
+                });
+                if let Ok(connection) = connection {
+                    game_state.chess_state.is_host = true;
+                    let mode = std::mem::replace(&mut game_state.mode,
+                                                 GameMode::Local);
+                    let GameMode::HostWaitForOpponent(listener, _) = mode
+                    else {
+                        unreachable!();
+                    };
+                    let mut session = HostSession::new(connection,
+                                                       game_state
+                                                       .host_is_white);
+                    session.interop.mode = game_state.settings.interop;
+                    session.heartbeat = game_state.settings.heartbeat;
+                    session.chat.enabled = game_state.settings.chat;
+                    // A game that is resumed keeps the time it had left.
+                    let control = game_state.settings.clock;
+                    session.clock = game_state.clock.take()
+                        .or_else(|| control.map(Clock::new));
+                    game_state.mode = GameMode::Host(listener, session);
                 }
             }
-            GameMode::Host(stream, poller) => {
-                let buffer_read = stream.read(&mut buffer).unwrap_or_default();
-                poller.feed(&buffer[0..buffer_read], &mut from_client_packets);
-                for packet in &from_client_packets {
-                    println!("[server] packet received {packet:#?}");
-                    match packet {
-                        Packet::Handshake(h) => {
-                            let is_white = h.server_color == White;
-                            game_state.host_is_white = is_white;
-                            let state = &game_state.chess_state;
-                            send_server_handshake(stream, state);
-                        }
-                        Packet::Data(d) => {
-                            match d {
-                                ClientToServer::Move(m) => {
-                                    let white_turn = game_state
-                                        .chess_state
-                                        .is_white_turn;
-                                    if game_state.host_is_white == white_turn {
-                                        // It isn't the client's turn yet!
-                                        continue
-                                    }
-                                    let s = &mut game_state.chess_state;
-                                    handle_client_move(stream, m, s);
-                                },
-                                ClientToServer::Resign | ClientToServer::Draw
-                                    => {
-                                    println!("Such dishonorable actions,");
-                                    println!("are not supported!");
-                                },
-
-                            }
-                        }
+            GameMode::Host(listener, session) => {
+                // Everyone connecting once the game has started watches it.
+                let record = game_state.settings.record.as_deref();
+                let framing = game_state.settings.framing;
+                while let Ok((stream, _)) = listener.accept() {
+                    let recorder = Recorder::start(record, Role::Host);
+                    match Connection::spawn_recorded(stream, framing,
+                                                     recorder) {
+                        Ok(connection) => session.spectators.add(connection),
+                        Err(e) => eprintln!("[server] spectator failed: {e}"),
                     }
                 }
-                from_client_packets.clear();
-                synchronize_board_state(stream, &mut game_state.chess_state);
-            }
-            GameMode::Client(stream, poller) => {
-                let buffer_read = stream.read(&mut buffer).unwrap_or_default();
-                poller.feed(&buffer[0..buffer_read], &mut from_server_packets);
-                //println!("read: {buffer_read}");
-                for packet in &from_server_packets {
-                    println!("[client] packet received {packet:#?}");
-                    match packet {
-                        Packet::Handshake(h) => {
-                            game_state.chess_state.is_client = true;
-                            game_state.chess_state.chess_representation =
-                                wire_to_chess_representation(&h.board);
-                        }
-                        Packet::Data(d) => {
-                            match d {
-                                ServerToClient::State
-                                    { board, joever, .. }
-                                | ServerToClient::Resigned
-                                    { board, joever, .. }
-                                | ServerToClient::Error
-                                    { board, joever, .. } => {
-                                    game_state
-                                        .chess_state
-                                        .chess_representation =
-                                        wire_to_chess_representation(&board);
-                                    let over = joever != &Joever::Ongoing;
-                                    game_state.chess_state.is_game_over = over;
-                                    game_state.chess_state.is_white_turn =
-                                        !game_state.chess_state.is_white_turn;
-                                }
-                                ServerToClient::Draw { board, .. } => {
-                                    game_state
-                                        .chess_state
-                                        .chess_representation =
-                                        wire_to_chess_representation(&board);
-                                    game_state.chess_state.is_game_over = true;
-                                }
-                            };
-                        }
-                    }
+                disconnected = !session.update(&mut game_state.chess_state);
+                game_state.host_is_white = session.host_is_white;
+                if std::mem::take(&mut session.rematch.started) {
+                    game_state.start_rematch();
                 }
-                from_server_packets.clear();
-                send_client_move(stream, &mut game_state.chess_state);
             }
-            GameMode::Undecided(_) | GameMode::Local => {}
+            GameMode::Client(session) => {
+                let errors_before = game_state.error_log.len();
+                disconnected = !session.update(&mut game_state.chess_state,
+                                               &mut game_state.error_log,
+                                               &mut game_state.desync_log);
+                if game_state.error_log.len() != errors_before {
+                    game_state.error_toast = game_state.error_log.last()
+                        .cloned();
+                }
+                if std::mem::take(&mut session.rematch.started) {
+                    game_state.start_rematch();
+                }
+            }
+            GameMode::Undecided(_) => {
+                if let Some(discovery) = &mut game_state.discovery {
+                    discovery.update();
+                }
+            }
+            GameMode::Local => {
+                if let Some(clock) = &mut game_state.clock {
+                    clock.update(&mut game_state.chess_state);
+                }
+            }
+            GameMode::Disconnected(..)
+            | GameMode::ConnectionLost(_) => {}
+        }
+        if disconnected {
+            game_state.disconnect();
         }
+        game_state.check_liveness();
+        game_state.keep_score();
 
 
         platform.prepare_frame(&mut imgui, &window, &event_pump);
@@ -790,61 +1240,6 @@ fn main() {
         window.gl_swap_window();
         std::thread::sleep(std::time::Duration::from_millis(20));
     }
+    game_state.report_interop();
 }
 
-#[cfg(test)]
-mod tests {
-    use crate::{JsonFinder, JsonPoller, Packet};
-    use serde::Deserialize;
-
-    #[test]
-    pub fn json_finder() {
-        let mut finder = JsonFinder::new();
-        assert_eq!(finder.feed(b"{\"hi\": 3"), false);
-        assert_eq!(finder.feed(b"2 } excess data here"), true);
-        assert_eq!(finder.length, 10);
-
-        finder.reset();
-        assert_eq!(finder.feed(b"{\"hi\": \""), false);
-        assert_eq!(finder.feed(b"s\\\\t\\\"r\" } excess data here"), true);
-        assert_eq!(finder.length, 17);
-    }
-
-    #[derive(Deserialize, Debug, Eq, PartialEq)]
-    struct TestStruct {
-        hi: String,
-    }
-
-    #[test]
-    pub fn json_poller() {
-        let mut poller  = JsonPoller::<TestStruct, TestStruct>::new();
-        let mut into = vec![];
-        poller.feed(b"{\"hi\": \"", &mut into);
-        assert_eq!(into, vec![]);
-        poller.feed(b"s\\\\t\\\"r\" }{\"hi\": \"hey\"}", &mut into);
-        assert_eq!(into, vec![
-            Packet::Handshake(TestStruct  {
-                hi: "s\\t\"r".into(),
-            }),
-            Packet::Data(TestStruct {
-                hi: "hey".into(),
-            })
-        ]);
-        into.clear();
-        poller.feed(b" ", &mut into);
-        assert_eq!(into, vec![]);
-    }
-
-    #[test]
-    pub fn json_poller_empty() {
-        let mut poller  = JsonPoller::<TestStruct, TestStruct>::new();
-        let mut into = vec![];
-        poller.feed(b"{\"hi\": \"there\"}", &mut into);
-        assert_eq!(into, vec![Packet::Handshake(TestStruct  {
-                hi: "there".into(),
-        })]);
-        into.clear();
-        poller.feed(b"", &mut into);
-        assert_eq!(into, vec![]);
-    }
-}
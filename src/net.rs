@@ -0,0 +1,994 @@
+//! The connection to the peer and the messages sent over it.
+
+use std::io::Read;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use chess_network_protocol::{ClientToServerHandshake, ClientToServer,
+                             ServerToClient, ServerToClientHandshake, Move,
+                             Joever, Features};
+use chess_network_protocol::Color::White;
+
+use crate::chat::{Chat, CHAT_FEATURE};
+use crate::clock::{Clock, ClockState, CLOCK_FEATURE};
+use crate::codec::{Framing, Packet};
+use crate::extension::{extended_poller, Extension, Incoming};
+use crate::interop::{Deviation, InteropLog, InteropMode};
+use crate::record::{Direction, Event, Recorder};
+use crate::rematch::{Rematch, RematchRequest, REMATCH_FEATURE};
+use crate::game::{ChessState, GameEnd, UnsentNetAction, UnsentNetMove};
+use crate::settings::DEFAULT_PORT;
+use crate::spectators::Spectators;
+use crate::wire::{chess_representaiton_to_wire, wire_move_to_indices,
+                  wire_to_chess_representation};
+
+/// Binds a listener to the given address. IPv6 addresses may be written both
+/// with and without brackets, e.g. `[::]` and `::`.
+pub fn bind_host(address: &str, port: u16)
+    -> std::io::Result<std::net::TcpListener> {
+    let address = address.trim();
+    let host = address.strip_prefix('[')
+        .and_then(|a| a.strip_suffix(']'))
+        .unwrap_or(address);
+    let listener = std::net::TcpListener::bind((host, port))?;
+    listener.set_nonblocking(true)?;
+    Ok(listener)
+}
+
+/// Connects to a server and sends the handshake. The address may leave out
+/// the port, in which case the default one is used.
+pub fn connect_client(address: &str, host_is_white: bool)
+    -> std::io::Result<ClientConnection> {
+    connect_client_recorded(address, host_is_white, Framing::Finder, None)
+}
+
+/// Adds the default port to addresses that leave it out.
+pub fn address_with_port(address: &str) -> String {
+    if address.contains(":") {
+        address.to_string()
+    } else {
+        format!("{address}:{DEFAULT_PORT}")
+    }
+}
+
+/// Like `connect_client`, but tells messages apart with `framing` and writes
+/// the traffic to `recorder` if given.
+pub fn connect_client_recorded(address: &str, host_is_white: bool,
+                               framing: Framing, recorder: Option<Recorder>)
+    -> std::io::Result<ClientConnection> {
+    let address = address_with_port(address);
+    println!("[client] attempting to connect to: {address}");
+    let stream = std::net::TcpStream::connect(address)?;
+    start_client(stream, host_is_white, framing, recorder)
+}
+
+/// Spawns the client's end of a connection and sends the handshake.
+pub fn start_client(stream: std::net::TcpStream, host_is_white: bool,
+                    framing: Framing, recorder: Option<Recorder>)
+    -> std::io::Result<ClientConnection> {
+    let connection = Connection::spawn_recorded(stream, framing, recorder)?;
+
+    let server_color = if host_is_white {
+        White
+    } else {
+        chess_network_protocol::Color::Black
+    };
+    let handshake = ClientToServerHandshake { server_color };
+    connection.send(Packet::Handshake(handshake));
+    Ok(connection)
+}
+
+/// The feature hosts advertise in their handshake if they send heartbeats
+/// to clients that send them heartbeats in turn.
+pub const HEARTBEAT_FEATURE: &str = "Heartbeat";
+
+/// How many heartbeat intervals may pass without hearing from a peer that
+/// sends heartbeats before it is considered lost.
+pub const HEARTBEAT_TIMEOUT_INTERVALS: u32 = 3;
+
+/// What the network thread knows about whether the peer is still there.
+struct Liveness {
+    last_heard: Instant,
+    heartbeats: usize,
+    /// How often to send heartbeats, once they have been started.
+    interval: Option<Duration>,
+}
+
+/// What the network thread is asked to send. Our own messages go along
+/// with the packets, as they have to be sent in the same order.
+enum Outgoing<Handshake, Data> {
+    Packet(Packet<Handshake, Data>),
+    Extension(Extension),
+}
+
+/// A connection whose socket is owned by a thread of its own. Packets are
+/// exchanged with it through channels, so that a slow peer never stalls the
+/// render loop.
+pub struct Connection<InHandshake, InData, OutHandshake, OutData> {
+    incoming: std::sync::mpsc::Receiver<Incoming<InHandshake, InData>>,
+    outgoing: std::sync::mpsc::Sender<Outgoing<OutHandshake, OutData>>,
+    deviations: std::sync::mpsc::Receiver<Deviation>,
+    liveness: Arc<Mutex<Liveness>>,
+}
+
+pub type HostConnection = Connection<ClientToServerHandshake, ClientToServer,
+                                 ServerToClientHandshake, ServerToClient>;
+pub type ClientConnection = Connection<ServerToClientHandshake, ServerToClient,
+                                   ClientToServerHandshake, ClientToServer>;
+
+/// How long the network thread waits for data before checking whether there
+/// is anything to send.
+const NETWORK_POLL_INTERVAL: std::time::Duration
+    = std::time::Duration::from_millis(10);
+
+impl<InHandshake, InData, OutHandshake, OutData>
+    Connection<InHandshake, InData, OutHandshake, OutData>
+where InHandshake: serde::de::DeserializeOwned + serde::Serialize
+                   + Send + 'static,
+      InData: serde::de::DeserializeOwned + serde::Serialize + Send + 'static,
+      OutHandshake: serde::Serialize + Send + 'static,
+      OutData: serde::Serialize + Send + 'static {
+    pub fn spawn(stream: std::net::TcpStream) -> std::io::Result<Self> {
+        Self::spawn_recorded(stream, Framing::Finder, None)
+    }
+
+    /// Like `spawn`, but the network thread tells the messages received
+    /// apart with `framing`, and writes everything sent and received to
+    /// `recorder` if given.
+    pub fn spawn_recorded(stream: std::net::TcpStream, framing: Framing,
+                          recorder: Option<Recorder>)
+        -> std::io::Result<Self> {
+        // Streams accepted from a non-blocking listener may be non-blocking
+        // themselves.
+        stream.set_nonblocking(false)?;
+        stream.set_read_timeout(Some(NETWORK_POLL_INTERVAL))?;
+        stream.set_nodelay(true)?;
+
+        let (incoming_sender, incoming) = std::sync::mpsc::channel();
+        let (outgoing, outgoing_receiver) = std::sync::mpsc::channel();
+        let (deviation_sender, deviations) = std::sync::mpsc::channel();
+        let liveness = Arc::new(Mutex::new(Liveness {
+            last_heard: Instant::now(),
+            heartbeats: 0,
+            interval: None,
+        }));
+        let thread_liveness = liveness.clone();
+        std::thread::Builder::new()
+            .name("network".into())
+            .spawn(move || {
+                Self::run(stream, outgoing_receiver, incoming_sender,
+                          deviation_sender, thread_liveness, framing,
+                          recorder)
+            })?;
+        Ok(Connection {
+            incoming,
+            outgoing,
+            deviations,
+            liveness,
+        })
+    }
+
+    /// Queues a packet to be sent. Packets sent after the connection is lost
+    /// are dropped, which `poll` will tell us about.
+    pub fn send(&self, packet: Packet<OutHandshake, OutData>) {
+        let _ = self.outgoing.send(Outgoing::Packet(packet));
+    }
+
+    /// Moves all packets received so far into `into`, throwing away our own
+    /// messages. Returns false once the connection has been lost.
+    pub fn poll(&self, into: &mut Vec<Packet<InHandshake, InData>>) -> bool {
+        let mut incoming = vec![];
+        let connected = self.poll_incoming(&mut incoming);
+        into.extend(incoming.into_iter().filter_map(|incoming| {
+            match incoming {
+                Incoming::Packet(packet) => Some(packet),
+                Incoming::Extension(_) => None,
+            }
+        }));
+        connected
+    }
+
+    /// Moves all packets and messages of our own received so far into
+    /// `into`, in the order they were sent. Heartbeats are left out, as the
+    /// network thread keeps track of them. Returns false once the
+    /// connection has been lost.
+    pub fn poll_incoming(&self, into: &mut Vec<Incoming<InHandshake, InData>>)
+        -> bool {
+        loop {
+            match self.incoming.try_recv() {
+                Ok(incoming) => into.push(incoming),
+                Err(std::sync::mpsc::TryRecvError::Empty) => return true,
+                Err(std::sync::mpsc::TryRecvError::Disconnected) => {
+                    return false
+                }
+            }
+        }
+    }
+
+    /// Moves the deviations from the protocol seen so far into `into`.
+    pub fn poll_deviations(&self, into: &mut Vec<Deviation>) {
+        into.extend(self.deviations.try_iter());
+    }
+
+    /// Starts sending a heartbeat whenever nothing else has been sent for
+    /// `interval`. Only peers that have opted in to heartbeats may be sent
+    /// them.
+    pub fn start_heartbeat(&self, interval: Duration) {
+        self.liveness.lock().unwrap().interval = Some(interval);
+    }
+
+    /// Whether the peer has sent us heartbeats, and so promises to never be
+    /// silent for long.
+    pub fn peer_sends_heartbeats(&self) -> bool {
+        self.liveness.lock().unwrap().heartbeats > 0
+    }
+
+    /// How long it has been since anything was heard from the peer.
+    pub fn silence(&self) -> Duration {
+        self.liveness.lock().unwrap().last_heard.elapsed()
+    }
+
+    /// Whether the peer sends heartbeats, yet has been silent for longer
+    /// than `timeout`. Peers that do not send heartbeats may take as long
+    /// as they like to think about their move.
+    pub fn timed_out(&self, timeout: Duration) -> bool {
+        let liveness = self.liveness.lock().unwrap();
+        liveness.heartbeats > 0 && liveness.last_heard.elapsed() > timeout
+    }
+
+    /// Gives a silent peer another timeout before it is considered lost.
+    pub fn wait_longer(&self) {
+        self.liveness.lock().unwrap().last_heard = Instant::now();
+    }
+
+    fn run(mut stream: std::net::TcpStream,
+           outgoing: std::sync::mpsc::Receiver<Outgoing<OutHandshake,
+                                                         OutData>>,
+           incoming: std::sync::mpsc::Sender<Incoming<InHandshake, InData>>,
+           deviations: std::sync::mpsc::Sender<Deviation>,
+           liveness: Arc<Mutex<Liveness>>,
+           framing: Framing,
+           mut recorder: Option<Recorder>) {
+        use std::io::{ErrorKind, Write};
+        use std::sync::mpsc::TryRecvError;
+
+        let mut poller = extended_poller::<InHandshake, InData>(framing);
+        let heartbeat = serde_json::to_vec(&Extension::Heartbeat)
+            .expect("heartbeats always serialize");
+        let mut buffer = vec![0u8; 65535];
+        let mut packets = vec![];
+        let mut last_sent = Instant::now();
+        'worker: loop {
+            let interval = liveness.lock().unwrap().interval;
+            if interval.is_some_and(|i| last_sent.elapsed() >= i) {
+                if let Some(recorder) = &mut recorder {
+                    recorder.bytes(Direction::Sent, &heartbeat);
+                }
+                if let Err(e) = stream.write_all(&heartbeat) {
+                    eprintln!("write error: {e}");
+                    break 'worker
+                }
+                last_sent = Instant::now();
+            }
+            loop {
+                let bytes = match outgoing.try_recv() {
+                    Ok(Outgoing::Packet(packet)) => {
+                        let bytes = match &packet {
+                            Packet::Handshake(h) => serde_json::to_vec(h),
+                            Packet::Data(d) => serde_json::to_vec(d),
+                        };
+                        let bytes =
+                            bytes.expect("protocol messages always serialize");
+                        if let Some(recorder) = &mut recorder {
+                            recorder.bytes(Direction::Sent, &bytes);
+                            recorder.packet(Direction::Sent, &packet);
+                        }
+                        bytes
+                    }
+                    Ok(Outgoing::Extension(extension)) => {
+                        let bytes = serde_json::to_vec(&extension)
+                            .expect("our messages always serialize");
+                        if let Some(recorder) = &mut recorder {
+                            recorder.bytes(Direction::Sent, &bytes);
+                            if let Extension::Chat(text) = extension {
+                                let direction = Direction::Sent;
+                                recorder.record(Event::Chat { direction,
+                                                              text });
+                            }
+                        }
+                        bytes
+                    }
+                    Err(TryRecvError::Empty) => break,
+                    // The other end of the connection was dropped.
+                    Err(TryRecvError::Disconnected) => break 'worker,
+                };
+                if let Err(e) = stream.write_all(&bytes) {
+                    eprintln!("write error: {e}");
+                    break 'worker
+                }
+                last_sent = Instant::now();
+            }
+
+            match stream.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(length) => {
+                    poller.feed(&buffer[0..length], &mut packets);
+                    liveness.lock().unwrap().last_heard = Instant::now();
+                    if let Some(recorder) = &mut recorder {
+                        recorder.bytes(Direction::Received,
+                                       &buffer[0..length]);
+                        for deviation in &poller.deviations {
+                            let event = Event::Deviation(deviation.to_string());
+                            recorder.record(event);
+                        }
+                    }
+                    for deviation in poller.deviations.drain(..) {
+                        let _ = deviations.send(deviation);
+                    }
+                    for packet in packets.drain(..) {
+                        let packet = Incoming::split(packet);
+                        if let Some(recorder) = &mut recorder {
+                            match &packet {
+                                Incoming::Packet(packet) => recorder
+                                    .packet(Direction::Received, packet),
+                                Incoming::Extension(Extension::Chat(text)) => {
+                                    recorder.record(Event::Chat {
+                                        direction: Direction::Received,
+                                        text: text.clone(),
+                                    });
+                                }
+                                Incoming::Extension(_) => {}
+                            }
+                        }
+                        if matches!(packet,
+                                    Incoming::Extension(Extension::Heartbeat)) {
+                            liveness.lock().unwrap().heartbeats += 1;
+                            continue
+                        }
+                        if incoming.send(packet).is_err() {
+                            break 'worker
+                        }
+                    }
+                }
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock
+                                            | ErrorKind::TimedOut
+                                            | ErrorKind::Interrupted) => {}
+                Err(e) => {
+                    eprintln!("read error: {e}");
+                    break
+                }
+            }
+        }
+        let _ = stream.shutdown(std::net::Shutdown::Both);
+    }
+}
+
+impl<InHandshake, InData, OutHandshake, OutData>
+    Connection<InHandshake, InData, OutHandshake, OutData> {
+    /// Queues a message of our own to be sent. Only peers that have agreed
+    /// to it may be sent any, see `Chat`, `Clock` and `Rematch`.
+    pub fn send_extension(&self, extension: Extension) {
+        let _ = self.outgoing.send(Outgoing::Extension(extension));
+    }
+}
+
+/// Sends the handshake, advertising the given features of our own on top of
+/// those of the protocol.
+pub fn send_server_handshake(connection: &HostConnection,
+                             chess_state: &ChessState, extra: &[&str]) {
+    let mut features = vec![
+        chess_network_protocol::Features::EnPassant,
+        chess_network_protocol::Features::Castling,
+        chess_network_protocol::Features::Promotion,
+        chess_network_protocol::Features::PossibleMoveGeneration,
+    ];
+    features.extend(extra.iter().map(|name| Features::Other(name.to_string())));
+    let handshake = ServerToClientHandshake {
+        features,
+        board: chess_representaiton_to_wire(&chess_state.chess_representation),
+        moves: chess_state.legal_moves.clone(),
+        joever: chess_state.to_joever(),
+    };
+    connection.send(Packet::Handshake(handshake));
+}
+
+/// Plays a move sent by a client. Only the host can judge moves, so this
+/// fails on the state of a client.
+pub fn handle_client_move(connection: &HostConnection,
+                          mv: &Move,
+                          chess_state: &mut ChessState)
+    -> Result<(), String> {
+    if chess_state.is_client {
+        return Err("client moves can only be judged by the host".into())
+    }
+    let good = chess_state.ingest_client_move(mv);
+    if !good {
+        // If the move fails, we immedietly tell the client.
+        let state = error_message(chess_state, "Bad move!");
+        connection.send(Packet::Data(state));
+    }
+    Ok(())
+}
+
+/// Builds an error telling the client what the game looks like right now.
+pub fn error_message(chess_state: &ChessState, message: &str)
+    -> ServerToClient {
+    ServerToClient::Error {
+        board: chess_representaiton_to_wire(&chess_state.chess_representation),
+        moves: chess_state.legal_moves.clone(),
+        joever: chess_state.to_joever(),
+        message: message.into(),
+    }
+}
+
+pub fn send_client_move(connection: &ClientConnection,
+                        chess_state: &mut ChessState) {
+    let UnsentNetMove::Unsent(mv) = chess_state.unsent_net_move else {
+        return
+    };
+
+    // If an unsent move has been created before the handshake marks the
+    // chess state as client state - something has gone terribly wrong.
+    assert!(chess_state.is_client);
+
+    chess_state.unsent_net_move = UnsentNetMove::None;
+    let send_move = ClientToServer::Move(mv);
+    connection.send(Packet::Data(send_move));
+}
+
+pub fn send_client_action(connection: &ClientConnection,
+                          chess_state: &mut ChessState) {
+    let message = match chess_state.unsent_net_action {
+        UnsentNetAction::Resign => ClientToServer::Resign,
+        UnsentNetAction::OfferDraw => ClientToServer::Draw,
+        // Only the host keeps time, so flags falling are not ours to tell.
+        UnsentNetAction::AcceptDraw | UnsentNetAction::OutOfTime
+        | UnsentNetAction::None => return,
+    };
+    chess_state.unsent_net_action = UnsentNetAction::None;
+    connection.send(Packet::Data(message));
+}
+
+pub fn log_desync(desync_log: &mut Vec<String>, result: Result<(), String>) {
+    if let Err(message) = result {
+        eprintln!("[client] turn desync: {message}");
+        desync_log.push(message);
+    }
+}
+
+/// The first move in a packet from the server with squares off the board.
+fn off_board_move(packet: &Packet<ServerToClientHandshake, ServerToClient>)
+    -> Option<&Move> {
+    let (moves, move_made) = match packet {
+        Packet::Handshake(h) => (&h.moves, None),
+        Packet::Data(ServerToClient::State { moves, move_made, .. }) => {
+            (moves, Some(move_made))
+        }
+        Packet::Data(ServerToClient::Error { moves, .. }) => (moves, None),
+        Packet::Data(_) => return None,
+    };
+    moves.iter().chain(move_made)
+        .find(|mv| wire_move_to_indices(mv).is_none())
+}
+
+/// Updates the client's view of the game with a packet from the server.
+/// Returns the message of the error if the server sent one.
+pub fn ingest_server_packet(
+    chess_state: &mut ChessState,
+    packet: &Packet<ServerToClientHandshake, ServerToClient>,
+    desync_log: &mut Vec<String>) -> Option<String> {
+    let s = chess_state;
+    match packet {
+        Packet::Handshake(h) => {
+            s.is_client = true;
+            s.chess_representation = wire_to_chess_representation(&h.board);
+            s.ingest_legal_moves(&h.moves);
+            let advertised = h.features.contains(
+                &chess_network_protocol::Features::PossibleMoveGeneration);
+            s.has_move_list = advertised || !h.moves.is_empty();
+            let result = s.sync_turn(None, s.is_white_turn);
+            log_desync(desync_log, result);
+        }
+        Packet::Data(ServerToClient::Resigned { board, joever }) => {
+            s.chess_representation = wire_to_chess_representation(board);
+            let white_won = joever == &Joever::White;
+            s.end_game(GameEnd::Resignation { white_won });
+        }
+        Packet::Data(ServerToClient::State
+                     { board, moves, joever, move_made }) => {
+            s.ingest_server_state(board, moves, joever);
+            s.draw_offered = false;
+            let expected = !s.is_white_turn;
+            let result = s.sync_turn(Some(move_made), expected);
+            log_desync(desync_log, result);
+        }
+        Packet::Data(ServerToClient::Error
+                     { board, moves, joever, message }) => {
+            // Our last move was rejected, so the board is rolled back and it
+            // is still our turn.
+            eprintln!("[client] server error: {message}");
+            s.ingest_server_state(board, moves, joever);
+            let result = s.sync_turn(None, s.is_white_turn);
+            log_desync(desync_log, result);
+            return Some(message.clone())
+        }
+        Packet::Data(ServerToClient::Draw { board, .. }) => {
+            s.chess_representation = wire_to_chess_representation(board);
+            s.end_game(GameEnd::DrawAgreed);
+        }
+    }
+    None
+}
+
+pub fn send_server_action(connection: &HostConnection,
+                          chess_state: &mut ChessState) {
+    if let Some(message) = take_server_action(chess_state) {
+        connection.send(Packet::Data(message));
+    }
+}
+
+/// Takes the unsent action, if any, and builds the message announcing it.
+pub fn take_server_action(chess_state: &mut ChessState)
+    -> Option<ServerToClient> {
+    let board =
+        chess_representaiton_to_wire(&chess_state.chess_representation);
+    let message = match chess_state.unsent_net_action {
+        UnsentNetAction::Resign => ServerToClient::Resigned {
+            board,
+            joever: chess_state.to_joever(),
+        },
+        UnsentNetAction::AcceptDraw => ServerToClient::Draw {
+            board,
+            moves: vec![],
+        },
+        // The protocol has no message for running out of time, so the game
+        // ends like a resignation or an agreed draw would.
+        UnsentNetAction::OutOfTime => match chess_state.to_joever() {
+            Joever::Draw => ServerToClient::Draw { board, moves: vec![] },
+            joever => ServerToClient::Resigned { board, joever },
+        },
+        UnsentNetAction::OfferDraw | UnsentNetAction::None => return None,
+    };
+    chess_state.unsent_net_action = UnsentNetAction::None;
+    Some(message)
+}
+
+pub fn synchronize_board_state(connection: &HostConnection,
+                               chess_state: &mut ChessState) {
+    if let Some(state) = take_board_state(chess_state) {
+        connection.send(Packet::Data(state));
+    }
+}
+
+/// Takes the unsent move, if any, and builds the state message announcing
+/// it.
+pub fn take_board_state(chess_state: &mut ChessState)
+    -> Option<ServerToClient> {
+    let UnsentNetMove::Unsent(move_made) = chess_state.unsent_net_move else {
+        // No need to perform any work.
+        return None
+    };
+    println!("attempt synch");
+    chess_state.unsent_net_move = UnsentNetMove::None;
+
+    let board =
+        chess_representaiton_to_wire(&chess_state.chess_representation);
+    let state = ServerToClient::State {
+        board,
+        moves: chess_state.legal_moves.clone(),
+        joever: chess_state.to_joever(),
+        move_made,
+    };
+    Some(state)
+}
+
+/// The host's end of a game against a client.
+pub struct HostSession {
+    pub connection: HostConnection,
+    /// The colour we play, as asked for by the client in its handshake.
+    pub host_is_white: bool,
+    pub handshake_received: bool,
+    pub interop: InteropLog,
+    /// How often to send heartbeats to clients that send them too, if at
+    /// all.
+    pub heartbeat: Option<Duration>,
+    /// Everyone watching the game, who are sent every move and action sent
+    /// to the client.
+    pub spectators: Spectators,
+    /// Set `chat.enabled` to offer chat to the client.
+    pub chat: Chat,
+    /// The clock of a timed game, which we keep for both sides.
+    pub clock: Option<Clock>,
+    /// Whether the client has said that it keeps time.
+    client_keeps_time: bool,
+    /// The state of the clock last sent to the client.
+    sent_clock: Option<ClockState>,
+    /// Call `rematch.offer` once the game is over to play again.
+    pub rematch: Rematch,
+    incoming: Vec<Incoming<ClientToServerHandshake, ClientToServer>>,
+}
+
+impl HostSession {
+    pub fn new(connection: HostConnection, host_is_white: bool) -> Self {
+        HostSession {
+            connection,
+            host_is_white,
+            handshake_received: false,
+            interop: InteropLog::new(InteropMode::Lenient),
+            heartbeat: None,
+            spectators: Spectators::new(),
+            chat: Chat::new(),
+            clock: None,
+            client_keeps_time: false,
+            sent_clock: None,
+            rematch: Rematch::new(),
+            incoming: vec![],
+        }
+    }
+
+    /// Whether the client, having opted in to heartbeats, has gone silent.
+    pub fn timed_out(&self) -> bool {
+        self.heartbeat.is_some_and(|interval| {
+            self.connection.timed_out(interval * HEARTBEAT_TIMEOUT_INTERVALS)
+        })
+    }
+
+    /// The features we advertise on top of those of the protocol.
+    fn features(&self) -> Vec<&'static str> {
+        let mut features = vec![];
+        if self.heartbeat.is_some() {
+            features.push(HEARTBEAT_FEATURE);
+        }
+        if self.chat.enabled {
+            features.push(CHAT_FEATURE);
+        }
+        if self.clock.is_some() {
+            features.push(CLOCK_FEATURE);
+        }
+        features.push(REMATCH_FEATURE);
+        features
+    }
+
+    /// Starts the game over from the initial position, with the colours
+    /// swapped and the clock wound back.
+    fn start_rematch(&mut self, chess_state: &mut ChessState) {
+        self.host_is_white = !self.host_is_white;
+        println!("[server] rematch, playing as {}",
+                 if self.host_is_white { "white" } else { "black" });
+        *chess_state = ChessState::new();
+        chess_state.is_host = true;
+        if let Some(clock) = &mut self.clock {
+            *clock = Clock::new(clock.control);
+        }
+        self.sent_clock = None;
+    }
+
+    /// Takes in a message of our own from the client, and starts the new
+    /// game once a rematch has been agreed on.
+    fn receive_extension(&mut self, extension: Extension,
+                         chess_state: &mut ChessState) {
+        match extension {
+            Extension::Chat(text) => self.chat.receive(text),
+            // Any state the client sends along is ignored, the clock we keep
+            // is the one that counts.
+            Extension::Clock(_) => self.client_keeps_time = true,
+            Extension::Rematch(request) => {
+                self.rematch.receive(request, chess_state.is_game_over);
+                if self.rematch.take_agreed() {
+                    self.start_rematch(chess_state);
+                }
+            }
+            // Counted by the network thread.
+            Extension::Heartbeat => {}
+        }
+    }
+
+    /// Runs the clock once the client has joined, and sends its state to
+    /// clients that keep time whenever another side's clock starts or
+    /// stops.
+    fn update_clock(&mut self, chess_state: &mut ChessState) {
+        let Some(clock) = &mut self.clock else {
+            return
+        };
+        if self.handshake_received {
+            clock.update(chess_state);
+        }
+        let state = clock.state();
+        let running = self.sent_clock.map(|sent| sent.running_white);
+        if self.client_keeps_time && running != Some(state.running_white) {
+            self.connection.send_extension(Extension::Clock(Some(state)));
+            self.sent_clock = Some(state);
+        }
+    }
+
+    /// Handles everything the client sent and sends it our moves and
+    /// actions. Returns false once the connection has been lost, or when
+    /// the client strayed from the protocol in strict mode.
+    pub fn update(&mut self, chess_state: &mut ChessState) -> bool {
+        let mut connected = self.connection.poll_incoming(&mut self.incoming);
+        let mut deviations = vec![];
+        self.connection.poll_deviations(&mut deviations);
+        let features = self.features();
+        // A rematch we accepted since the last update starts before anything
+        // the client sent is handled.
+        if self.rematch.take_agreed() {
+            self.start_rematch(chess_state);
+        }
+        let mut incoming = std::mem::take(&mut self.incoming);
+        for incoming in incoming.drain(..) {
+            // Our messages are handled where they were sent between the
+            // packets, as requests for rematches before an acceptance
+            // belong to the game before and those after it to the new one.
+            let packet = match incoming {
+                Incoming::Packet(packet) => packet,
+                Incoming::Extension(extension) => {
+                    self.receive_extension(extension, chess_state);
+                    continue
+                }
+            };
+            println!("[server] packet received {packet:#?}");
+            let data = match packet {
+                Packet::Handshake(h) => {
+                    self.handshake_received = true;
+                    let is_white = h.server_color == White;
+                    send_server_handshake(&self.connection, chess_state,
+                                          &features);
+                    if is_white != self.host_is_white
+                        && chess_state.has_started() {
+                        // A client coming back to a game has to take the
+                        // side it left.
+                        let message = "The colours cannot change during \
+                                       a game";
+                        let error = error_message(chess_state, message);
+                        self.connection.send(Packet::Data(error));
+                        continue
+                    }
+                    println!("[server] playing as {}",
+                             if is_white { "white" } else { "black" });
+                    self.host_is_white = is_white;
+                    continue
+                }
+                Packet::Data(data) => data,
+            };
+            if !self.handshake_received {
+                // The client still has to be told what the board looks
+                // like.
+                self.handshake_received = true;
+                send_server_handshake(&self.connection, chess_state,
+                                      &features);
+            }
+            if chess_state.is_game_over {
+                let message = format!("{data:?} after the game ended");
+                deviations.push(Deviation::UnexpectedMessage(message));
+            }
+            match data {
+                ClientToServer::Move(m) => {
+                    if self.host_is_white == chess_state.is_white_turn
+                        && !chess_state.is_game_over {
+                        // It isn't the client's turn yet!
+                        let message = format!("{m:?} out of turn");
+                        deviations.push(Deviation::UnexpectedMessage(message));
+                        let error = error_message(chess_state,
+                                                  "It is not your turn");
+                        self.connection.send(Packet::Data(error));
+                        continue
+                    }
+                    let result =
+                        handle_client_move(&self.connection, &m, chess_state);
+                    if let Err(message) = result {
+                        eprintln!("[server] {message}");
+                    }
+                }
+                ClientToServer::Resign => {
+                    chess_state.resign(!self.host_is_white);
+                }
+                ClientToServer::Draw => {
+                    if !chess_state.is_game_over {
+                        chess_state.draw_offered = true;
+                    }
+                }
+            }
+        }
+        self.incoming = incoming;
+        for deviation in deviations {
+            connected &= self.interop.record(deviation);
+        }
+        self.update_clock(chess_state);
+        // Clients opt in to heartbeats by sending them.
+        if let Some(interval) = self.heartbeat {
+            if self.connection.peer_sends_heartbeats() {
+                self.connection.start_heartbeat(interval);
+            }
+        }
+        let messages = [take_board_state(chess_state),
+                        take_server_action(chess_state)];
+        for message in messages.into_iter().flatten() {
+            self.spectators.broadcast(&message);
+            self.connection.send(Packet::Data(message));
+        }
+        // Spectators that join now are sent the board as it is after the
+        // messages above.
+        self.spectators.update(chess_state);
+        connected
+    }
+}
+
+/// The client's end of a game against a server.
+pub struct ClientSession {
+    pub connection: ClientConnection,
+    /// The number of packets received from the server so far.
+    pub received: usize,
+    pub handshake_received: bool,
+    pub interop: InteropLog,
+    /// How often to send heartbeats to servers that advertise them, if at
+    /// all.
+    pub heartbeat: Option<Duration>,
+    /// Set `chat.enabled` to chat with servers that advertise it.
+    pub chat: Chat,
+    /// The clock kept by the server, once it has sent one.
+    pub clock: Option<Clock>,
+    /// Call `rematch.offer` once the game is over to play again.
+    pub rematch: Rematch,
+    incoming: Vec<Incoming<ServerToClientHandshake, ServerToClient>>,
+}
+
+impl ClientSession {
+    pub fn new(connection: ClientConnection) -> Self {
+        ClientSession {
+            connection,
+            received: 0,
+            handshake_received: false,
+            interop: InteropLog::new(InteropMode::Lenient),
+            heartbeat: None,
+            chat: Chat::new(),
+            clock: None,
+            rematch: Rematch::new(),
+            incoming: vec![],
+        }
+    }
+
+    /// Whether the server, having opted in to heartbeats, has gone silent.
+    pub fn timed_out(&self) -> bool {
+        self.heartbeat.is_some_and(|interval| {
+            self.connection.timed_out(interval * HEARTBEAT_TIMEOUT_INTERVALS)
+        })
+    }
+
+    /// Starts the game over from the initial position.
+    fn start_rematch(&mut self, chess_state: &mut ChessState) {
+        println!("[client] rematch");
+        let has_move_list = chess_state.has_move_list;
+        *chess_state = ChessState::new();
+        chess_state.is_client = true;
+        chess_state.has_move_list = has_move_list;
+        self.clock = None;
+    }
+
+    /// Takes in a message of our own from the server, and starts the new
+    /// game once a rematch has been agreed on.
+    fn receive_extension(&mut self, extension: Extension,
+                         chess_state: &mut ChessState) {
+        match extension {
+            Extension::Chat(text) => self.chat.receive(text),
+            Extension::Clock(Some(state)) => {
+                self.clock = Some(Clock::from_state(&state));
+            }
+            Extension::Rematch(request) => {
+                self.rematch.receive(request, chess_state.is_game_over);
+                if self.rematch.take_agreed() {
+                    self.start_rematch(chess_state);
+                }
+            }
+            // Heartbeats are counted by the network thread.
+            Extension::Clock(None) | Extension::Heartbeat => {}
+        }
+    }
+
+    /// Handles everything the server sent and sends it our moves and
+    /// actions. The messages of errors sent by the server are added to
+    /// `errors` and turn desyncs to `desync_log`. Returns false once the
+    /// connection has been lost, or when the server strayed from the
+    /// protocol in strict mode.
+    pub fn update(&mut self, chess_state: &mut ChessState,
+                  errors: &mut Vec<String>, desync_log: &mut Vec<String>)
+        -> bool {
+        let mut connected = self.connection.poll_incoming(&mut self.incoming);
+        let mut deviations = vec![];
+        self.connection.poll_deviations(&mut deviations);
+        // A rematch we accepted since the last update starts before anything
+        // the server sent is handled.
+        if self.rematch.take_agreed() {
+            self.start_rematch(chess_state);
+        }
+        let mut incoming = std::mem::take(&mut self.incoming);
+        for incoming in incoming.drain(..) {
+            // Our messages are handled where they were sent between the
+            // packets, see `HostSession::update`.
+            let packet = match incoming {
+                Incoming::Packet(packet) => packet,
+                Incoming::Extension(extension) => {
+                    self.receive_extension(extension, chess_state);
+                    continue
+                }
+            };
+            println!("[client] packet received {packet:#?}");
+            self.received += 1;
+            match &packet {
+                Packet::Handshake(h) => {
+                    self.handshake_received = true;
+                    for feature in &h.features {
+                        let Features::Other(name) = feature else {
+                            continue
+                        };
+                        match name.as_str() {
+                            HEARTBEAT_FEATURE => {
+                                if let Some(interval) = self.heartbeat {
+                                    self.connection
+                                        .start_heartbeat(interval);
+                                }
+                            }
+                            CHAT_FEATURE => {
+                                if self.chat.enabled {
+                                    // A line without text says that we
+                                    // can chat too.
+                                    self.chat.peer_can_chat = true;
+                                    self.connection.send_extension(
+                                        Extension::Chat(String::new()));
+                                }
+                            }
+                            CLOCK_FEATURE => {
+                                // A clock without a state says that we
+                                // keep time.
+                                self.connection.send_extension(
+                                    Extension::Clock(None));
+                            }
+                            REMATCH_FEATURE => {
+                                self.rematch.peer_can_rematch = true;
+                                self.connection.send_extension(
+                                    Extension::Rematch(
+                                        RematchRequest::Supported));
+                            }
+                            _ => {
+                                let name = name.clone();
+                                deviations.push(
+                                    Deviation::UnknownFeature(name));
+                            }
+                        }
+                    }
+                }
+                Packet::Data(data) if chess_state.is_game_over => {
+                    let kind = match data {
+                        ServerToClient::State { .. } => "state",
+                        ServerToClient::Error { .. } => "error",
+                        ServerToClient::Resigned { .. } => "resignation",
+                        ServerToClient::Draw { .. } => "draw",
+                    };
+                    let message = format!("{kind} after the game ended");
+                    deviations.push(Deviation::UnexpectedMessage(message));
+                }
+                Packet::Data(_) => {}
+            }
+            if let Some(mv) = off_board_move(&packet) {
+                let mv = format!("{mv:?}");
+                deviations.push(Deviation::OffBoardMove(mv));
+            }
+            if let Some(message) = ingest_server_packet(chess_state, &packet,
+                                                        desync_log) {
+                errors.push(message);
+            }
+        }
+        self.incoming = incoming;
+        for deviation in deviations {
+            connected &= self.interop.record(deviation);
+        }
+        if let Some(clock) = &mut self.clock {
+            if chess_state.is_game_over {
+                clock.stop();
+                clock.explain_end(chess_state);
+            }
+        }
+        send_client_move(&self.connection, chess_state);
+        send_client_action(&self.connection, chess_state);
+        connected
+    }
+}
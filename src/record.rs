@@ -0,0 +1,439 @@
+//! Recording everything sent and received over a connection to a session
+//! file, and replaying such files to reproduce what a client, or the host,
+//! saw.
+//!
+//! A session file holds one JSON encoded `Entry` per line, starting with
+//! `Event::Start`.
+
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+
+use chess_network_protocol::{ClientToServer, ClientToServerHandshake, Move,
+                             Piece, ServerToClient, ServerToClientHandshake};
+use serde::{Deserialize, Serialize};
+
+use crate::codec::{Framing, Packet};
+use crate::extension::{extended_poller, Extension, Incoming};
+use crate::game::ChessState;
+use crate::interop::Deviation;
+use crate::net::ingest_server_packet;
+use crate::rematch::RematchRequest;
+use crate::wire::chess_representaiton_to_wire;
+
+/// Which end of the connection was recorded.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum Role {
+    Host,
+    Client,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum Event {
+    Start { role: Role, unix_time_ms: u64 },
+    /// Raw bytes, written as hex as they need not be valid UTF-8.
+    Bytes { direction: Direction, hex: String },
+    Handshake { direction: Direction, message: serde_json::Value },
+    Data { direction: Direction, message: serde_json::Value },
+    Deviation(String),
+    /// A line of chat, see `crate::chat`. Lines without text only say that
+    /// the peer can chat.
+    Chat { direction: Direction, text: String },
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Entry {
+    /// Milliseconds since the start of the session.
+    pub time_ms: u64,
+    pub event: Event,
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+pub fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    hex.as_bytes().chunks(2).map(|pair| {
+        let pair = std::str::from_utf8(pair).ok().filter(|p| p.len() == 2)?;
+        u8::from_str_radix(pair, 16).ok()
+    }).collect()
+}
+
+/// Writes the traffic of one connection to a session file.
+pub struct Recorder {
+    pub path: PathBuf,
+    file: std::fs::File,
+    start: std::time::Instant,
+}
+
+impl Recorder {
+    /// Creates a session file in the given directory, named after the role
+    /// and the time.
+    pub fn create(directory: &Path, role: Role) -> std::io::Result<Self> {
+        let unix_time_ms = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |time| time.as_millis() as u64);
+        let name = match role {
+            Role::Host => "host",
+            Role::Client => "client",
+        };
+        // Sessions started within the same millisecond, such as those of
+        // both players on a server, get a number to tell them apart.
+        let mut number = 0;
+        let (path, file) = loop {
+            let file_name = match number {
+                0 => format!("gchess-{name}-{unix_time_ms}.jsonl"),
+                n => format!("gchess-{name}-{unix_time_ms}-{n}.jsonl"),
+            };
+            let path = directory.join(file_name);
+            let file = std::fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&path);
+            match file {
+                Ok(file) => break (path, file),
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists
+                    && number < 100 => number += 1,
+                Err(e) => return Err(e),
+            }
+        };
+        let mut recorder = Recorder {
+            path,
+            file,
+            start: std::time::Instant::now(),
+        };
+        recorder.record(Event::Start { role, unix_time_ms });
+        Ok(recorder)
+    }
+
+    /// Creates a session file if a directory to record to is given. Failing
+    /// to do so only means that the game is not recorded, so errors are
+    /// printed rather than returned.
+    pub fn start(directory: Option<&str>, role: Role) -> Option<Self> {
+        let directory = directory?;
+        match Recorder::create(Path::new(directory), role) {
+            Ok(recorder) => {
+                println!("[record] recording to {}", recorder.path.display());
+                Some(recorder)
+            }
+            Err(e) => {
+                eprintln!("[record] could not record to {directory}: {e}");
+                None
+            }
+        }
+    }
+
+    /// Writes an entry. Failing to do so is not worth ending the game over,
+    /// so errors are only printed.
+    pub fn record(&mut self, event: Event) {
+        let entry = Entry {
+            time_ms: self.start.elapsed().as_millis() as u64,
+            event,
+        };
+        let mut line = serde_json::to_vec(&entry)
+            .expect("entries always serialize");
+        line.push(b'\n');
+        if let Err(e) = self.file.write_all(&line) {
+            eprintln!("[record] failed to write {}: {e}",
+                      self.path.display());
+        }
+    }
+
+    pub fn bytes(&mut self, direction: Direction, bytes: &[u8]) {
+        self.record(Event::Bytes { direction, hex: to_hex(bytes) });
+    }
+
+    pub fn packet<Handshake: Serialize, Data: Serialize>(
+        &mut self, direction: Direction, packet: &Packet<Handshake, Data>) {
+        let event = match packet {
+            Packet::Handshake(h) => Event::Handshake {
+                direction,
+                message: serde_json::to_value(h).unwrap_or_default(),
+            },
+            Packet::Data(d) => Event::Data {
+                direction,
+                message: serde_json::to_value(d).unwrap_or_default(),
+            },
+        };
+        self.record(event);
+    }
+}
+
+pub fn read_session(path: &Path) -> std::io::Result<Vec<Entry>> {
+    let file = std::io::BufReader::new(std::fs::File::open(path)?);
+    let mut entries = vec![];
+    for (number, line) in file.lines().enumerate() {
+        let line = line?;
+        let entry = serde_json::from_str(&line).map_err(|e| {
+            let message = format!("line {}: {e}", number + 1);
+            std::io::Error::new(std::io::ErrorKind::InvalidData, message)
+        })?;
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+/// A message decoded again while replaying a session.
+pub enum Replayed {
+    FromServer(Packet<ServerToClientHandshake, ServerToClient>),
+    FromClient(Packet<ClientToServerHandshake, ClientToServer>),
+}
+
+/// What was decoded from either side, in the order it was sent.
+enum Decoded {
+    Message(Replayed),
+    Extension(Extension),
+}
+
+/// A line of chat found while replaying a session.
+pub struct ReplayedChat {
+    pub time_ms: u64,
+    pub from_server: bool,
+    pub text: String,
+}
+
+/// The outcome of replaying a session.
+pub struct Replay {
+    /// The game as the client saw it.
+    pub chess_state: ChessState,
+    /// The game as the host saw it, if it was the host that was recorded.
+    pub host_state: Option<ChessState>,
+    pub errors: Vec<String>,
+    pub desync_log: Vec<String>,
+    pub deviations: Vec<Deviation>,
+    /// Messages that decode differently now than when they were recorded.
+    pub mismatches: Vec<String>,
+    pub chat: Vec<ReplayedChat>,
+}
+
+/// Plays a move of the host on its own game. Those only show in the states
+/// it sent, which also echo the moves of the client, so only moves that
+/// the game does not have yet are played. Returns a description of the
+/// problem if the game does not end up as the one that was sent.
+fn replay_host_move(host_state: &mut ChessState, board: &[[Piece; 8]; 8],
+                    move_made: &Move) -> Result<(), String> {
+    let sent = |host_state: &ChessState| {
+        chess_representaiton_to_wire(&host_state.chess_representation)
+            == *board
+    };
+    if !sent(host_state) {
+        host_state.ingest_client_move(move_made);
+    }
+    if sent(host_state) {
+        Ok(())
+    } else {
+        Err(format!("the host's board after {move_made:?} is not the one \
+                     it sent"))
+    }
+}
+
+/// Takes in a request for a rematch the way the sessions do. `offers` says
+/// whether the server and the client have asked for one, and the games are
+/// started over once both have.
+fn replay_rematch(replay: &mut Replay, offers: &mut [bool; 2],
+                  from_server: bool, request: RematchRequest) {
+    let (ours, theirs) = if from_server { (0, 1) } else { (1, 0) };
+    if !replay.chess_state.is_game_over {
+        return
+    }
+    match request {
+        RematchRequest::Offer => offers[ours] = true,
+        RematchRequest::Accept if offers[theirs] => offers[ours] = true,
+        RematchRequest::Decline => offers[theirs] = false,
+        RematchRequest::Supported | RematchRequest::Accept => {}
+    }
+    if *offers != [true, true] {
+        return
+    }
+    *offers = [false, false];
+    let has_move_list = replay.chess_state.has_move_list;
+    replay.chess_state = ChessState::new();
+    replay.chess_state.is_client = true;
+    replay.chess_state.has_move_list = has_move_list;
+    if let Some(host_state) = &mut replay.host_state {
+        *host_state = ChessState::new();
+        host_state.is_host = true;
+    }
+}
+
+/// Feeds the bytes of a recorded session through `JsonPoller` again, and the
+/// messages sent by the server through the client's ingestion. When the
+/// host was recorded the moves of both sides are also played on a game of
+/// the host's, with those of the client going through the host's
+/// ingestion. Rematches start the games over where they were agreed on.
+/// `on_message` is called with every message along with the state of the
+/// client's game after it.
+pub fn replay(entries: &[Entry],
+              mut on_message: impl FnMut(u64, &Replayed, &ChessState))
+    -> Replay {
+    let mut replay = Replay {
+        chess_state: ChessState::new(),
+        host_state: None,
+        errors: vec![],
+        desync_log: vec![],
+        deviations: vec![],
+        mismatches: vec![],
+        chat: vec![],
+    };
+    replay.chess_state.is_client = true;
+
+    let role = entries.iter().find_map(|entry| match entry.event {
+        Event::Start { role, .. } => Some(role),
+        _ => None,
+    }).unwrap_or(Role::Client);
+    let from_server = match role {
+        Role::Host => Direction::Sent,
+        Role::Client => Direction::Received,
+    };
+    if role == Role::Host {
+        let mut host_state = ChessState::new();
+        host_state.is_host = true;
+        replay.host_state = Some(host_state);
+    }
+
+    let mut server_poller = extended_poller::<ServerToClientHandshake,
+                                              ServerToClient>(Framing::Finder);
+    let mut client_poller = extended_poller::<ClientToServerHandshake,
+                                              ClientToServer>(Framing::Finder);
+    // The messages as they were decoded when recorded, to compare against.
+    let mut recorded_sent = std::collections::VecDeque::new();
+    let mut recorded_received = std::collections::VecDeque::new();
+    for entry in entries {
+        match &entry.event {
+            Event::Handshake { direction, message }
+            | Event::Data { direction, message } => match direction {
+                Direction::Sent => recorded_sent.push_back(message),
+                Direction::Received => recorded_received.push_back(message),
+            },
+            _ => {}
+        }
+    }
+    let mut server_packets = vec![];
+    let mut client_packets = vec![];
+    let mut rematch_offers = [false, false];
+    for entry in entries {
+        let (direction, bytes) = match &entry.event {
+            Event::Bytes { direction, hex } => match from_hex(hex) {
+                Some(bytes) => (*direction, bytes),
+                None => {
+                    replay.mismatches.push(format!("bad hex at {} ms",
+                                                   entry.time_ms));
+                    continue
+                }
+            },
+            _ => continue,
+        };
+
+        let mut decoded = vec![];
+        if direction == from_server {
+            server_poller.feed(&bytes, &mut server_packets);
+            replay.deviations.append(&mut server_poller.deviations);
+            decoded.extend(server_packets.drain(..).map(|packet| {
+                match Incoming::split(packet) {
+                    Incoming::Packet(packet) => {
+                        Decoded::Message(Replayed::FromServer(packet))
+                    }
+                    Incoming::Extension(e) => Decoded::Extension(e),
+                }
+            }));
+        } else {
+            client_poller.feed(&bytes, &mut client_packets);
+            replay.deviations.append(&mut client_poller.deviations);
+            decoded.extend(client_packets.drain(..).map(|packet| {
+                match Incoming::split(packet) {
+                    Incoming::Packet(packet) => {
+                        Decoded::Message(Replayed::FromClient(packet))
+                    }
+                    Incoming::Extension(e) => Decoded::Extension(e),
+                }
+            }));
+        }
+
+        for decoded in decoded {
+            let message = match decoded {
+                Decoded::Message(message) => message,
+                Decoded::Extension(Extension::Chat(text)) => {
+                    if !text.is_empty() {
+                        replay.chat.push(ReplayedChat {
+                            time_ms: entry.time_ms,
+                            from_server: direction == from_server,
+                            text,
+                        });
+                    }
+                    continue
+                }
+                // Requests for rematches are replayed where they were sent
+                // between the packets, see `HostSession::update`.
+                Decoded::Extension(Extension::Rematch(request)) => {
+                    replay_rematch(&mut replay, &mut rematch_offers,
+                                   direction == from_server, request);
+                    continue
+                }
+                Decoded::Extension(_) => continue,
+            };
+            let value = match &message {
+                Replayed::FromServer(packet) => {
+                    let s = &mut replay.chess_state;
+                    let log = &mut replay.desync_log;
+                    if let Some(e) = ingest_server_packet(s, packet, log) {
+                        replay.errors.push(e);
+                    }
+                    if let (Some(host_state), Packet::Data(
+                        ServerToClient::State { board, move_made, .. }))
+                        = (&mut replay.host_state, packet) {
+                        let result =
+                            replay_host_move(host_state, board, move_made);
+                        if let Err(e) = result {
+                            replay.mismatches.push(
+                                format!("{e} at {} ms", entry.time_ms));
+                        }
+                    }
+                    match packet {
+                        Packet::Handshake(h) => serde_json::to_value(h),
+                        Packet::Data(d) => serde_json::to_value(d),
+                    }
+                }
+                Replayed::FromClient(Packet::Handshake(h)) => {
+                    serde_json::to_value(h)
+                }
+                Replayed::FromClient(Packet::Data(d)) => {
+                    if let (Some(host_state), ClientToServer::Move(mv))
+                        = (&mut replay.host_state, d) {
+                        host_state.ingest_client_move(mv);
+                    }
+                    serde_json::to_value(d)
+                }
+            }.unwrap_or_default();
+            let recorded = match direction {
+                Direction::Sent => recorded_sent.pop_front(),
+                Direction::Received => recorded_received.pop_front(),
+            };
+            if recorded != Some(&value) {
+                replay.mismatches.push(format!(
+                    "{value} at {} ms was recorded as {}", entry.time_ms,
+                    recorded.map_or("nothing".into(), |m| m.to_string())));
+            }
+            on_message(entry.time_ms, &message, &replay.chess_state);
+        }
+    }
+    replay
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::record::*;
+
+    #[test]
+    pub fn hex() {
+        let bytes = b"{\"Move\": 1}\n\xff\x00";
+        assert_eq!(to_hex(b"{\n\xff"), "7b0aff");
+        assert_eq!(from_hex(&to_hex(bytes)).unwrap(), bytes);
+        assert_eq!(from_hex("7"), None);
+        assert_eq!(from_hex("zz"), None);
+    }
+}
@@ -0,0 +1,191 @@
+//! Relaying a game between two clients without playing a side, as done by
+//! the headless server for every game it runs. Each client asks for the
+//! colour of the server in its handshake, which the referee takes to mean
+//! the colour of the other client.
+
+use chess_network_protocol::{ClientToServer, ClientToServerHandshake,
+                             ServerToClient};
+use chess_network_protocol::Color::White;
+
+use crate::codec::Packet;
+use crate::game::ChessState;
+use crate::interop::{InteropLog, InteropMode};
+use crate::net::{error_message, handle_client_move, send_server_handshake,
+                 take_board_state, take_server_action, HostConnection};
+
+/// One of the two clients playing a game.
+pub struct Player {
+    pub connection: HostConnection,
+    /// Not known until the handshake has been received.
+    pub is_white: Option<bool>,
+    /// Set once the connection is lost or the player has been kicked.
+    pub gone: bool,
+    pub interop: InteropLog,
+}
+
+pub fn color_name(is_white: bool) -> &'static str {
+    if is_white { "white" } else { "black" }
+}
+
+/// A game between up to two clients.
+pub struct Game {
+    pub chess_state: ChessState,
+    pub players: Vec<Player>,
+    interop: InteropMode,
+}
+
+impl Game {
+    pub fn new(interop: InteropMode) -> Self {
+        let mut chess_state = ChessState::new();
+        chess_state.is_host = true;
+        Game { chess_state, players: vec![], interop }
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.players.len() >= 2
+    }
+
+    /// The colour nobody has asked for yet, if one player has.
+    pub fn free_color(&self) -> Option<bool> {
+        match self.players.as_slice() {
+            [player] => player.is_white.map(|is_white| !is_white),
+            _ => None,
+        }
+    }
+
+    /// Adds a player, whose colour is decided by its handshake. Returns its
+    /// index.
+    pub fn add_player(&mut self, connection: HostConnection) -> usize {
+        self.players.push(Player {
+            connection,
+            is_white: None,
+            gone: false,
+            interop: InteropLog::new(self.interop),
+        });
+        self.players.len() - 1
+    }
+
+    pub fn handle_handshake(&mut self, index: usize,
+                            handshake: &ClientToServerHandshake) {
+        // The client names the colour of its opponent, which is us.
+        let is_white = handshake.server_color != White;
+        let taken = self.players.iter()
+            .any(|player| !player.gone && player.is_white == Some(is_white));
+
+        let chess_state = &self.chess_state;
+        let player = &mut self.players[index];
+        // The protocol demands that the handshake is answered before
+        // anything else, even if the player is about to be kicked.
+        send_server_handshake(&player.connection, chess_state, &[]);
+        if taken {
+            println!("[server] {} is already taken", color_name(is_white));
+            let message = format!("{} is already taken",
+                                  color_name(is_white));
+            let error = error_message(chess_state, &message);
+            player.connection.send(Packet::Data(error));
+            player.gone = true;
+            return
+        }
+        println!("[server] player {index} plays {}", color_name(is_white));
+        player.is_white = Some(is_white);
+    }
+
+    fn handle_data(&mut self, index: usize, data: &ClientToServer) {
+        let seated = self.players.iter()
+            .filter(|player| !player.gone && player.is_white.is_some())
+            .count();
+        let chess_state = &mut self.chess_state;
+        let player = &self.players[index];
+        let Some(is_white) = player.is_white else {
+            // Data before the handshake is not something we can make sense
+            // of.
+            return
+        };
+
+        let error = match data {
+            ClientToServer::Move(_) if seated < 2 => {
+                Some("Waiting for an opponent")
+            }
+            ClientToServer::Move(_)
+                if is_white != chess_state.is_white_turn => {
+                Some("It is not your turn")
+            }
+            ClientToServer::Move(mv) => {
+                match handle_client_move(&player.connection, mv, chess_state) {
+                    Ok(()) => None,
+                    Err(message) => {
+                        eprintln!("[server] {message}");
+                        Some("The move could not be played")
+                    }
+                }
+            }
+            ClientToServer::Resign => {
+                chess_state.resign(is_white);
+                None
+            }
+            // There is no message for passing an offer on to the other
+            // client.
+            ClientToServer::Draw => Some("Draw offers are not supported"),
+        };
+        if let Some(message) = error {
+            let error = error_message(chess_state, message);
+            player.connection.send(Packet::Data(error));
+        }
+    }
+
+    fn broadcast(&self, message: ServerToClient) {
+        let seated = self.players.iter()
+            .filter(|player| player.is_white.is_some());
+        for player in seated {
+            player.connection.send(Packet::Data(message.clone()));
+        }
+    }
+
+    /// Handles everything the players sent, forgets those that left and
+    /// tells the rest about every move and action.
+    pub fn update(&mut self) {
+        for index in 0..self.players.len() {
+            let mut packets = vec![];
+            let alive = self.players[index].connection.poll(&mut packets);
+            for packet in packets {
+                println!("[server] packet received from {index} \
+                          {packet:#?}");
+                match &packet {
+                    Packet::Handshake(h) => self.handle_handshake(index, h),
+                    Packet::Data(d) => self.handle_data(index, d),
+                }
+            }
+
+            let player = &mut self.players[index];
+            let mut deviations = vec![];
+            player.connection.poll_deviations(&mut deviations);
+            for deviation in deviations {
+                player.gone |= !player.interop.record(deviation);
+            }
+            if !alive {
+                println!("[server] player {index} disconnected");
+                player.gone = true;
+            }
+            if player.gone {
+                println!("[interop] player {index}: {}",
+                         player.interop.summary());
+            }
+        }
+        // The game is kept as is so that a new client can take the place of
+        // one that left.
+        self.players.retain(|player| !player.gone);
+
+        if let Some(state) = take_board_state(&mut self.chess_state) {
+            self.broadcast(state);
+        }
+        if let Some(message) = take_server_action(&mut self.chess_state) {
+            self.broadcast(message);
+        }
+
+        if self.players.is_empty() && self.chess_state.is_game_over {
+            println!("[server] everyone left, starting a new game");
+            self.chess_state = ChessState::new();
+            self.chess_state.is_host = true;
+        }
+    }
+}
@@ -0,0 +1,183 @@
+//! Playing another game against the same opponent once a game is over, with
+//! the colours swapped. The protocol has no way to start a new game, so both
+//! peers have to know about rematches: hosts advertise `REMATCH_FEATURE` in
+//! their handshake and clients that know it answer with `Supported`. Once
+//! both have asked for a rematch each of them starts the new game from the
+//! initial position on its own.
+
+use chess_network_protocol::Joever;
+use serde::{Deserialize, Serialize};
+
+use crate::extension::Extension;
+use crate::net::Connection;
+
+/// The feature hosts advertise in their handshake.
+pub const REMATCH_FEATURE: &str = "Rematch";
+
+/// Sent as `{"Rematch": "Offer"}` and so on.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum RematchRequest {
+    /// Sent by clients that know about rematches, in answer to the feature.
+    Supported,
+    Offer,
+    Accept,
+    Decline,
+}
+
+/// The results of the games played against the same opponent.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct MatchScore {
+    pub wins: u32,
+    pub losses: u32,
+    pub draws: u32,
+}
+
+/// Writes half points as a number with ½ for the half.
+fn half_points(halves: u32) -> String {
+    match (halves / 2, halves % 2) {
+        (0, 1) => "½".into(),
+        (whole, 1) => format!("{whole}½"),
+        (whole, _) => whole.to_string(),
+    }
+}
+
+impl MatchScore {
+    /// Counts a game that ended as `joever`.
+    pub fn add(&mut self, joever: &Joever, we_are_white: bool) {
+        match joever {
+            Joever::White if we_are_white => self.wins += 1,
+            Joever::Black if !we_are_white => self.wins += 1,
+            Joever::White | Joever::Black => self.losses += 1,
+            Joever::Draw => self.draws += 1,
+            Joever::Ongoing | Joever::Indeterminate => {}
+        }
+    }
+
+    pub fn games(&self) -> u32 {
+        self.wins + self.losses + self.draws
+    }
+}
+
+impl std::fmt::Display for MatchScore {
+    /// Shows the score in points, a win being worth one and a draw a half,
+    /// ours first.
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let ours = half_points(self.wins * 2 + self.draws);
+        let theirs = half_points(self.losses * 2 + self.draws);
+        write!(f, "{ours} - {theirs}")
+    }
+}
+
+/// Where the two peers are in agreeing on a rematch.
+pub struct Rematch {
+    /// Whether the peer has said that it knows about rematches.
+    pub peer_can_rematch: bool,
+    /// Whether we asked for a rematch that the peer has not answered.
+    pub offered: bool,
+    /// Whether the peer asked for a rematch that we have not answered.
+    pub peer_offered: bool,
+    /// Whether the peer turned down our last offer.
+    pub declined: bool,
+    /// Set once a rematch has started, for the caller to take.
+    pub started: bool,
+    /// Set once both have asked for a rematch, until the session starts it.
+    agreed: bool,
+}
+
+impl Rematch {
+    pub fn new() -> Self {
+        Rematch {
+            peer_can_rematch: false,
+            offered: false,
+            peer_offered: false,
+            declined: false,
+            started: false,
+            agreed: false,
+        }
+    }
+
+    /// Takes in a request the peer sent. It has to be taken in where it was
+    /// sent between the packets, so that it is never taken for one about
+    /// the game before or after it. Offers made before the game is over are
+    /// ignored.
+    pub fn receive(&mut self, request: RematchRequest, is_game_over: bool) {
+        println!("[rematch] received {request:?}");
+        match request {
+            RematchRequest::Supported => self.peer_can_rematch = true,
+            _ if !is_game_over => {}
+            // Both asked at once, which is as good as accepting.
+            RematchRequest::Offer | RematchRequest::Accept
+                if self.offered => self.agreed = true,
+            RematchRequest::Offer => {
+                self.peer_can_rematch = true;
+                self.peer_offered = true;
+            }
+            RematchRequest::Accept => {}
+            RematchRequest::Decline => {
+                self.offered = false;
+                self.declined = true;
+            }
+        }
+    }
+
+    /// Asks for a rematch, or accepts the one the peer asked for.
+    pub fn offer<InHandshake, InData, OutHandshake, OutData>(
+        &mut self,
+        connection: &Connection<InHandshake, InData, OutHandshake, OutData>) {
+        if !self.peer_can_rematch || self.offered {
+            return
+        }
+        if self.peer_offered {
+            let request = RematchRequest::Accept;
+            connection.send_extension(Extension::Rematch(request));
+            self.agreed = true;
+        } else {
+            let request = RematchRequest::Offer;
+            connection.send_extension(Extension::Rematch(request));
+            self.offered = true;
+            self.declined = false;
+        }
+    }
+
+    pub fn decline<InHandshake, InData, OutHandshake, OutData>(
+        &mut self,
+        connection: &Connection<InHandshake, InData, OutHandshake, OutData>) {
+        if self.peer_offered {
+            let request = RematchRequest::Decline;
+            connection.send_extension(Extension::Rematch(request));
+            self.peer_offered = false;
+        }
+    }
+
+    /// Whether a rematch has been agreed on and should be started now.
+    pub fn take_agreed(&mut self) -> bool {
+        if !self.agreed {
+            return false
+        }
+        self.agreed = false;
+        self.offered = false;
+        self.peer_offered = false;
+        self.declined = false;
+        self.started = true;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::rematch::*;
+
+    #[test]
+    pub fn match_score() {
+        let mut score = MatchScore::default();
+        assert_eq!(score.to_string(), "0 - 0");
+        score.add(&Joever::White, true);
+        score.add(&Joever::Draw, false);
+        score.add(&Joever::White, false);
+        score.add(&Joever::Black, false);
+        score.add(&Joever::Ongoing, true);
+        assert_eq!(score, MatchScore { wins: 2, losses: 1, draws: 1 });
+        assert_eq!(score.games(), 4);
+        assert_eq!(score.to_string(), "2½ - 1½");
+    }
+}
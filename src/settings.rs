@@ -0,0 +1,295 @@
+//! Settings given on the command line.
+
+use std::time::Duration;
+
+use crate::clock::TimeControl;
+use crate::codec::Framing;
+use crate::interop::InteropMode;
+
+/// The port used when none is given, both for hosting and joining.
+pub const DEFAULT_PORT: u16 = 8483;
+
+/// How often heartbeats are sent when none is given.
+pub const DEFAULT_HEARTBEAT: Duration = Duration::from_secs(5);
+
+/// The options that only matter to those who play, which the server turns
+/// down.
+const PLAYER_OPTIONS: [&str; 5] =
+    ["--join", "--play", "--heartbeat", "--no-chat", "--clock"];
+
+/// The name of the program without the directory it was run from.
+fn program_name(program: &str) -> String {
+    std::path::Path::new(program)
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or(program.into())
+}
+
+pub fn usage(program: &str) -> String {
+    let program = program_name(program);
+    format!("\
+usage: {program} [options]
+
+options:
+    --bind <address>    address to host games on (default: 127.0.0.1)
+    --port <port>       port to host games on (default: 8483)
+    --join <address>    address of the game to join (default: localhost)
+    --play <colour>     white, black or random (default: random)
+    --strict            hang up on peers that stray from the protocol
+    --record <dir>      write network traffic to session files in <dir>
+    --framing <kind>    finder or streaming, how messages received are told
+                        apart (default: finder)
+    --heartbeat <secs>  send heartbeats to peers that do so too, 0 for never
+                        (default: 5)
+    --lobby             run many games at once (server only)
+    --no-chat           never chat with the opponent
+    --clock <control>   time the games we host or play locally: minutes,
+                        plus seconds of increment as in 5+3 or of delay as
+                        in 5d3 (default: untimed)
+    --help              print this message")
+}
+
+pub fn server_usage(program: &str) -> String {
+    let program = program_name(program);
+    format!("\
+usage: {program} [options]
+
+options:
+    --bind <address>    address to host games on (default: 127.0.0.1)
+    --port <port>       port to host games on (default: 8483)
+    --strict            hang up on clients that stray from the protocol
+    --record <dir>      write network traffic to session files in <dir>
+    --framing <kind>    finder or streaming, how messages received are told
+                        apart (default: finder)
+    --lobby             run many games at once
+    --help              print this message")
+}
+
+/// The colour the joining player wants to play as.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ColorChoice {
+    White,
+    Black,
+    Random,
+}
+
+impl ColorChoice {
+    /// Resolves the choice into a colour, flipping a coin for `Random`.
+    pub fn is_white(self) -> bool {
+        use std::hash::{BuildHasher, Hasher};
+        match self {
+            ColorChoice::White => true,
+            ColorChoice::Black => false,
+            ColorChoice::Random => {
+                // RandomState is seeded randomly for every process, which is
+                // plenty for a coin flip.
+                let state = std::collections::hash_map::RandomState::new();
+                state.build_hasher().finish() & 1 == 0
+            }
+        }
+    }
+}
+
+/// Settings that can be given on the command line and later be edited in the
+/// "Select Mode" window.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Settings {
+    pub bind_address: String,
+    pub port: u16,
+    pub join_address: String,
+    pub color: ColorChoice,
+    pub interop: InteropMode,
+    /// The directory to write session files to, if traffic is recorded.
+    pub record: Option<String>,
+    /// How the messages received over connections are told apart.
+    pub framing: Framing,
+    /// How often to send heartbeats to peers that opt in to them, if at all.
+    pub heartbeat: Option<Duration>,
+    /// Whether the server runs a lobby of many games rather than one.
+    pub lobby: bool,
+    /// Whether to chat with peers that can.
+    pub chat: bool,
+    /// How the games we host or play locally are timed, if at all.
+    pub clock: Option<TimeControl>,
+}
+
+impl Settings {
+    /// Parses the arguments of the program, including its name as the first
+    /// one.
+    pub fn parse(args: impl Iterator<Item = String>)
+        -> Result<Settings, String> {
+        Self::parse_options(args, false)
+    }
+
+    /// Like `parse`, but for the server, which turns down the options that
+    /// only matter to those who play.
+    pub fn parse_server(args: impl Iterator<Item = String>)
+        -> Result<Settings, String> {
+        Self::parse_options(args, true)
+    }
+
+    fn parse_options(mut args: impl Iterator<Item = String>, server: bool)
+        -> Result<Settings, String> {
+        let program = args.next().unwrap_or_default();
+        let mut settings = Settings {
+            bind_address: String::from("127.0.0.1"),
+            port: DEFAULT_PORT,
+            join_address: String::from("localhost"),
+            color: ColorChoice::Random,
+            interop: InteropMode::Lenient,
+            record: None,
+            framing: Framing::Finder,
+            heartbeat: Some(DEFAULT_HEARTBEAT),
+            lobby: false,
+            chat: true,
+            clock: None,
+        };
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                option if server && PLAYER_OPTIONS.contains(&option) => {
+                    return Err(format!("{option} is not used by the server"))
+                }
+                "--bind" => {
+                    settings.bind_address = args.next()
+                        .ok_or("--bind expects an address")?;
+                }
+                "--port" => {
+                    let port = args.next().ok_or("--port expects a port")?;
+                    settings.port = port.parse()
+                        .map_err(|_| format!("bad port: {port}"))?;
+                }
+                "--join" => {
+                    settings.join_address = args.next()
+                        .ok_or("--join expects an address")?;
+                }
+                "--play" => {
+                    let color = args.next().ok_or("--play expects a colour")?;
+                    settings.color = match color.as_str() {
+                        "white" => ColorChoice::White,
+                        "black" => ColorChoice::Black,
+                        "random" => ColorChoice::Random,
+                        _ => return Err(format!("bad colour: {color}")),
+                    };
+                }
+                "--strict" => settings.interop = InteropMode::Strict,
+                "--record" => {
+                    let directory = args.next()
+                        .ok_or("--record expects a directory")?;
+                    settings.record = Some(directory);
+                }
+                "--framing" => {
+                    let framing = args.next()
+                        .ok_or("--framing expects finder or streaming")?;
+                    settings.framing = match framing.as_str() {
+                        "finder" => Framing::Finder,
+                        "streaming" => Framing::Streaming,
+                        _ => return Err(format!("bad framing: {framing}")),
+                    };
+                }
+                "--heartbeat" => {
+                    let seconds = args.next()
+                        .ok_or("--heartbeat expects a number of seconds")?;
+                    let seconds: u64 = seconds.parse()
+                        .map_err(|_| format!("bad heartbeat: {seconds}"))?;
+                    settings.heartbeat = match seconds {
+                        0 => None,
+                        s => Some(Duration::from_secs(s)),
+                    };
+                }
+                "--lobby" => settings.lobby = true,
+                "--no-chat" => settings.chat = false,
+                "--clock" => {
+                    let control = args.next()
+                        .ok_or("--clock expects a time control")?;
+                    settings.clock = Some(TimeControl::parse(&control)?);
+                }
+                "--help" | "-h" => {
+                    if server {
+                        println!("{}", server_usage(&program));
+                    } else {
+                        println!("{}", usage(&program));
+                    }
+                    std::process::exit(0);
+                }
+                _ => return Err(format!("unknown argument: {arg}")),
+            }
+        }
+        Ok(settings)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::settings::*;
+
+    fn parse(args: &[&str]) -> Result<Settings, String> {
+        let args = std::iter::once("gchess").chain(args.iter().copied());
+        Settings::parse(args.map(|s| s.to_string()))
+    }
+
+    #[test]
+    pub fn settings_parse() {
+        let settings = parse(&[]).unwrap();
+        assert_eq!(settings.bind_address, "127.0.0.1");
+        assert_eq!(settings.port, DEFAULT_PORT);
+
+        let settings = parse(&["--bind", "[::]", "--port", "9000"]).unwrap();
+        assert_eq!(settings.bind_address, "[::]");
+        assert_eq!(settings.port, 9000);
+
+        let settings = parse(&["--join", "10.0.0.2:9000", "--play", "black"])
+            .unwrap();
+        assert_eq!(settings.join_address, "10.0.0.2:9000");
+        assert_eq!(settings.color, ColorChoice::Black);
+
+        assert_eq!(parse(&[]).unwrap().interop, InteropMode::Lenient);
+        assert_eq!(parse(&["--strict"]).unwrap().interop, InteropMode::Strict);
+
+        assert_eq!(parse(&[]).unwrap().record, None);
+        let settings = parse(&["--record", "sessions"]).unwrap();
+        assert_eq!(settings.record.as_deref(), Some("sessions"));
+
+        assert_eq!(parse(&[]).unwrap().framing, Framing::Finder);
+        let settings = parse(&["--framing", "streaming"]).unwrap();
+        assert_eq!(settings.framing, Framing::Streaming);
+        assert!(parse(&["--framing", "lines"]).is_err());
+
+        assert_eq!(parse(&[]).unwrap().heartbeat, Some(DEFAULT_HEARTBEAT));
+        let settings = parse(&["--heartbeat", "2"]).unwrap();
+        assert_eq!(settings.heartbeat, Some(Duration::from_secs(2)));
+        assert_eq!(parse(&["--heartbeat", "0"]).unwrap().heartbeat, None);
+
+        assert!(!parse(&[]).unwrap().lobby);
+        assert!(parse(&["--lobby"]).unwrap().lobby);
+
+        assert!(parse(&[]).unwrap().chat);
+        assert!(!parse(&["--no-chat"]).unwrap().chat);
+
+        assert_eq!(parse(&[]).unwrap().clock, None);
+        let settings = parse(&["--clock", "3+2"]).unwrap();
+        assert_eq!(settings.clock, Some(TimeControl::Increment {
+            base_ms: 180_000,
+            increment_ms: 2000,
+        }));
+        assert!(parse(&["--clock", "soon"]).is_err());
+
+        assert!(parse(&["--heartbeat", "-1"]).is_err());
+        assert!(parse(&["--port", "70000"]).is_err());
+        assert!(parse(&["--play", "green"]).is_err());
+        assert!(parse(&["--bind"]).is_err());
+        assert!(parse(&["--what"]).is_err());
+
+        // The server turns down what only players use.
+        let server = |args: &[&str]| {
+            let args = std::iter::once("gchess-server")
+                .chain(args.iter().copied());
+            Settings::parse_server(args.map(|s| s.to_string()))
+        };
+        assert!(server(&["--lobby", "--record", "sessions"]).unwrap().lobby);
+        let settings = server(&["--framing", "streaming"]).unwrap();
+        assert_eq!(settings.framing, Framing::Streaming);
+        assert!(server(&["--clock", "5"]).is_err());
+        assert!(server(&["--join", "localhost"]).is_err());
+        assert!(server(&["--no-chat"]).is_err());
+    }
+}
@@ -0,0 +1,93 @@
+//! Read-only connections to a hosted game, which are shown every move but
+//! may not make any.
+
+use chess_network_protocol::{ClientToServer, ClientToServerHandshake,
+                             ServerToClient};
+
+use crate::codec::Packet;
+use crate::game::ChessState;
+use crate::net::{error_message, send_server_handshake, HostConnection};
+
+/// The error sent to spectators that try to move.
+pub const SPECTATOR_ERROR: &str = "Spectators cannot move";
+
+struct Spectator {
+    connection: HostConnection,
+    /// Set once the spectator has been sent the board, after which it is
+    /// sent every change to it.
+    handshake_sent: bool,
+    packets: Vec<Packet<ClientToServerHandshake, ClientToServer>>,
+}
+
+/// Everyone watching the host's game.
+pub struct Spectators {
+    spectators: Vec<Spectator>,
+}
+
+impl Spectators {
+    pub fn new() -> Self {
+        Spectators { spectators: vec![] }
+    }
+
+    pub fn len(&self) -> usize {
+        self.spectators.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.spectators.is_empty()
+    }
+
+    /// Adds a spectator, which is sent the board once it has sent its
+    /// handshake.
+    pub fn add(&mut self, connection: HostConnection) {
+        println!("[server] spectator joined");
+        self.spectators.push(Spectator {
+            connection,
+            handshake_sent: false,
+            packets: vec![],
+        });
+    }
+
+    /// Answers handshakes and rejects moves, and forgets the spectators
+    /// that have left.
+    pub fn update(&mut self, chess_state: &ChessState) {
+        self.spectators.retain_mut(|spectator| {
+            let connected = spectator.connection.poll(&mut spectator.packets);
+            for packet in spectator.packets.drain(..) {
+                // Whatever a spectator sends first, it has to be told what
+                // the board looks like. The colour it asks for is ignored.
+                if !spectator.handshake_sent {
+                    spectator.handshake_sent = true;
+                    send_server_handshake(&spectator.connection, chess_state,
+                                          &[]);
+                }
+                match packet {
+                    Packet::Handshake(_) => {}
+                    Packet::Data(ClientToServer::Move(m)) => {
+                        println!("[server] spectator tried to move: {m:?}");
+                        let error = error_message(chess_state,
+                                                  SPECTATOR_ERROR);
+                        spectator.connection.send(Packet::Data(error));
+                    }
+                    Packet::Data(data) => {
+                        println!("[server] ignoring spectator: {data:?}");
+                    }
+                }
+            }
+            if !connected {
+                println!("[server] spectator left");
+            }
+            connected
+        });
+    }
+
+    /// Sends a change to the board to everyone that has been sent the
+    /// board.
+    pub fn broadcast(&self, message: &ServerToClient) {
+        for spectator in &self.spectators {
+            if spectator.handshake_sent {
+                spectator.connection.send(Packet::Data(message.clone()));
+            }
+        }
+    }
+}
@@ -0,0 +1,92 @@
+//! Conversion between our representation of the board and the one used by
+//! the network protocol.
+
+use chess_network_protocol::{Move, Piece};
+
+pub fn chess_piece_to_wire(square: (i8, i8)) -> Piece {
+    match square {
+        (1, -1) => Piece::WhitePawn,
+        (2, -1) => Piece::WhiteRook,
+        (3, -1) => Piece::WhiteKnight,
+        (4, -1) => Piece::WhiteBishop,
+        (5, -1) => Piece::WhiteQueen,
+        (6, -1) => Piece::WhiteKing,
+        (1, 1) => Piece::BlackPawn,
+        (2, 1) => Piece::BlackRook,
+        (3, 1) => Piece::BlackKnight,
+        (4, 1) => Piece::BlackBishop,
+        (5, 1) => Piece::BlackQueen,
+        (6, 1) => Piece::BlackKing,
+        _ => Piece::None,
+    }
+}
+
+pub fn chess_representaiton_to_wire(data: &[(i8, i8); 64]) -> [[Piece; 8]; 8] {
+    let mut result = [[Piece::None; 8]; 8];
+    for i in 0..64 {
+        let square = data[i];
+        let row = i >> 3;
+        let column = i & 7;
+        result[7 - row][column] = chess_piece_to_wire(square);
+    }
+    result
+}
+
+pub fn wire_to_chess_representation(data: &[[Piece; 8]; 8]) -> [(i8, i8); 64] {
+    let mut result = [(0, 0); 64];
+    for i in 0..64 {
+        let row = i >> 3;
+        let column = i & 7;
+        let square = data[7 - row][column];
+        result[i] = match square {
+            Piece::WhitePawn   => (1, -1),
+            Piece::WhiteRook   => (2, -1),
+            Piece::WhiteKnight => (3, -1),
+            Piece::WhiteBishop => (4, -1),
+            Piece::WhiteQueen  => (5, -1),
+            Piece::WhiteKing   => (6, -1),
+            Piece::BlackPawn   => (1, 1),
+            Piece::BlackRook   => (2, 1),
+            Piece::BlackKnight => (3, 1),
+            Piece::BlackBishop => (4, 1),
+            Piece::BlackQueen  => (5, 1),
+            Piece::BlackKing   => (6, 1),
+            _ => (0, 0),
+        }
+    }
+    result
+}
+
+pub fn indices_to_wire_move(from: usize, to: usize) -> Move {
+    Move {
+        end_y: 7 - (to >> 3),
+        end_x: to & 7,
+        start_y: 7 - (from >> 3),
+        start_x: from & 7,
+        promotion: Piece::None,
+    }
+}
+
+pub fn wire_to_piece_kind(piece: Piece) -> i8 {
+    match piece {
+        Piece::WhitePawn   | Piece::BlackPawn   => 1,
+        Piece::WhiteRook   | Piece::BlackRook   => 2,
+        Piece::WhiteKnight | Piece::BlackKnight => 3,
+        Piece::WhiteBishop | Piece::BlackBishop => 4,
+        Piece::WhiteQueen  | Piece::BlackQueen  => 5,
+        Piece::WhiteKing   | Piece::BlackKing   => 6,
+        _ => 0,
+    }
+}
+
+/// Returns the squares a move goes from and to, or `None` if any of its
+/// coordinates is off the board.
+pub fn wire_move_to_indices(mv: &Move) -> Option<(usize, usize)> {
+    let coordinates = [mv.start_x, mv.start_y, mv.end_x, mv.end_y];
+    if coordinates.iter().any(|&coordinate| coordinate >= 8) {
+        return None
+    }
+    let from = (7 - mv.start_y) << 3 | mv.start_x;
+    let to = (7 - mv.end_y) << 3 | mv.end_x;
+    Some((from, to))
+}
@@ -0,0 +1,1394 @@
+// Plays scripted games between our sessions and a peer that speaks the
+// protocol by hand over 127.0.0.1, checking every message sent on the wire.
+
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::time::{Duration, Instant};
+
+use albjorkm_chess_gui::{chess_representaiton_to_wire, connect_client,
+                         ChatLine, ChessState, ClientConnection,
+                         ClientSession, Clock, Connection, Deviation,
+                         Extension, Framing, GameEnd, HostSession, Incoming,
+                         InteropMode, JsonPoller, Packet, TimeControl,
+                         CHAT_FEATURE, CLOCK_FEATURE,
+                         HEARTBEAT_FEATURE, REMATCH_FEATURE,
+                         SPECTATOR_ERROR};
+use albjorkm_chess_gui::clock::ClockState;
+use albjorkm_chess_gui::extension::{extended_poller, ExtendedData};
+use albjorkm_chess_gui::lobby::{Lobby, LobbyClient, LobbyReply, OpenGame};
+use albjorkm_chess_gui::record::{read_session, replay, Recorder, Replayed,
+                                 Role};
+use albjorkm_chess_gui::rematch::RematchRequest;
+use chess_network_protocol::{ClientToServer, ClientToServerHandshake, Color,
+                             Features, Joever, Move, Piece, ServerToClient,
+                             ServerToClientHandshake};
+
+/// How long to wait for a message before failing the test.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The far end of a connection, played by the test.
+struct Peer<Handshake: serde::de::DeserializeOwned,
+            Data: serde::de::DeserializeOwned> {
+    stream: TcpStream,
+    poller: JsonPoller<Handshake, ExtendedData<Data>>,
+    received: Vec<Packet<Handshake, Data>>,
+    /// The messages of our own received, which are not protocol packets.
+    extensions: Vec<Extension>,
+}
+
+impl<Handshake: serde::de::DeserializeOwned,
+     Data: serde::de::DeserializeOwned> Peer<Handshake, Data> {
+    fn new(stream: TcpStream) -> Self {
+        stream.set_nonblocking(true).unwrap();
+        Peer {
+            stream,
+            poller: extended_poller(Framing::Finder),
+            received: vec![],
+            extensions: vec![],
+        }
+    }
+
+    fn send(&mut self, message: &impl serde::Serialize) {
+        let bytes = serde_json::to_vec(message).unwrap();
+        self.stream.write_all(&bytes).unwrap();
+    }
+
+    /// Sends a message followed by a newline, like some other
+    /// implementations do.
+    fn send_line(&mut self, message: &impl serde::Serialize) {
+        self.send(message);
+        self.stream.write_all(b"\n").unwrap();
+    }
+
+    fn read(&mut self) {
+        let mut buffer = [0u8; 65536];
+        match self.stream.read(&mut buffer) {
+            Ok(0) => panic!("the connection was closed"),
+            Ok(length) => {
+                let mut packets = vec![];
+                self.poller.feed(&buffer[..length], &mut packets);
+                for packet in packets {
+                    match Incoming::split(packet) {
+                        Incoming::Packet(packet) => self.received.push(packet),
+                        Incoming::Extension(e) => self.extensions.push(e),
+                    }
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {}
+            Err(e) => panic!("read error: {e}"),
+        }
+    }
+
+    /// Waits for the next message, calling `pump` to let the session under
+    /// test do its work.
+    fn receive(&mut self, mut pump: impl FnMut()) -> Packet<Handshake, Data> {
+        let start = Instant::now();
+        while self.received.is_empty() {
+            assert!(start.elapsed() < TIMEOUT, "no message was received");
+            pump();
+            self.read();
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        self.received.remove(0)
+    }
+
+    /// Checks that nothing is sent for a while.
+    fn assert_silent(&mut self, mut pump: impl FnMut()) {
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_millis(100) {
+            pump();
+            self.read();
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert!(self.received.is_empty(), "unexpected message was received");
+    }
+}
+
+/// Turns a square such as "e2" into its index in the board representation.
+fn index(square: &str) -> usize {
+    let bytes = square.as_bytes();
+    let column = (bytes[0] - b'a') as usize;
+    let row = 7 - (bytes[1] - b'1') as usize;
+    row * 8 + column
+}
+
+fn mv(from: &str, to: &str) -> Move {
+    let (from, to) = (index(from), index(to));
+    Move {
+        start_x: from & 7,
+        start_y: 7 - (from >> 3),
+        end_x: to & 7,
+        end_y: 7 - (to >> 3),
+        promotion: Piece::None,
+    }
+}
+
+/// Looks up a square on a board as sent over the wire.
+fn at(board: &[[Piece; 8]; 8], square: &str) -> Piece {
+    let bytes = square.as_bytes();
+    board[(bytes[1] - b'1') as usize][(bytes[0] - b'a') as usize]
+}
+
+type ClientPeer = Peer<ServerToClientHandshake, ServerToClient>;
+type ServerPeer = Peer<ClientToServerHandshake, ClientToServer>;
+
+/// Connects a hand written client to a host session.
+fn host_game() -> (HostSession, ChessState, ClientPeer) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (accepted, _) = listener.accept().unwrap();
+    let session = HostSession::new(Connection::spawn(accepted).unwrap(), true);
+    let mut chess_state = ChessState::new();
+    chess_state.is_host = true;
+    (session, chess_state, Peer::new(stream))
+}
+
+/// Connects a client session to a hand written server.
+fn client_game(is_white: bool) -> (ClientSession, ChessState, ServerPeer) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    let connection = connect_client(&address, !is_white).unwrap();
+    let (stream, _) = listener.accept().unwrap();
+    let mut chess_state = ChessState::new();
+    chess_state.is_client = true;
+    (ClientSession::new(connection), chess_state, Peer::new(stream))
+}
+
+/// The state the host should send after a move, built from its own view of
+/// the game.
+fn expected_state(chess_state: &ChessState, move_made: Move) -> ServerToClient {
+    ServerToClient::State {
+        board: chess_representaiton_to_wire(&chess_state.chess_representation),
+        moves: chess_state.legal_moves.clone(),
+        joever: chess_state.to_joever(),
+        move_made,
+    }
+}
+
+/// Sends the client's handshake and checks the host's answer.
+fn handshake_with_host(session: &mut HostSession, host: &mut ChessState,
+                       client: &mut ClientPeer, server_color: Color) {
+    client.send(&ClientToServerHandshake { server_color });
+    let Packet::Handshake(handshake) = client.receive(|| {
+        session.update(host);
+    }) else {
+        panic!("expected a handshake");
+    };
+    let initial = ChessState::new();
+    assert_eq!(handshake.board,
+               chess_representaiton_to_wire(&initial.chess_representation));
+    assert_eq!(handshake.joever, Joever::Ongoing);
+    assert_eq!(handshake.moves, initial.legal_moves);
+    for feature in [Features::EnPassant, Features::Castling,
+                    Features::Promotion, Features::PossibleMoveGeneration] {
+        assert!(handshake.features.contains(&feature));
+    }
+}
+
+/// Makes a move as the host and checks the state sent to the client.
+fn host_move(session: &mut HostSession, host: &mut ChessState,
+             client: &mut ClientPeer, from: &str, to: &str)
+    -> ServerToClient {
+    assert!(host.do_move(index(from), index(to)), "{from}{to} failed");
+    let packet = client.receive(|| {
+        session.update(host);
+    });
+    assert_eq!(packet, Packet::Data(expected_state(host, mv(from, to))));
+    let Packet::Data(state) = packet else { unreachable!() };
+    state
+}
+
+/// Sends a move as the client and checks the state sent back by the host.
+fn client_move(session: &mut HostSession, host: &mut ChessState,
+               client: &mut ClientPeer, mv: Move) -> ServerToClient {
+    client.send(&ClientToServer::Move(mv));
+    let packet = client.receive(|| {
+        session.update(host);
+    });
+    assert_eq!(packet, Packet::Data(expected_state(host, mv)));
+    let Packet::Data(state) = packet else { unreachable!() };
+    state
+}
+
+fn board_of(state: &ServerToClient) -> &[[Piece; 8]; 8] {
+    match state {
+        ServerToClient::State { board, .. }
+        | ServerToClient::Error { board, .. }
+        | ServerToClient::Resigned { board, .. }
+        | ServerToClient::Draw { board, .. } => board,
+    }
+}
+
+#[test]
+pub fn host_handshake() {
+    let (mut session, mut host, mut client) = host_game();
+    handshake_with_host(&mut session, &mut host, &mut client, Color::Black);
+    assert!(!session.host_is_white);
+
+    // Nothing else is sent until someone moves.
+    client.assert_silent(|| {
+        session.update(&mut host);
+    });
+}
+
+#[test]
+pub fn host_castling_and_en_passant() {
+    let (mut session, mut host, mut client) = host_game();
+    let (s, h, c) = (&mut session, &mut host, &mut client);
+    handshake_with_host(s, h, c, Color::White);
+
+    host_move(s, h, c, "e2", "e4");
+    client_move(s, h, c, mv("a7", "a6"));
+    host_move(s, h, c, "e4", "e5");
+    client_move(s, h, c, mv("d7", "d5"));
+
+    // The pawn on d5 is taken in passing.
+    let state = host_move(s, h, c, "e5", "d6");
+    assert_eq!(at(board_of(&state), "d6"), Piece::WhitePawn);
+    assert_eq!(at(board_of(&state), "d5"), Piece::None);
+    assert_eq!(at(board_of(&state), "e5"), Piece::None);
+
+    client_move(s, h, c, mv("c7", "d6"));
+    host_move(s, h, c, "g1", "f3");
+    client_move(s, h, c, mv("b8", "c6"));
+    host_move(s, h, c, "f1", "c4");
+    client_move(s, h, c, mv("c8", "g4"));
+
+    // Castling is sent as the move of the king.
+    let state = host_move(s, h, c, "e1", "g1");
+    assert_eq!(at(board_of(&state), "g1"), Piece::WhiteKing);
+    assert_eq!(at(board_of(&state), "f1"), Piece::WhiteRook);
+    assert_eq!(at(board_of(&state), "e1"), Piece::None);
+    assert_eq!(at(board_of(&state), "h1"), Piece::None);
+
+    // The client can castle queen side just the same.
+    client_move(s, h, c, mv("d8", "d7"));
+    host_move(s, h, c, "d2", "d3");
+    let state = client_move(s, h, c, mv("e8", "c8"));
+    assert_eq!(at(board_of(&state), "c8"), Piece::BlackKing);
+    assert_eq!(at(board_of(&state), "d8"), Piece::BlackRook);
+    assert_eq!(at(board_of(&state), "a8"), Piece::None);
+}
+
+#[test]
+pub fn host_rejects_illegal_moves() {
+    let (mut session, mut host, mut client) = host_game();
+    let (s, h, c) = (&mut session, &mut host, &mut client);
+    handshake_with_host(s, h, c, Color::White);
+    host_move(s, h, c, "e2", "e4");
+
+    let before = chess_representaiton_to_wire(&h.chess_representation);
+    // The last one has squares off the board.
+    let off_board = Move { start_x: 8, end_y: 200, ..mv("e7", "e5") };
+    for bad in [mv("e7", "e4"), mv("e2", "e3"), mv("a8", "a6"), off_board] {
+        c.send(&ClientToServer::Move(bad));
+        let packet = c.receive(|| {
+            s.update(h);
+        });
+        let Packet::Data(ServerToClient::Error
+                         { board, moves, joever, message }) = packet else {
+            panic!("expected an error, got {packet:?}");
+        };
+        // The board is unchanged and it is still the client's turn.
+        assert_eq!(board, before);
+        assert_eq!(moves, h.legal_moves);
+        assert_eq!(joever, Joever::Ongoing);
+        assert!(!message.is_empty());
+        assert!(!h.is_white_turn);
+    }
+
+    // A good move is still accepted afterwards.
+    client_move(s, h, c, mv("e7", "e5"));
+}
+
+#[test]
+pub fn host_keeps_colour_in_resumed_game() {
+    let (mut session, mut host, mut client) = host_game();
+    let (s, h, c) = (&mut session, &mut host, &mut client);
+    handshake_with_host(s, h, c, Color::White);
+    host_move(s, h, c, "e2", "e4");
+
+    // The client comes back asking for the other side.
+    let (mut session, _, mut client) = host_game();
+    let (s, c) = (&mut session, &mut client);
+    c.send(&ClientToServerHandshake { server_color: Color::Black });
+    let packet = c.receive(|| {
+        s.update(h);
+    });
+    assert!(matches!(packet, Packet::Handshake(_)));
+    let packet = c.receive(|| {
+        s.update(h);
+    });
+    let Packet::Data(ServerToClient::Error { message, .. }) = packet else {
+        panic!("expected an error, got {packet:?}");
+    };
+    assert!(message.contains("colours"));
+    assert!(s.host_is_white);
+
+    // It can still play the side it had.
+    client_move(s, h, c, mv("e7", "e5"));
+}
+
+#[test]
+pub fn host_promotion() {
+    let (mut session, mut host, mut client) = host_game();
+    let (s, h, c) = (&mut session, &mut host, &mut client);
+    // The client plays white.
+    handshake_with_host(s, h, c, Color::Black);
+
+    client_move(s, h, c, mv("h2", "h4"));
+    host_move(s, h, c, "g7", "g5");
+    client_move(s, h, c, mv("h4", "g5"));
+    host_move(s, h, c, "h7", "h6");
+    client_move(s, h, c, mv("g5", "h6"));
+    host_move(s, h, c, "f8", "g7");
+    client_move(s, h, c, mv("h6", "g7"));
+    host_move(s, h, c, "g8", "f6");
+
+    // The legal moves list every piece the pawn can become.
+    let promotions: Vec<_> = h.legal_moves.iter()
+        .filter(|m| (m.start_x, m.start_y, m.end_x, m.end_y) == (6, 6, 7, 7))
+        .map(|m| m.promotion)
+        .collect();
+    for piece in [Piece::WhiteQueen, Piece::WhiteRook, Piece::WhiteBishop,
+                  Piece::WhiteKnight] {
+        assert!(promotions.contains(&piece));
+    }
+
+    // Reaching the last rank without saying what to promote to is refused.
+    c.send(&ClientToServer::Move(mv("g7", "h8")));
+    let packet = c.receive(|| {
+        s.update(h);
+    });
+    let Packet::Data(ServerToClient::Error { message, .. }) = packet else {
+        panic!("expected an error, got {packet:?}");
+    };
+    assert_eq!(message, "Bad move!");
+    assert!(h.is_white_turn && !h.is_promoting);
+
+    let underpromotion = Move {
+        promotion: Piece::WhiteKnight,
+        ..mv("g7", "h8")
+    };
+    let state = client_move(s, h, c, underpromotion);
+    assert_eq!(at(board_of(&state), "h8"), Piece::WhiteKnight);
+    assert_eq!(at(board_of(&state), "g7"), Piece::None);
+}
+
+#[test]
+pub fn host_checkmate() {
+    let (mut session, mut host, mut client) = host_game();
+    let (s, h, c) = (&mut session, &mut host, &mut client);
+    handshake_with_host(s, h, c, Color::Black);
+
+    client_move(s, h, c, mv("f2", "f3"));
+    host_move(s, h, c, "e7", "e5");
+    client_move(s, h, c, mv("g2", "g4"));
+    let state = host_move(s, h, c, "d8", "h4");
+    let ServerToClient::State { joever, moves, .. } = state else {
+        unreachable!()
+    };
+    assert_eq!(joever, Joever::Black);
+    assert_eq!(moves, vec![]);
+
+    // No more moves are accepted once the game is over.
+    c.send(&ClientToServer::Move(mv("a2", "a3")));
+    let packet = c.receive(|| {
+        s.update(h);
+    });
+    assert!(matches!(packet, Packet::Data(ServerToClient::Error
+                                          { joever: Joever::Black, .. })));
+}
+
+#[test]
+pub fn host_resignation() {
+    let (mut session, mut host, mut client) = host_game();
+    let (s, h, c) = (&mut session, &mut host, &mut client);
+    handshake_with_host(s, h, c, Color::White);
+    host_move(s, h, c, "e2", "e4");
+
+    c.send(&ClientToServer::Resign);
+    let packet = c.receive(|| {
+        s.update(h);
+    });
+    let expected = ServerToClient::Resigned {
+        board: chess_representaiton_to_wire(&h.chess_representation),
+        joever: Joever::White,
+    };
+    assert_eq!(packet, Packet::Data(expected));
+    assert_eq!(h.end, Some(GameEnd::Resignation { white_won: true }));
+}
+
+/// Receives the client's handshake and answers it with the starting
+/// position.
+fn handshake_with_client(session: &mut ClientSession,
+                         client: &mut ChessState, server: &mut ServerPeer,
+                         server_color: Color) -> ChessState {
+    let mut errors = vec![];
+    let mut desyncs = vec![];
+    let packet = server.receive(|| {
+        session.update(client, &mut errors, &mut desyncs);
+    });
+    assert_eq!(packet,
+               Packet::Handshake(ClientToServerHandshake { server_color }));
+
+    let host = ChessState::new();
+    server.send(&ServerToClientHandshake {
+        features: vec![Features::PossibleMoveGeneration],
+        board: chess_representaiton_to_wire(&host.chess_representation),
+        moves: host.legal_moves.clone(),
+        joever: Joever::Ongoing,
+    });
+    let start = Instant::now();
+    while !session.handshake_received {
+        assert!(start.elapsed() < TIMEOUT, "the handshake was not received");
+        session.update(client, &mut errors, &mut desyncs);
+    }
+    assert!(client.has_move_list);
+    assert_eq!(errors, Vec::<String>::new());
+    assert_eq!(desyncs, Vec::<String>::new());
+    host
+}
+
+/// Plays a move as the server, sending the client the resulting state.
+fn server_move(host: &mut ChessState, server: &mut ServerPeer,
+               from: &str, to: &str) {
+    assert!(host.do_move(index(from), index(to)), "{from}{to} failed");
+    server.send(&expected_state(host, mv(from, to)));
+}
+
+/// Lets the client make a move and checks what is sent to the server.
+fn expect_client_move(session: &mut ClientSession, client: &mut ChessState,
+                      server: &mut ServerPeer, expected: Move) {
+    let packet = server.receive(|| {
+        session.update(client, &mut vec![], &mut vec![]);
+    });
+    assert_eq!(packet, Packet::Data(ClientToServer::Move(expected)));
+}
+
+/// Waits for the client to take in everything the server has sent.
+fn settle(session: &mut ClientSession, client: &mut ChessState,
+          errors: &mut Vec<String>, desyncs: &mut Vec<String>) {
+    let start = Instant::now();
+    let received = session.received;
+    while session.received == received {
+        assert!(start.elapsed() < TIMEOUT, "nothing was received");
+        session.update(client, errors, desyncs);
+        std::thread::sleep(Duration::from_millis(1));
+    }
+}
+
+#[test]
+pub fn client_moves_and_errors() {
+    let (mut session, mut client, mut server) = client_game(false);
+    let (s, cl, sv) = (&mut session, &mut client, &mut server);
+    let mut host = handshake_with_client(s, cl, sv, Color::White);
+    let (mut errors, mut desyncs) = (vec![], vec![]);
+
+    server_move(&mut host, sv, "e2", "e4");
+    settle(s, cl, &mut errors, &mut desyncs);
+    assert!(!cl.is_white_turn);
+
+    // Moves that are not in the list never leave the client.
+    assert!(!cl.do_move(index("e7"), index("e4")));
+    sv.assert_silent(|| {
+        s.update(cl, &mut errors, &mut desyncs);
+    });
+
+    assert!(cl.do_move(index("e7"), index("e5")));
+    expect_client_move(s, cl, sv, mv("e7", "e5"));
+
+    // The server disagrees, so the client is rolled back.
+    sv.send(&ServerToClient::Error {
+        board: chess_representaiton_to_wire(&host.chess_representation),
+        moves: host.legal_moves.clone(),
+        joever: Joever::Ongoing,
+        message: "No thanks".into(),
+    });
+    settle(s, cl, &mut errors, &mut desyncs);
+    assert_eq!(errors, vec![String::from("No thanks")]);
+    assert_eq!(cl.chess_representation, host.chess_representation);
+    assert!(!cl.is_white_turn);
+
+    assert!(cl.do_move(index("d7"), index("d5")));
+    expect_client_move(s, cl, sv, mv("d7", "d5"));
+    assert!(host.do_move(index("d7"), index("d5")));
+    sv.send(&expected_state(&host, mv("d7", "d5")));
+    settle(s, cl, &mut errors, &mut desyncs);
+    assert!(cl.is_white_turn);
+    assert_eq!(cl.chess_representation, host.chess_representation);
+    assert_eq!(desyncs, Vec::<String>::new());
+}
+
+#[test]
+pub fn client_promotion() {
+    let (mut session, mut client, mut server) = client_game(true);
+    let (s, cl, sv) = (&mut session, &mut client, &mut server);
+    let mut host = handshake_with_client(s, cl, sv, Color::Black);
+    let (mut errors, mut desyncs) = (vec![], vec![]);
+
+    let script = [("h2", "h4"), ("g7", "g5"), ("h4", "g5"), ("h7", "h6"),
+                  ("g5", "h6"), ("f8", "g7"), ("h6", "g7"), ("g8", "f6")];
+    for (i, (from, to)) in script.into_iter().enumerate() {
+        if i % 2 == 0 {
+            assert!(cl.do_move(index(from), index(to)));
+            expect_client_move(s, cl, sv, mv(from, to));
+            assert!(host.ingest_client_move(&mv(from, to)));
+            sv.send(&expected_state(&host, mv(from, to)));
+        } else {
+            server_move(&mut host, sv, from, to);
+        }
+        settle(s, cl, &mut errors, &mut desyncs);
+    }
+
+    // The move is held back until the piece has been picked.
+    assert!(cl.do_move(index("g7"), index("h8")));
+    assert!(cl.is_promoting);
+    sv.assert_silent(|| {
+        s.update(cl, &mut errors, &mut desyncs);
+    });
+    cl.promote(5).unwrap();
+    let queen = Move { promotion: Piece::WhiteQueen, ..mv("g7", "h8") };
+    expect_client_move(s, cl, sv, queen);
+    assert_eq!(errors, Vec::<String>::new());
+    assert_eq!(desyncs, Vec::<String>::new());
+}
+
+#[test]
+pub fn client_checkmate() {
+    let (mut session, mut client, mut server) = client_game(true);
+    let (s, cl, sv) = (&mut session, &mut client, &mut server);
+    let mut host = handshake_with_client(s, cl, sv, Color::Black);
+    let (mut errors, mut desyncs) = (vec![], vec![]);
+
+    for (from, to) in [("f2", "f3"), ("g2", "g4")] {
+        assert!(cl.do_move(index(from), index(to)));
+        expect_client_move(s, cl, sv, mv(from, to));
+        assert!(host.ingest_client_move(&mv(from, to)));
+        sv.send(&expected_state(&host, mv(from, to)));
+        settle(s, cl, &mut errors, &mut desyncs);
+        let reply = if from == "f2" { ("e7", "e5") } else { ("d8", "h4") };
+        server_move(&mut host, sv, reply.0, reply.1);
+        settle(s, cl, &mut errors, &mut desyncs);
+    }
+    assert_eq!(cl.end, Some(GameEnd::Checkmate { white_won: false }));
+    assert!(!cl.do_move(index("a2"), index("a3")));
+}
+
+#[test]
+pub fn client_resignation() {
+    let (mut session, mut client, mut server) = client_game(true);
+    let (s, cl, sv) = (&mut session, &mut client, &mut server);
+    let host = handshake_with_client(s, cl, sv, Color::Black);
+    let (mut errors, mut desyncs) = (vec![], vec![]);
+
+    cl.resign(true);
+    let packet = sv.receive(|| {
+        s.update(cl, &mut errors, &mut desyncs);
+    });
+    assert_eq!(packet, Packet::Data(ClientToServer::Resign));
+    // The resignation only counts once the server has confirmed it.
+    assert!(!cl.is_game_over);
+
+    sv.send(&ServerToClient::Resigned {
+        board: chess_representaiton_to_wire(&host.chess_representation),
+        joever: Joever::Black,
+    });
+    settle(s, cl, &mut errors, &mut desyncs);
+    assert_eq!(cl.end, Some(GameEnd::Resignation { white_won: false }));
+}
+
+#[test]
+pub fn host_lenient_interop() {
+    let (mut session, mut host, mut client) = host_game();
+    let (s, h, c) = (&mut session, &mut host, &mut client);
+    c.send_line(&ClientToServerHandshake { server_color: Color::White });
+    let packet = c.receive(|| {
+        s.update(h);
+    });
+    assert!(matches!(packet, Packet::Handshake(_)));
+
+    // Moving out of turn is answered with an error.
+    c.send_line(&ClientToServer::Move(mv("e7", "e5")));
+    let packet = c.receive(|| {
+        s.update(h);
+    });
+    assert!(matches!(packet, Packet::Data(ServerToClient::Error { .. })));
+
+    host_move(s, h, c, "e2", "e4");
+    client_move(s, h, c, mv("e7", "e5"));
+    let deviations = &s.interop.deviations;
+    assert_eq!(deviations.len(), 3);
+    assert_eq!(deviations[0], Deviation::Whitespace);
+    assert!(matches!(deviations[1], Deviation::UnexpectedMessage(_)));
+    assert_eq!(deviations[2], Deviation::Whitespace);
+}
+
+#[test]
+pub fn host_strict_interop() {
+    let (mut session, mut host, mut client) = host_game();
+    session.interop.mode = InteropMode::Strict;
+    handshake_with_host(&mut session, &mut host, &mut client, Color::White);
+    assert!(host.do_move(index("e2"), index("e4")));
+    client.send(&ClientToServer::Move(mv("e7", "e5")));
+    client.send(&ClientToServerHandshake { server_color: Color::White });
+
+    let start = Instant::now();
+    while session.update(&mut host) {
+        assert!(start.elapsed() < TIMEOUT, "the session did not hang up");
+    }
+    assert_eq!(session.interop.deviations, vec![Deviation::RepeatedHandshake]);
+}
+
+#[test]
+pub fn client_unknown_feature() {
+    let (mut session, mut client, mut server) = client_game(true);
+    let (mut errors, mut desyncs) = (vec![], vec![]);
+    let packet = server.receive(|| {
+        session.update(&mut client, &mut errors, &mut desyncs);
+    });
+    assert!(matches!(packet, Packet::Handshake(_)));
+
+    server.send(&ServerToClientHandshake {
+        features: vec![Features::Other("Clocks".into())],
+        board: chess_representaiton_to_wire(&client.chess_representation),
+        moves: vec![],
+        joever: Joever::Ongoing,
+    });
+    settle(&mut session, &mut client, &mut errors, &mut desyncs);
+    assert_eq!(session.interop.deviations,
+               vec![Deviation::UnknownFeature("Clocks".into())]);
+    // Without a move list the client trusts the server to check moves.
+    assert!(!client.has_move_list);
+}
+
+#[test]
+pub fn client_off_board_moves() {
+    let (mut session, mut client, mut server) = client_game(false);
+    let (s, cl, sv) = (&mut session, &mut client, &mut server);
+    let mut host = handshake_with_client(s, cl, sv, Color::White);
+    let (mut errors, mut desyncs) = (vec![], vec![]);
+
+    // Moves off the board are left out rather than trusted.
+    assert!(host.do_move(index("e2"), index("e4")));
+    let ServerToClient::State { board, mut moves, joever, .. }
+        = expected_state(&host, mv("e2", "e4")) else {
+        unreachable!();
+    };
+    moves.push(Move { end_y: 8, ..mv("e7", "e5") });
+    let move_made = Move { start_x: 9, ..mv("e2", "e4") };
+    sv.send(&ServerToClient::State { board, moves, joever, move_made });
+    settle(s, cl, &mut errors, &mut desyncs);
+    assert!(matches!(s.interop.deviations.as_slice(),
+                     [Deviation::OffBoardMove(_)]));
+    assert_eq!(cl.legal_moves, host.legal_moves);
+    assert_eq!(cl.chess_representation, host.chess_representation);
+    assert!(!cl.is_white_turn);
+}
+
+/// Calls `step` until it returns true, failing the test if it takes too long.
+fn wait_for(mut step: impl FnMut() -> bool) {
+    let start = Instant::now();
+    while !step() {
+        assert!(start.elapsed() < TIMEOUT, "timed out");
+        std::thread::sleep(Duration::from_millis(1));
+    }
+}
+
+#[test]
+pub fn host_heartbeat() {
+    let (mut session, mut host, mut client) = host_game();
+    session.heartbeat = Some(Duration::from_millis(20));
+    client.send(&ClientToServerHandshake { server_color: Color::Black });
+    let Packet::Handshake(handshake) = client.receive(|| {
+        session.update(&mut host);
+    }) else {
+        panic!("expected a handshake");
+    };
+    let feature = Features::Other(HEARTBEAT_FEATURE.into());
+    assert!(handshake.features.contains(&feature));
+
+    // Clients that do not send heartbeats are neither sent them, nor ever
+    // considered lost.
+    client.assert_silent(|| {
+        session.update(&mut host);
+    });
+    assert!(client.extensions.is_empty());
+    assert!(!session.timed_out());
+
+    client.send(&"Heartbeat");
+    wait_for(|| {
+        session.update(&mut host);
+        client.read();
+        client.extensions.contains(&Extension::Heartbeat)
+    });
+    assert!(client.received.is_empty());
+    assert!(session.interop.deviations.is_empty());
+
+    // Once the client has opted in, its silence is noticed.
+    wait_for(|| {
+        session.update(&mut host);
+        session.timed_out()
+    });
+    client.send(&"Heartbeat");
+    wait_for(|| {
+        session.update(&mut host);
+        !session.timed_out()
+    });
+}
+
+#[test]
+pub fn client_heartbeat() {
+    let (mut session, mut client, mut server) = client_game(true);
+    session.heartbeat = Some(Duration::from_millis(20));
+    let (mut errors, mut desyncs) = (vec![], vec![]);
+    let packet = server.receive(|| {
+        session.update(&mut client, &mut errors, &mut desyncs);
+    });
+    assert!(matches!(packet, Packet::Handshake(_)));
+    // Nothing is sent before the server has advertised heartbeats.
+    server.assert_silent(|| {
+        session.update(&mut client, &mut errors, &mut desyncs);
+    });
+    assert!(server.extensions.is_empty());
+
+    server.send(&ServerToClientHandshake {
+        features: vec![Features::Other(HEARTBEAT_FEATURE.into())],
+        board: chess_representaiton_to_wire(&client.chess_representation),
+        moves: vec![],
+        joever: Joever::Ongoing,
+    });
+    wait_for(|| {
+        session.update(&mut client, &mut errors, &mut desyncs);
+        server.read();
+        server.extensions.len() > 1
+    });
+    assert!(server.extensions.iter().all(|e| *e == Extension::Heartbeat));
+    assert!(session.handshake_received);
+    assert!(session.interop.deviations.is_empty());
+    assert!(server.received.is_empty());
+    assert!(!session.timed_out());
+
+    server.send(&"Heartbeat");
+    wait_for(|| {
+        session.update(&mut client, &mut errors, &mut desyncs);
+        session.timed_out()
+    });
+}
+
+#[test]
+pub fn host_streaming_framing() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (accepted, _) = listener.accept().unwrap();
+    let connection =
+        Connection::spawn_recorded(accepted, Framing::Streaming, None)
+            .unwrap();
+    let mut session = HostSession::new(connection, true);
+    session.chat.enabled = true;
+    let mut host = ChessState::new();
+    host.is_host = true;
+    let mut client: ClientPeer = Peer::new(stream);
+    let (s, h, c) = (&mut session, &mut host, &mut client);
+
+    // Messages are read as they come, however they are split up.
+    let handshake = ClientToServerHandshake { server_color: Color::Black };
+    let mut bytes = serde_json::to_vec(&handshake).unwrap();
+    bytes.extend(serde_json::to_vec(&Extension::Chat(String::new())).unwrap());
+    let (first, rest) = bytes.split_at(9);
+    c.stream.write_all(first).unwrap();
+    c.assert_silent(|| {
+        s.update(h);
+    });
+    c.stream.write_all(rest).unwrap();
+    let packet = c.receive(|| {
+        s.update(h);
+    });
+    assert!(matches!(packet, Packet::Handshake(_)));
+    wait_for(|| {
+        s.update(h);
+        s.chat.is_open()
+    });
+
+    client_move(s, h, c, mv("e2", "e4"));
+    host_move(s, h, c, "e7", "e5");
+    assert!(s.interop.deviations.is_empty());
+}
+
+#[test]
+pub fn host_chat() {
+    let directory = std::env::temp_dir()
+        .join(format!("gchess-chat-{}", std::process::id()));
+    std::fs::create_dir_all(&directory).unwrap();
+    let recorder = Recorder::create(&directory, Role::Host).unwrap();
+    let path = recorder.path.clone();
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (accepted, _) = listener.accept().unwrap();
+    let connection =
+        Connection::spawn_recorded(accepted, Framing::Finder, Some(recorder))
+            .unwrap();
+    let mut session = HostSession::new(connection, true);
+    session.chat.enabled = true;
+    let mut host = ChessState::new();
+    host.is_host = true;
+    let mut client: ClientPeer = Peer::new(stream);
+
+    client.send(&ClientToServerHandshake { server_color: Color::White });
+    let Packet::Handshake(handshake) = client.receive(|| {
+        session.update(&mut host);
+    }) else {
+        panic!("expected a handshake");
+    };
+    let feature = Features::Other(CHAT_FEATURE.into());
+    assert!(handshake.features.contains(&feature));
+
+    // Clients that have not said that they can chat are never sent any.
+    session.chat.send(&session.connection, "hello");
+    client.assert_silent(|| {
+        session.update(&mut host);
+    });
+    assert!(client.extensions.is_empty());
+    assert!(session.chat.lines.is_empty());
+
+    client.send(&serde_json::json!({ "Chat": "" }));
+    wait_for(|| {
+        session.update(&mut host);
+        session.chat.is_open()
+    });
+    assert!(session.chat.lines.is_empty());
+    session.chat.send(&session.connection, " hello ");
+    wait_for(|| {
+        client.read();
+        !client.extensions.is_empty()
+    });
+    assert_eq!(client.extensions, vec![Extension::Chat("hello".into())]);
+    assert!(client.received.is_empty());
+
+    client.send(&serde_json::json!({ "Chat": "hi" }));
+    wait_for(|| {
+        session.update(&mut host);
+        session.chat.lines.len() > 1
+    });
+    assert_eq!(session.chat.lines, vec![
+        ChatLine { ours: true, text: "hello".into() },
+        ChatLine { ours: false, text: "hi".into() },
+    ]);
+    assert!(session.interop.deviations.is_empty());
+
+    // The lines are recorded along with the game.
+    let entries = read_session(&path).unwrap();
+    std::fs::remove_dir_all(&directory).unwrap();
+    let result = replay(&entries, |_, _, _| {});
+    let chat: Vec<_> = result.chat.iter()
+        .map(|line| (line.from_server, line.text.as_str()))
+        .collect();
+    assert_eq!(chat, vec![(true, "hello"), (false, "hi")]);
+    assert!(result.deviations.is_empty());
+    assert!(result.mismatches.is_empty(), "{:?}", result.mismatches);
+}
+
+#[test]
+pub fn client_chat() {
+    for enabled in [false, true] {
+        let (mut session, mut client, mut server) = client_game(true);
+        session.chat.enabled = enabled;
+        let (mut errors, mut desyncs) = (vec![], vec![]);
+        let packet = server.receive(|| {
+            session.update(&mut client, &mut errors, &mut desyncs);
+        });
+        assert!(matches!(packet, Packet::Handshake(_)));
+        server.send(&ServerToClientHandshake {
+            features: vec![Features::Other(CHAT_FEATURE.into())],
+            board: chess_representaiton_to_wire(&client.chess_representation),
+            moves: vec![],
+            joever: Joever::Ongoing,
+        });
+
+        if !enabled {
+            // Clients that do not want to chat stay quiet.
+            server.assert_silent(|| {
+                session.update(&mut client, &mut errors, &mut desyncs);
+            });
+            assert!(server.extensions.is_empty());
+            assert!(!session.chat.is_open());
+            assert!(session.interop.deviations.is_empty());
+            continue
+        }
+
+        // Those that do answer with a line without text.
+        wait_for(|| {
+            session.update(&mut client, &mut errors, &mut desyncs);
+            server.read();
+            !server.extensions.is_empty()
+        });
+        assert_eq!(server.extensions, vec![Extension::Chat(String::new())]);
+        assert!(session.chat.is_open());
+        assert!(session.interop.deviations.is_empty());
+
+        server.send(&serde_json::json!({ "Chat": "good luck" }));
+        wait_for(|| {
+            session.update(&mut client, &mut errors, &mut desyncs);
+            !session.chat.lines.is_empty()
+        });
+        let line = ChatLine { ours: false, text: "good luck".into() };
+        assert_eq!(session.chat.lines, vec![line]);
+        assert!(errors.is_empty());
+    }
+}
+
+#[test]
+pub fn host_clock() {
+    let (mut session, mut host, mut client) = host_game();
+    let control = TimeControl::Increment { base_ms: 60_000, increment_ms: 0 };
+    session.clock = Some(Clock::new(control));
+    let (s, h, c) = (&mut session, &mut host, &mut client);
+    handshake_with_host(s, h, c, Color::White);
+    // Clients that do not keep time are never sent the clock.
+    c.assert_silent(|| {
+        s.update(h);
+    });
+    assert!(c.extensions.is_empty());
+
+    c.send(&serde_json::json!({ "Clock": null }));
+    wait_for(|| {
+        s.update(h);
+        c.read();
+        !c.extensions.is_empty()
+    });
+    let Extension::Clock(Some(state)) = c.extensions.remove(0) else {
+        panic!("expected the state of the clock");
+    };
+    assert_eq!(state.control, control);
+    assert_eq!(state.running_white, Some(true));
+
+    // Every move starts the other clock.
+    host_move(s, h, c, "e2", "e4");
+    wait_for(|| {
+        s.update(h);
+        c.read();
+        !c.extensions.is_empty()
+    });
+    let Extension::Clock(Some(state)) = c.extensions.remove(0) else {
+        panic!("expected the state of the clock");
+    };
+    assert_eq!(state.running_white, Some(false));
+    assert!(state.white_ms <= 60_000);
+    assert!(s.interop.deviations.is_empty());
+
+    // When the flag of the client falls, it is told that it lost the only
+    // way the protocol allows.
+    s.clock = Some(Clock::from_state(&ClockState {
+        black_ms: 0,
+        ..state
+    }));
+    let packet = c.receive(|| {
+        s.update(h);
+    });
+    let Packet::Data(ServerToClient::Resigned { joever, .. }) = packet else {
+        panic!("expected a resignation, got {packet:?}");
+    };
+    assert_eq!(joever, Joever::White);
+    assert_eq!(h.end, Some(GameEnd::OutOfTime { white_won: Some(true) }));
+    wait_for(|| {
+        s.update(h);
+        c.read();
+        c.extensions.iter().any(|e| matches!(e, Extension::Clock(Some(state))
+                                              if state.running_white.is_none()))
+    });
+}
+
+#[test]
+pub fn client_clock() {
+    let (mut session, mut client, mut server) = client_game(true);
+    let (mut errors, mut desyncs) = (vec![], vec![]);
+    let packet = server.receive(|| {
+        session.update(&mut client, &mut errors, &mut desyncs);
+    });
+    assert!(matches!(packet, Packet::Handshake(_)));
+    let board = chess_representaiton_to_wire(&client.chess_representation);
+    server.send(&ServerToClientHandshake {
+        features: vec![Features::Other(CLOCK_FEATURE.into())],
+        board,
+        moves: vec![],
+        joever: Joever::Ongoing,
+    });
+    // The client says that it keeps time.
+    wait_for(|| {
+        session.update(&mut client, &mut errors, &mut desyncs);
+        server.read();
+        !server.extensions.is_empty()
+    });
+    assert_eq!(server.extensions, vec![Extension::Clock(None)]);
+    assert!(session.interop.deviations.is_empty());
+
+    let state = ClockState {
+        control: TimeControl::SuddenDeath { base_ms: 60_000 },
+        white_ms: 0,
+        black_ms: 30_000,
+        running_white: None,
+    };
+    server.send(&serde_json::json!({ "Clock": state }));
+    wait_for(|| {
+        session.update(&mut client, &mut errors, &mut desyncs);
+        session.clock.is_some()
+    });
+    assert_eq!(session.clock.as_ref().unwrap().state(), state);
+
+    // The game ended on time rather than by resignation, as the clock
+    // shows.
+    server.send(&ServerToClient::Resigned {
+        board: chess_representaiton_to_wire(&client.chess_representation),
+        joever: Joever::Black,
+    });
+    wait_for(|| {
+        session.update(&mut client, &mut errors, &mut desyncs);
+        client.is_game_over
+    });
+    let end = GameEnd::OutOfTime { white_won: Some(false) };
+    assert_eq!(client.end, Some(end));
+    assert!(errors.is_empty());
+}
+
+/// Has the client resign and waits for the host to confirm it.
+fn client_resigns(session: &mut HostSession, host: &mut ChessState,
+                  client: &mut ClientPeer) {
+    client.send(&ClientToServer::Resign);
+    let packet = client.receive(|| {
+        session.update(host);
+    });
+    assert!(matches!(packet, Packet::Data(ServerToClient::Resigned { .. })));
+    assert!(host.is_game_over);
+}
+
+#[test]
+pub fn host_rematch() {
+    let (mut session, mut host, mut client) = host_game();
+    let (s, h, c) = (&mut session, &mut host, &mut client);
+    c.send(&ClientToServerHandshake { server_color: Color::White });
+    let Packet::Handshake(handshake) = c.receive(|| {
+        s.update(h);
+    }) else {
+        panic!("expected a handshake");
+    };
+    let feature = Features::Other(REMATCH_FEATURE.into());
+    assert!(handshake.features.contains(&feature));
+    c.send(&serde_json::json!({ "Rematch": "Supported" }));
+    host_move(s, h, c, "e2", "e4");
+    client_resigns(s, h, c);
+    assert!(s.rematch.peer_can_rematch);
+
+    s.rematch.offer(&s.connection);
+    wait_for(|| {
+        c.read();
+        !c.extensions.is_empty()
+    });
+    assert_eq!(c.extensions[0], Extension::Rematch(RematchRequest::Offer));
+
+    // The client accepts and, playing white now, moves at once. The move
+    // belongs to the new game.
+    let mut bytes = serde_json::to_vec(
+        &serde_json::json!({ "Rematch": "Accept" })).unwrap();
+    let first_move = ClientToServer::Move(mv("d2", "d4"));
+    bytes.extend(serde_json::to_vec(&first_move).unwrap());
+    c.stream.write_all(&bytes).unwrap();
+    let packet = c.receive(|| {
+        s.update(h);
+    });
+    assert!(std::mem::take(&mut s.rematch.started));
+    assert!(!s.host_is_white);
+    assert_eq!(packet, Packet::Data(expected_state(h, mv("d2", "d4"))));
+    assert!(!h.is_game_over);
+    assert!(s.interop.deviations.is_empty());
+
+    // An offer sent right after resigning is one for after the game.
+    let mut bytes = serde_json::to_vec(&ClientToServer::Resign).unwrap();
+    bytes.extend(serde_json::to_vec(
+        &serde_json::json!({ "Rematch": "Offer" })).unwrap());
+    c.stream.write_all(&bytes).unwrap();
+    wait_for(|| {
+        s.update(h);
+        s.rematch.peer_offered
+    });
+    assert!(h.is_game_over);
+
+    // Offers can be turned down too.
+    s.rematch.decline(&s.connection);
+    wait_for(|| {
+        c.read();
+        c.extensions.len() > 1
+    });
+    assert_eq!(c.extensions[1], Extension::Rematch(RematchRequest::Decline));
+    assert!(!s.rematch.started);
+}
+
+#[test]
+pub fn client_rematch() {
+    let (mut session, mut client, mut server) = client_game(true);
+    let (mut errors, mut desyncs) = (vec![], vec![]);
+    let packet = server.receive(|| {
+        session.update(&mut client, &mut errors, &mut desyncs);
+    });
+    assert!(matches!(packet, Packet::Handshake(_)));
+    let board = chess_representaiton_to_wire(&client.chess_representation);
+    server.send(&ServerToClientHandshake {
+        features: vec![Features::Other(REMATCH_FEATURE.into())],
+        board: board.clone(),
+        moves: vec![],
+        joever: Joever::Ongoing,
+    });
+    wait_for(|| {
+        session.update(&mut client, &mut errors, &mut desyncs);
+        server.read();
+        !server.extensions.is_empty()
+    });
+    let supported = Extension::Rematch(RematchRequest::Supported);
+    assert_eq!(server.extensions, vec![supported]);
+    assert!(session.rematch.peer_can_rematch);
+
+    // No rematch can be asked for while the game is going on.
+    session.rematch.offer(&session.connection);
+    server.send(&ServerToClient::Resigned { board, joever: Joever::White });
+    wait_for(|| {
+        session.update(&mut client, &mut errors, &mut desyncs);
+        server.read();
+        server.extensions.len() > 1
+    });
+    let offer = Extension::Rematch(RematchRequest::Offer);
+    assert_eq!(server.extensions[1], offer);
+    assert!(client.is_game_over);
+
+    server.send(&serde_json::json!({ "Rematch": "Accept" }));
+    wait_for(|| {
+        session.update(&mut client, &mut errors, &mut desyncs);
+        session.rematch.started
+    });
+    assert!(!client.is_game_over);
+    assert!(client.is_client);
+    assert!(client.is_white_turn);
+    assert_eq!(client.chess_representation,
+               ChessState::new().chess_representation);
+    assert!(errors.is_empty());
+    assert!(session.interop.deviations.is_empty());
+}
+
+#[test]
+pub fn host_spectators() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let (mut session, mut host, mut client) = host_game();
+    handshake_with_host(&mut session, &mut host, &mut client, Color::White);
+    let (s, h, c) = (&mut session, &mut host, &mut client);
+    host_move(s, h, c, "e2", "e4");
+
+    let stream = TcpStream::connect(address).unwrap();
+    let (accepted, _) = listener.accept().unwrap();
+    s.spectators.add(Connection::spawn(accepted).unwrap());
+    let mut spectator: ClientPeer = Peer::new(stream);
+    // Spectators are sent the board as it is when they join.
+    spectator.send(&ClientToServerHandshake { server_color: Color::White });
+    let Packet::Handshake(handshake) = spectator.receive(|| {
+        s.update(h);
+    }) else {
+        panic!("expected a handshake");
+    };
+    assert_eq!(handshake.board,
+               chess_representaiton_to_wire(&h.chess_representation));
+    assert_eq!(handshake.moves, h.legal_moves);
+    assert_eq!(s.spectators.len(), 1);
+
+    // Every move is sent to the spectator too.
+    let state = client_move(s, h, c, mv("e7", "e5"));
+    assert_eq!(spectator.receive(|| {
+        s.update(h);
+    }), Packet::Data(state));
+    let state = host_move(s, h, c, "g1", "f3");
+    assert_eq!(spectator.receive(|| {
+        s.update(h);
+    }), Packet::Data(state));
+
+    // Spectators may not move, even when it is the client's turn.
+    let board = h.chess_representation;
+    spectator.send(&ClientToServer::Move(mv("b8", "c6")));
+    let packet = spectator.receive(|| {
+        s.update(h);
+    });
+    let Packet::Data(ServerToClient::Error { message, .. }) = packet else {
+        panic!("expected an error, got {packet:?}");
+    };
+    assert_eq!(message, SPECTATOR_ERROR);
+    assert_eq!(h.chess_representation, board);
+    assert!(!h.is_white_turn);
+    c.assert_silent(|| {
+        s.update(h);
+    });
+    assert!(s.interop.deviations.is_empty());
+
+    // Spectators that leave are forgotten, and the game carries on.
+    drop(spectator);
+    wait_for(|| {
+        s.update(h);
+        s.spectators.is_empty()
+    });
+    client_move(s, h, c, mv("b8", "c6"));
+}
+
+/// Runs a lobby on a thread of its own, returning its address.
+fn start_lobby() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.set_nonblocking(true).unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    std::thread::spawn(move || {
+        let mut lobby = Lobby::new(InteropMode::Lenient, None,
+                                   Framing::Finder);
+        loop {
+            lobby.accept(&listener);
+            lobby.update();
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    });
+    address
+}
+
+/// Waits for the next packet on a connection to a server.
+fn next_packet(connection: &ClientConnection)
+    -> Packet<ServerToClientHandshake, ServerToClient> {
+    let mut packets = vec![];
+    wait_for(|| {
+        assert!(connection.poll(&mut packets), "the connection was lost");
+        !packets.is_empty()
+    });
+    packets.remove(0)
+}
+
+#[test]
+pub fn lobby_games() {
+    let address = start_lobby();
+    let mut lobby = LobbyClient::connect(&address).unwrap();
+    assert_eq!(lobby.peer_addr().unwrap().to_string(), address);
+    assert_eq!(lobby.list().unwrap(), vec![]);
+
+    let white = LobbyClient::connect(&address).unwrap()
+        .create(true, Framing::Finder, None).unwrap();
+    assert!(matches!(next_packet(&white), Packet::Handshake(_)));
+    let open = OpenGame { id: 1, is_white: false };
+    assert_eq!(lobby.list().unwrap(), vec![open]);
+
+    let mut late = LobbyClient::connect(&address).unwrap();
+    let (black, is_white) = lobby.join(1, Framing::Finder, None).unwrap();
+    assert!(!is_white);
+    assert!(matches!(next_packet(&black), Packet::Handshake(_)));
+    // Full games are no longer listed, nor can they be joined.
+    assert!(late.list().unwrap().is_empty());
+    assert!(late.join(1, Framing::Finder, None).is_err());
+
+    // The game is played like on any other server.
+    white.send(Packet::Data(ClientToServer::Move(mv("e2", "e4"))));
+    for connection in [&white, &black] {
+        let Packet::Data(state) = next_packet(connection) else {
+            panic!("expected a state");
+        };
+        assert_eq!(at(board_of(&state), "e4"), Piece::WhitePawn);
+    }
+
+    // Clients that send a handshake straight away get a game of their own,
+    // which others may join.
+    let plain = connect_client(&address, true).unwrap();
+    assert!(matches!(next_packet(&plain), Packet::Handshake(_)));
+    let mut lobby = LobbyClient::connect(&address).unwrap();
+    let open = OpenGame { id: 2, is_white: true };
+    assert_eq!(lobby.list().unwrap(), vec![open]);
+    let (_, is_white) = lobby.join(2, Framing::Finder, None).unwrap();
+    assert!(is_white);
+}
+
+#[test]
+pub fn lobby_replies_in_background() {
+    // A lobby that never answers does not hold up whoever asked it.
+    let silent = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = silent.local_addr().unwrap().to_string();
+    let start = Instant::now();
+    let reply = LobbyReply::spawn(move || {
+        LobbyClient::connect(&address)?.list()
+    }).unwrap();
+    assert!(reply.poll().is_none());
+    assert!(start.elapsed() < Duration::from_secs(1));
+
+    let address = start_lobby();
+    let reply = LobbyReply::spawn(move || {
+        LobbyClient::connect(&address)?.list()
+    }).unwrap();
+    let mut games = None;
+    wait_for(|| {
+        games = reply.poll();
+        games.is_some()
+    });
+    assert_eq!(games.unwrap().unwrap(), vec![]);
+}
+
+#[test]
+pub fn host_recording_replays() {
+    let directory = std::env::temp_dir()
+        .join(format!("gchess-record-{}", std::process::id()));
+    std::fs::create_dir_all(&directory).unwrap();
+    let recorder = Recorder::create(&directory, Role::Host).unwrap();
+    let path = recorder.path.clone();
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (accepted, _) = listener.accept().unwrap();
+    let connection =
+        Connection::spawn_recorded(accepted, Framing::Finder, Some(recorder))
+            .unwrap();
+    let mut session = HostSession::new(connection, true);
+    let mut host = ChessState::new();
+    host.is_host = true;
+    let mut client = Peer::new(stream);
+    let (s, h, c) = (&mut session, &mut host, &mut client);
+
+    handshake_with_host(s, h, c, Color::White);
+    host_move(s, h, c, "e2", "e4");
+    client_move(s, h, c, mv("e7", "e5"));
+    host_move(s, h, c, "g1", "f3");
+    c.send(&ClientToServer::Move(mv("a7", "a4")));
+    let packet = c.receive(|| {
+        s.update(h);
+    });
+    assert!(matches!(packet, Packet::Data(ServerToClient::Error { .. })));
+
+    // A rematch starts the replayed games over too.
+    client_resigns(s, h, c);
+    c.send(&serde_json::json!({ "Rematch": "Offer" }));
+    wait_for(|| {
+        s.update(h);
+        s.rematch.peer_offered
+    });
+    s.rematch.offer(&s.connection);
+    s.update(h);
+    assert!(s.rematch.started);
+    client_move(s, h, c, mv("d2", "d4"));
+
+    // Everything is written before it is sent or handed to the session.
+    let entries = read_session(&path).unwrap();
+    std::fs::remove_dir_all(&directory).unwrap();
+    let (mut from_server, mut from_client) = (0, 0);
+    let result = replay(&entries, |_, message, _| match message {
+        Replayed::FromServer(_) => from_server += 1,
+        Replayed::FromClient(_) => from_client += 1,
+    });
+    assert_eq!((from_server, from_client), (7, 5));
+    assert_eq!(result.chess_state.chess_representation,
+               host.chess_representation);
+    assert!(!result.chess_state.is_white_turn);
+    assert!(!result.chess_state.is_game_over);
+    // The host's game is played again too, rejecting the same move.
+    let host_state = result.host_state.unwrap();
+    assert_eq!(host_state.chess_representation, host.chess_representation);
+    assert_eq!(host_state.legal_moves, host.legal_moves);
+    assert!(!host_state.is_white_turn);
+    assert_eq!(result.errors, vec![String::from("Bad move!")]);
+    assert!(result.desync_log.is_empty());
+    assert!(result.deviations.is_empty());
+    assert!(result.mismatches.is_empty(), "{:?}", result.mismatches);
+}